@@ -46,8 +46,8 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
         .collect::<Vec<_>>();
 
     unsafe {
-        let instance_properties = Instance::properties();
-        let instance = Instance::create("audir-music");
+        let instance = Instance::create("audir-music")?;
+        let instance_properties = instance.properties();
 
         let output_device = match instance.default_physical_output_device() {
             Some(device) => device,
@@ -66,7 +66,7 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
 
         let sample_rate = 48_000;
         let format = audir::Format::F32;
-        let output_channels = audir::ChannelMask::FRONT_LEFT | audir::ChannelMask::FRONT_RIGHT;
+        let output_channels = audir::ChannelLayout::from(audir::ChannelMask::STEREO);
 
         assert!(instance.physical_device_supports_format(
             output_device,
@@ -87,24 +87,17 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
                     format,
                     sample_rate,
                 },
+                buffering: audir::Buffering::PowerSaving,
             },
             audir::Channels {
-                input: audir::ChannelMask::empty(),
+                input: audir::ChannelLayout::empty(),
                 output: output_channels,
             },
-            Box::new(move |stream| {
-                let properties = stream.properties;
-                let num_channels = properties.num_channels();
-
-                let buffer = std::slice::from_raw_parts_mut(
-                    stream.buffers.output as *mut f32,
-                    stream.buffers.frames as usize * num_channels,
-                );
-
-                for dt in 0..stream.buffers.frames as usize {
-                    let frame = samples[sample];
-                    buffer[num_channels * dt as usize] = frame[0];
-                    buffer[num_channels * dt as usize + 1] = frame[1];
+            Box::new(move |mut stream| {
+                let mut buffer = stream.output::<f32>().unwrap();
+                for frame in buffer.frames_mut() {
+                    frame[0] = samples[sample][0];
+                    frame[1] = samples[sample][1];
                     sample = (sample + 1) % samples.len();
                 }
             }),
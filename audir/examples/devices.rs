@@ -1,16 +1,11 @@
-#[cfg(target_os = "linux")]
-use audir::pulse::Instance;
-#[cfg(windows)]
-use audir::wasapi::Instance;
+use audir::dynamic::Instance;
 
 use audir::Instance as InstanceTrait;
 
 fn main() -> anyhow::Result<()> {
     unsafe {
-        #[cfg(windows)]
-        let instance = Instance::create("audir - devices");
-        #[cfg(target_os = "linux")]
-        let instance = audir::pulse::Instance::create("audir - devices");
+        let instance = Instance::create("audir - devices")?;
+        println!("driver: {:?}", instance.driver_id());
 
         let physical_devices = instance.enumerate_physical_devices();
 
@@ -1,8 +1,9 @@
 use crate::{api, api::Result};
 use ndk::aaudio;
 use std::collections::HashMap;
+use std::panic;
 use std::ptr;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 struct PhysicalDevice {
     device_name: String,
@@ -19,11 +20,17 @@ pub struct Instance {
 }
 
 impl Instance {
-    unsafe fn devices(env: &jni::AttachGuard) -> jni::sys::jobject {
-        let class_ctxt = env.find_class("android/content/Context").unwrap();
-        let audio_service = env
-            .get_static_field(class_ctxt, "AUDIO_SERVICE", "Ljava/lang/String;")
-            .unwrap();
+    /// Lock the physical device map.
+    ///
+    /// The map is rebuilt on each enumeration, a poisoned lock holds no broken state.
+    fn physical_devices(&self) -> MutexGuard<'_, PhysicalDeviceMap> {
+        self.devices.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    unsafe fn devices(env: &jni::AttachGuard) -> jni::errors::Result<jni::sys::jobject> {
+        let class_ctxt = env.find_class("android/content/Context")?;
+        let audio_service =
+            env.get_static_field(class_ctxt, "AUDIO_SERVICE", "Ljava/lang/String;")?;
 
         let audio_manager = env
             .call_method(
@@ -31,21 +38,126 @@ impl Instance {
                 "getSystemService",
                 "(Ljava/lang/String;)Ljava/lang/Object;",
                 &[audio_service],
-            )
-            .unwrap()
-            .l()
-            .unwrap();
+            )?
+            .l()?;
 
-        let devices = env
-            .call_method(
-                audio_manager,
-                "getDevices",
-                "(I)[Landroid/media/AudioDeviceInfo;",
-                &[3.into()], // GET_DEVICES_ALL
-            )
-            .unwrap();
-
-        devices.l().unwrap().into_inner()
+        let devices = env.call_method(
+            audio_manager,
+            "getDevices",
+            "(I)[Landroid/media/AudioDeviceInfo;",
+            &[3.into()], // GET_DEVICES_ALL
+        )?;
+
+        Ok(devices.l()?.into_inner())
+    }
+
+    unsafe fn physical_device(
+        env: &jni::JNIEnv,
+        device: jni::objects::JObject,
+    ) -> jni::errors::Result<(DeviceId, PhysicalDevice)> {
+        let ty = env.call_method(device, "getType", "()I", &[])?;
+        let _ty_desc = match ty.i()? {
+            1 => "TYPE_BUILTIN_EARPIECE",
+            2 => "TYPE_BUILTIN_SPEAKER",
+            3 => "TYPE_WIRED_HEADSET",
+            4 => "TYPE_WIRED_HEADPHONES",
+            5 => "TYPE_LINE_ANALOG",
+            6 => "TYPE_LINE_DIGITAL",
+            7 => "TYPE_BLUETOOTH_SCO",
+            8 => "TYPE_BLUETOOTH_A2DP",
+            9 => "TYPE_HDMI",
+            10 => "TYPE_HDMI_ARC",
+            11 => "TYPE_USB_DEVICE",
+            12 => "TYPE_USB_ACCESSORY",
+            13 => "TYPE_DOCK",
+            14 => "TYPE_FM",
+            15 => "TYPE_BUILTIN_MIC",
+            16 => "TYPE_FM_TUNER",
+            17 => "TYPE_TV_TUNER",
+            18 => "TYPE_TELEPHONY",
+            19 => "TYPE_AUX_LINE",
+            20 => "TYPE_IP",
+            21 => "TYPE_BUS",
+            22 => "TYPE_USB_HEADSET",
+            23 => "TYPE_HEARING_AID",
+            24 => "TYPE_BUILTIN_SPEAKER_SAFE",
+            _ => "-",
+        };
+
+        // Device Name
+        let name = env.call_method(device, "getProductName", "()Ljava/lang/CharSequence;", &[])?;
+        let name = env.call_method(name.l()?, "toString", "()Ljava/lang/String;", &[])?;
+        let device_name: String = env.get_string(name.l()?.into())?.into();
+
+        // Sample Rates
+        let sample_rates_array = env.call_method(device, "getSampleRates", "()[I", &[])?;
+        let sample_rates_array = sample_rates_array.l()?.into_inner();
+        let num_sample_rates = env.get_array_length(sample_rates_array)?;
+
+        let sample_rates = if num_sample_rates > 0 {
+            let mut sample_rates = vec![0; num_sample_rates as usize];
+            env.get_int_array_region(sample_rates_array, 0, &mut sample_rates)?;
+            sample_rates
+        } else {
+            Vec::new()
+        };
+
+        // Channel Counts
+        let channel_count_array = env.call_method(device, "getChannelCounts", "()[I", &[])?;
+        let channel_counts_array = channel_count_array.l()?.into_inner();
+        let num_channel_counts = env.get_array_length(channel_counts_array)?;
+
+        let channel_counts = if num_channel_counts > 0 {
+            let mut channel_counts = vec![0; num_channel_counts as usize];
+            env.get_int_array_region(channel_counts_array, 0, &mut channel_counts)?;
+            channel_counts
+        } else {
+            Vec::new()
+        };
+
+        //  Encodings/Formats
+        let encodings_array = env.call_method(device, "getEncodings", "()[I", &[])?;
+
+        let encodings_array = encodings_array.l()?.into_inner();
+        let num_encodings = env.get_array_length(encodings_array)?;
+
+        let mut encodings = vec![0; num_encodings as usize];
+        env.get_int_array_region(encodings_array, 0, &mut encodings)?;
+
+        // Only encodings with a corresponding AAudio stream format can be opened.
+        let formats = encodings
+            .into_iter()
+            .filter_map(|encoding| {
+                match encoding {
+                    // ENCODING_PCM_16BIT
+                    0x2 => Some(api::Format::I16),
+                    // ENCODING_PCM_FLOAT
+                    0x4 => Some(api::Format::F32),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Stream Flags
+        let mut streams = api::StreamFlags::empty();
+        if env.call_method(device, "isSink", "()Z", &[])?.z()? {
+            streams |= api::StreamFlags::OUTPUT;
+        }
+        if env.call_method(device, "isSource", "()Z", &[])?.z()? {
+            streams |= api::StreamFlags::INPUT;
+        }
+
+        let id = env.call_method(device, "getId", "()I", &[])?.i()?;
+        Ok((
+            id,
+            PhysicalDevice {
+                device_name,
+                streams,
+                sample_rates,
+                channel_counts,
+                formats,
+            },
+        ))
     }
 }
 
@@ -53,7 +165,7 @@ impl api::Instance for Instance {
     type Device = Device;
     type Session = ();
 
-    unsafe fn properties() -> api::InstanceProperties {
+    unsafe fn properties(&self) -> api::InstanceProperties {
         api::InstanceProperties {
             driver_id: api::DriverId::AAudio,
             stream_mode: api::StreamMode::Callback,
@@ -61,168 +173,61 @@ impl api::Instance for Instance {
         }
     }
 
-    unsafe fn create(_name: &str) -> Self {
+    unsafe fn create(_name: &str) -> Result<Self> {
         let native_activity = ndk_glue::native_activity();
         let vm_ptr = native_activity.vm();
-        let vm = jni::JavaVM::from_raw(vm_ptr).unwrap();
+        let vm = match jni::JavaVM::from_raw(vm_ptr) {
+            Ok(vm) => vm,
+            Err(_) => return Err(api::Error::BackendUnavailable { source: None }),
+        };
 
         let instance = Instance {
             vm,
             devices: Mutex::new(PhysicalDeviceMap::new()),
         };
         instance.enumerate_physical_devices(); // populate physical devices
-        instance
+        Ok(instance)
     }
 
     unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
-        let env = self.vm.attach_current_thread().unwrap();
         let mut physical_devices = Vec::new();
+        let env = match self.vm.attach_current_thread() {
+            Ok(env) => env,
+            Err(err) => {
+                log::warn!("failed to attach to java vm: {}", err);
+                return physical_devices;
+            }
+        };
 
-        let mut devices = self.devices.lock().unwrap();
+        let mut devices = self.physical_devices();
         devices.clear();
 
-        let device_array = Self::devices(&env);
-        let len = env.get_array_length(device_array).unwrap();
-        for i in 0..len {
-            let device = env.get_object_array_element(device_array, i).unwrap();
-
-            let ty = env.call_method(device, "getType", "()I", &[]).unwrap();
-            let _ty_desc = match ty.i().unwrap() {
-                1 => "TYPE_BUILTIN_EARPIECE",
-                2 => "TYPE_BUILTIN_SPEAKER",
-                3 => "TYPE_WIRED_HEADSET",
-                4 => "TYPE_WIRED_HEADPHONES",
-                5 => "TYPE_LINE_ANALOG",
-                6 => "TYPE_LINE_DIGITAL",
-                7 => "TYPE_BLUETOOTH_SCO",
-                8 => "TYPE_BLUETOOTH_A2DP",
-                9 => "TYPE_HDMI",
-                10 => "TYPE_HDMI_ARC",
-                11 => "TYPE_USB_DEVICE",
-                12 => "TYPE_USB_ACCESSORY",
-                13 => "TYPE_DOCK",
-                14 => "TYPE_FM",
-                15 => "TYPE_BUILTIN_MIC",
-                16 => "TYPE_FM_TUNER",
-                17 => "TYPE_TV_TUNER",
-                18 => "TYPE_TELEPHONY",
-                19 => "TYPE_AUX_LINE",
-                20 => "TYPE_IP",
-                21 => "TYPE_BUS",
-                22 => "TYPE_USB_HEADSET",
-                23 => "TYPE_HEARING_AID",
-                24 => "TYPE_BUILTIN_SPEAKER_SAFE",
-                _ => "-",
-            };
-
-            // Device Name
-            let name = env
-                .call_method(device, "getProductName", "()Ljava/lang/CharSequence;", &[])
-                .unwrap();
-            let name = env
-                .call_method(name.l().unwrap(), "toString", "()Ljava/lang/String;", &[])
-                .unwrap();
-            let device_name: String = env.get_string(name.l().unwrap().into()).unwrap().into();
-
-            // Sample Rates
-            let sample_rates_array = env
-                .call_method(device, "getSampleRates", "()[I", &[])
-                .unwrap();
-            let sample_rates_array = sample_rates_array.l().unwrap().into_inner();
-            let num_sample_rates = env.get_array_length(sample_rates_array).unwrap();
-
-            let sample_rates = if num_sample_rates > 0 {
-                let mut sample_rates = vec![0; num_sample_rates as usize];
-                env.get_int_array_region(sample_rates_array, 0, &mut sample_rates)
-                    .unwrap();
-                sample_rates
-            } else {
-                Vec::new()
-            };
-
-            // Channel Counts
-            let channel_count_array = env
-                .call_method(device, "getChannelCounts", "()[I", &[])
-                .unwrap();
-            let channel_counts_array = channel_count_array.l().unwrap().into_inner();
-            let num_channel_counts = env.get_array_length(channel_counts_array).unwrap();
-
-            let channel_counts = if num_channel_counts > 0 {
-                let mut channel_counts = vec![0; num_channel_counts as usize];
-                env.get_int_array_region(channel_counts_array, 0, &mut channel_counts)
-                    .unwrap();
-                channel_counts
-            } else {
-                Vec::new()
-            };
-
-            //  Encodings/Formats
-            let encodings_array = env
-                .call_method(device, "getEncodings", "()[I", &[])
-                .unwrap();
-
-            let encodings_array = encodings_array.l().unwrap().into_inner();
-            let num_encodings = env.get_array_length(encodings_array).unwrap();
-
-            let mut encodings = vec![0; num_encodings as usize];
-            env.get_int_array_region(encodings_array, 0, &mut encodings)
-                .unwrap();
-
-            let formats = encodings
-                .into_iter()
-                .filter_map(|encoding| {
-                    match encoding {
-                        // ENCODING_PCM_16BIT
-                        0x2 => Some(api::Format::I16),
-                        // ENCODING_PCM_FLOAT
-                        0x4 => Some(api::Format::F32),
-                        _ => None,
-                    }
-                })
-                .collect::<Vec<_>>();
-
-            // Stream Flags
-            let mut streams = api::StreamFlags::empty();
-            if env
-                .call_method(device, "isSink", "()Z", &[])
-                .unwrap()
-                .z()
-                .unwrap()
-            {
-                streams |= api::StreamFlags::OUTPUT;
+        let device_array = match Self::devices(&env) {
+            Ok(device_array) => device_array,
+            Err(err) => {
+                log::warn!("failed to query audio devices: {}", err);
+                return physical_devices;
             }
-            if env
-                .call_method(device, "isSource", "()Z", &[])
-                .unwrap()
-                .z()
-                .unwrap()
-            {
-                streams |= api::StreamFlags::INPUT;
+        };
+        let len = env.get_array_length(device_array).unwrap_or(0);
+        for i in 0..len {
+            let device = env
+                .get_object_array_element(device_array, i)
+                .and_then(|device| Self::physical_device(&env, device));
+            match device {
+                Ok((id, device)) => {
+                    physical_devices.push(id as _);
+                    devices.insert(id, device);
+                }
+                Err(err) => log::warn!("skipping audio device {}: {}", i, err),
             }
-
-            let id = env
-                .call_method(device, "getId", "()I", &[])
-                .unwrap()
-                .i()
-                .unwrap();
-            physical_devices.push(id as _);
-            devices.insert(
-                id,
-                PhysicalDevice {
-                    device_name,
-                    streams,
-                    sample_rates,
-                    channel_counts,
-                    formats,
-                },
-            );
         }
 
         physical_devices
     }
 
     unsafe fn default_physical_input_device(&self) -> Option<api::PhysicalDevice> {
-        let mut builder = ndk::aaudio::AAudioStreamBuilder::new().unwrap();
+        let mut builder = ndk::aaudio::AAudioStreamBuilder::new().ok()?;
         builder = builder.direction(ndk::aaudio::AAudioDirection::Input);
         match builder.open_stream() {
             Ok(stream) => {
@@ -234,7 +239,7 @@ impl api::Instance for Instance {
     }
 
     unsafe fn default_physical_output_device(&self) -> Option<api::PhysicalDevice> {
-        let mut builder = ndk::aaudio::AAudioStreamBuilder::new().unwrap();
+        let mut builder = ndk::aaudio::AAudioStreamBuilder::new().ok()?;
         builder = builder.direction(ndk::aaudio::AAudioDirection::Output);
         match builder.open_stream() {
             Ok(stream) => {
@@ -249,8 +254,11 @@ impl api::Instance for Instance {
         &self,
         physical_device: api::PhysicalDevice,
     ) -> Result<api::PhysicalDeviceProperties> {
-        let devices = self.devices.lock().unwrap();
-        let device = &devices[&(physical_device as i32)]; // TODO: check
+        let devices = self.physical_devices();
+        let device = match devices.get(&(physical_device as i32)) {
+            Some(device) => device,
+            None => return Err(api::Error::DeviceLost),
+        };
 
         Ok(api::PhysicalDeviceProperties {
             device_name: device.device_name.clone(),
@@ -265,10 +273,13 @@ impl api::Instance for Instance {
         _sharing: api::SharingMode,
         frame_desc: api::FrameDesc,
     ) -> bool {
-        let devices = self.devices.lock().unwrap();
-        let device = &devices[&(physical_device as i32)]; // TODO: check
+        let devices = self.physical_devices();
+        let device = match devices.get(&(physical_device as i32)) {
+            Some(device) => device,
+            None => return false,
+        };
 
-        let num_channels = frame_desc.channels.bits().count_ones();
+        let num_channels = frame_desc.num_channels();
 
         let supports_format = device
             .formats
@@ -291,35 +302,151 @@ impl api::Instance for Instance {
         supports_format && supports_sample_rate && supports_channel_count
     }
 
+    unsafe fn physical_device_format_capabilities(
+        &self,
+        physical_device: api::PhysicalDevice,
+        _sharing: api::SharingMode,
+    ) -> Result<api::FormatCapabilities> {
+        let devices = self.physical_devices();
+        let device = match devices.get(&(physical_device as i32)) {
+            Some(device) => device,
+            None => return Err(api::Error::DeviceLost),
+        };
+
+        // Devices without explicit sample rates or channel counts support arbitrary values.
+        let sample_rates = if device.sample_rates.is_empty() {
+            // `AudioFormat.SAMPLE_RATE_HZ_MIN` and `AudioFormat.SAMPLE_RATE_HZ_MAX`
+            vec![api::SampleRateRange {
+                min: 4_000,
+                max: 192_000,
+            }]
+        } else {
+            device
+                .sample_rates
+                .iter()
+                .map(|&sample_rate| api::SampleRateRange::fixed(sample_rate as _))
+                .collect()
+        };
+        let channels = if device.channel_counts.is_empty() {
+            vec![
+                api::ChannelMask::MONO.into(),
+                api::ChannelMask::STEREO.into(),
+            ]
+        } else {
+            device
+                .channel_counts
+                .iter()
+                .map(|&num_channels| map_channel_count(num_channels))
+                .collect()
+        };
+
+        Ok(api::FormatCapabilities {
+            formats: device.formats.clone(),
+            sample_rates,
+            channels,
+        })
+    }
+
     unsafe fn physical_device_default_concurrent_format(
         &self,
-        _physical_device: api::PhysicalDevice,
+        physical_device: api::PhysicalDevice,
     ) -> Result<api::FrameDesc> {
-        todo!()
+        let direction = match self.physical_devices().get(&(physical_device as i32)) {
+            Some(device) if device.streams.contains(api::StreamFlags::OUTPUT) => {
+                aaudio::AAudioDirection::Output
+            }
+            Some(_) => aaudio::AAudioDirection::Input,
+            None => return Err(api::Error::DeviceLost),
+        };
+
+        // Open a shared stream without explicit format to query the device defaults.
+        let stream = aaudio::AAudioStreamBuilder::new()
+            .map_err(map_error)?
+            .device_id(physical_device as _)
+            .direction(direction)
+            .sharing_mode(aaudio::AAudioSharingMode::Shared)
+            .open_stream()
+            .map_err(map_error)?;
+        Ok(api::FrameDesc {
+            format: stream_format(&stream)?,
+            channels: map_channel_count(stream.get_channel_count()),
+            sample_rate: stream.get_sample_rate() as _,
+        })
     }
 
     unsafe fn create_device(
         &self,
         desc: api::DeviceDesc,
-        _channels: api::Channels,
+        channels: api::Channels,
         mut callback: api::StreamCallback,
     ) -> Result<Device> {
-        let builder = aaudio::AAudioStreamBuilder::new()
-            .unwrap()
+        let format = match map_stream_format(desc.sample_desc.format) {
+            Some(format) => format,
+            None => {
+                return api::Error::validation(format!(
+                    "unsupported format: {:?}",
+                    desc.sample_desc.format
+                ))
+            }
+        };
+
+        // The default sample rate maps to `AAUDIO_UNSPECIFIED`.
+        let mut builder = aaudio::AAudioStreamBuilder::new()
+            .map_err(map_error)?
             .device_id(desc.physical_device as _)
-            .data_callback(Box::new(move |astream, data, frames| {
-                callback(api::Stream {
-                    properties: get_stream_properties(&astream),
-                    buffers: api::StreamBuffers {
-                        frames: frames as _,
-                        input: ptr::null(),
-                        output: data as *mut _,
-                    },
-                });
-                aaudio::AAudioCallbackResult::Continue
-            }));
-        let stream = builder.open_stream().unwrap();
-        Ok(Device { stream })
+            .format(format)
+            .sample_rate(desc.sample_desc.sample_rate as _)
+            .channel_count(channels.output.num_channels() as _);
+        builder = match desc.buffering {
+            api::Buffering::Default => builder,
+            api::Buffering::LowLatency => {
+                builder.performance_mode(aaudio::AAudioPerformanceMode::LowLatency)
+            }
+            api::Buffering::PowerSaving => {
+                builder.performance_mode(aaudio::AAudioPerformanceMode::PowerSaving)
+            }
+            api::Buffering::Explicit {
+                period_size,
+                num_periods,
+            } => builder
+                .frames_per_data_callback(period_size as _)
+                .buffer_capacity_in_frames((period_size * num_periods) as _),
+        };
+        // The stream format is validated after opening the stream.
+        let sample_format = desc.sample_desc.format;
+        let builder = builder.data_callback(Box::new(move |astream, data, frames| {
+            let stream = api::Stream {
+                properties: get_stream_properties(&astream, sample_format),
+                buffers: api::StreamBuffers {
+                    frames: frames as _,
+                    input: ptr::null(),
+                    output: data as *mut _,
+                },
+            };
+            // Unwinding into AAudio is undefined behaviour, stop the stream instead.
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| callback(stream))) {
+                Ok(()) => aaudio::AAudioCallbackResult::Continue,
+                Err(_) => {
+                    log::error!("stream callback panicked");
+                    aaudio::AAudioCallbackResult::Stop
+                }
+            }
+        }));
+        let stream = builder.open_stream().map_err(map_error)?;
+        if stream_format(&stream)? != sample_format {
+            return Err(api::Error::Unsupported { source: None });
+        }
+        if let api::Buffering::Explicit {
+            period_size,
+            num_periods,
+        } = desc.buffering
+        {
+            let _ = stream.set_buffer_size_in_frames((period_size * num_periods) as _);
+        }
+        Ok(Device {
+            stream,
+            format: sample_format,
+        })
     }
 
     unsafe fn create_session(&self, _: usize) -> Result<()> {
@@ -330,37 +457,115 @@ impl api::Instance for Instance {
     where
         F: FnMut(api::Event) + Send + 'static,
     {
-        todo!()
+        // Device change notifications aren't exposed via AAudio.
+        Ok(())
     }
 }
 
-unsafe fn get_stream_properties(stream: &aaudio::AAudioStream) -> api::StreamProperties {
-    let num_channels = stream.get_channel_count();
-    let channels = if num_channels == 2 {
-        api::ChannelMask::FRONT_LEFT | api::ChannelMask::FRONT_RIGHT
-    } else {
-        unimplemented!()
+fn map_error(err: aaudio::AAudioError) -> api::Error {
+    let result = match err {
+        aaudio::AAudioError::ErrorResult(result) => result,
+        _ => {
+            return api::Error::Internal {
+                cause: err.to_string(),
+            }
+        }
     };
+
+    let source = Some(api::NativeError::AAudio(result as _));
+    match result {
+        aaudio::AAudioErrorResult::Disconnected => api::Error::Disconnected { source },
+        aaudio::AAudioErrorResult::Timeout => api::Error::Timeout { source },
+        aaudio::AAudioErrorResult::NoService => api::Error::BackendUnavailable { source },
+        aaudio::AAudioErrorResult::Unavailable | aaudio::AAudioErrorResult::NoFreeHandles => {
+            api::Error::DeviceBusy { source }
+        }
+        aaudio::AAudioErrorResult::Unimplemented
+        | aaudio::AAudioErrorResult::InvalidFormat
+        | aaudio::AAudioErrorResult::InvalidRate => api::Error::Unsupported { source },
+        _ => api::Error::Internal {
+            cause: format!("AAudio error: {:?}", result),
+        },
+    }
+}
+
+/// Default channel layout for a channel count.
+fn map_channel_count(num_channels: i32) -> api::ChannelLayout {
+    match num_channels {
+        1 => api::ChannelMask::MONO.into(),
+        2 => api::ChannelMask::STEREO.into(),
+        4 => api::ChannelMask::QUAD.into(),
+        6 => api::ChannelMask::SURROUND_5_1.into(),
+        8 => api::ChannelMask::SURROUND_7_1.into(),
+        n => api::ChannelLayout::Discrete(n as _),
+    }
+}
+
+/// AAudio stream format for a sample format.
+fn map_stream_format(format: api::Format) -> Option<aaudio::AAudioFormat> {
+    match format {
+        api::Format::I16 => Some(aaudio::AAudioFormat::PCM_I16),
+        api::Format::F32 => Some(aaudio::AAudioFormat::PCM_Float),
+        _ => None,
+    }
+}
+
+/// Sample format of an opened stream.
+///
+/// Formats added after the `ndk` bindings (API level 31) are reported as unsupported values.
+fn stream_format(stream: &aaudio::AAudioStream) -> Result<api::Format> {
+    match stream.get_format() {
+        Ok(aaudio::AAudioFormat::PCM_I16) => Ok(api::Format::I16),
+        Ok(aaudio::AAudioFormat::PCM_Float) => Ok(api::Format::F32),
+        Err(aaudio::AAudioError::UnsupportedValue(ndk_sys::AAUDIO_FORMAT_PCM_I24_PACKED)) => {
+            Ok(api::Format::I24)
+        }
+        Err(aaudio::AAudioError::UnsupportedValue(ndk_sys::AAUDIO_FORMAT_PCM_I32)) => {
+            Ok(api::Format::I32)
+        }
+        Ok(format) => Err(api::Error::Internal {
+            cause: format!("unhandled stream format: {:?}", format),
+        }),
+        Err(err) => Err(map_error(err)),
+    }
+}
+
+unsafe fn get_stream_properties(
+    stream: &aaudio::AAudioStream,
+    format: api::Format,
+) -> api::StreamProperties {
+    let channels = map_channel_count(stream.get_channel_count());
+    let period_size = stream
+        .get_frames_per_data_callback()
+        .unwrap_or_else(|| stream.get_frames_per_burst())
+        .max(1);
     api::StreamProperties {
+        format,
         channels,
         sample_rate: stream.get_sample_rate() as _,
-        buffer_size: stream.get_buffer_size_in_frames() as _,
+        buffer_size: period_size as _,
+        num_periods: (stream.get_buffer_size_in_frames() / period_size).max(1) as _,
     }
 }
 
 pub struct Device {
     stream: aaudio::AAudioStream,
+    format: api::Format,
 }
 
 impl api::Device for Device {
     unsafe fn start(&self) {
-        self.stream.request_start().unwrap();
+        if let Err(err) = self.stream.request_start() {
+            log::error!("failed to start stream: {}", err);
+        }
     }
     unsafe fn stop(&self) {
-        self.stream.request_stop().unwrap();
+        if let Err(err) = self.stream.request_stop() {
+            log::error!("failed to stop stream: {}", err);
+        }
     }
 
     unsafe fn stream_properties(&self) -> api::StreamProperties {
-        get_stream_properties(&self.stream)
+        get_stream_properties(&self.stream, self.format)
     }
 }
@@ -1,6 +1,10 @@
-use crate::handle;
+use crate::{block, convert, handle, remix, resample};
 
-use std::{error, fmt, result};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{error, fmt, iter, mem, result, slice};
 
 /// Opaque physical device handle.
 pub type PhysicalDevice = handle::RawHandle;
@@ -75,10 +79,94 @@ pub enum FormFactor {
 }
 
 bitflags::bitflags! {
+    /// Speaker positions of a channel layout.
+    ///
+    /// Samples of a frame are interleaved in the order of the position bits,
+    /// starting with the least significant bit. The bit layout matches the
+    /// speaker positions of `WAVEFORMATEXTENSIBLE`.
     pub struct ChannelMask: u32 {
-        const FRONT_LEFT = 0b0001;
-        const FRONT_RIGHT = 0b0010;
-        const FRONT_CENTER = 0b0100;
+        const FRONT_LEFT = 0x1;
+        const FRONT_RIGHT = 0x2;
+        const FRONT_CENTER = 0x4;
+        const LOW_FREQUENCY = 0x8;
+        const BACK_LEFT = 0x10;
+        const BACK_RIGHT = 0x20;
+        const FRONT_LEFT_OF_CENTER = 0x40;
+        const FRONT_RIGHT_OF_CENTER = 0x80;
+        const BACK_CENTER = 0x100;
+        const SIDE_LEFT = 0x200;
+        const SIDE_RIGHT = 0x400;
+        const TOP_CENTER = 0x800;
+        const TOP_FRONT_LEFT = 0x1000;
+        const TOP_FRONT_CENTER = 0x2000;
+        const TOP_FRONT_RIGHT = 0x4000;
+        const TOP_BACK_LEFT = 0x8000;
+        const TOP_BACK_CENTER = 0x10000;
+        const TOP_BACK_RIGHT = 0x20000;
+
+        /// Mono layout.
+        const MONO = Self::FRONT_CENTER.bits;
+        /// Stereo layout.
+        const STEREO = Self::FRONT_LEFT.bits | Self::FRONT_RIGHT.bits;
+        /// Quadraphonic layout.
+        const QUAD = Self::STEREO.bits | Self::BACK_LEFT.bits | Self::BACK_RIGHT.bits;
+        /// 5.1 layout with back speakers.
+        const SURROUND_5_1 = Self::STEREO.bits
+            | Self::FRONT_CENTER.bits
+            | Self::LOW_FREQUENCY.bits
+            | Self::BACK_LEFT.bits
+            | Self::BACK_RIGHT.bits;
+        /// 5.1 layout with side speakers.
+        const SURROUND_5_1_SIDE = Self::STEREO.bits
+            | Self::FRONT_CENTER.bits
+            | Self::LOW_FREQUENCY.bits
+            | Self::SIDE_LEFT.bits
+            | Self::SIDE_RIGHT.bits;
+        /// 7.1 layout with back and side speakers.
+        const SURROUND_7_1 = Self::SURROUND_5_1.bits | Self::SIDE_LEFT.bits | Self::SIDE_RIGHT.bits;
+    }
+}
+
+/// Channel layout of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLayout {
+    /// Channels with speaker positions.
+    Mask(ChannelMask),
+    /// Number of channels without speaker positions.
+    ///
+    /// Used for devices with more channels than speaker positions
+    /// like multichannel pro-audio interfaces.
+    Discrete(usize),
+}
+
+impl ChannelLayout {
+    /// Layout without any channels.
+    pub fn empty() -> Self {
+        ChannelLayout::Mask(ChannelMask::empty())
+    }
+
+    /// Number of channels of the layout.
+    pub fn num_channels(&self) -> usize {
+        match *self {
+            ChannelLayout::Mask(mask) => mask.bits().count_ones() as _,
+            ChannelLayout::Discrete(num_channels) => num_channels,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_channels() == 0
+    }
+}
+
+impl Default for ChannelLayout {
+    fn default() -> Self {
+        ChannelLayout::empty()
+    }
+}
+
+impl From<ChannelMask> for ChannelLayout {
+    fn from(mask: ChannelMask) -> Self {
+        ChannelLayout::Mask(mask)
     }
 }
 
@@ -98,13 +186,140 @@ pub struct PhysicalDeviceProperties {
     pub form_factor: FormFactor,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Sample format.
+///
+/// Formats without suffix are little-endian, `Be` variants denote big-endian samples.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Format {
-    F32,
+    /// Unsigned 8-bit integer.
+    U8,
+    /// Signed 16-bit integer.
     I16,
-    U32,
+    /// Signed 16-bit integer, big-endian.
+    I16Be,
+    /// Signed 24-bit integer, packed into 3 bytes.
+    I24,
+    /// Signed 24-bit integer, packed into 3 bytes, big-endian.
+    I24Be,
+    /// Signed 24-bit integer in the lower 3 bytes of a 32-bit container.
+    I24In32,
+    /// Signed 24-bit integer in the lower 3 bytes of a 32-bit container, big-endian.
+    I24In32Be,
+    /// Signed 32-bit integer.
+    I32,
+    /// Signed 32-bit integer, big-endian.
+    I32Be,
+    /// 32-bit IEEE float.
+    F32,
+    /// 32-bit IEEE float, big-endian.
+    F32Be,
+    /// 64-bit IEEE float.
+    F64,
+    /// 64-bit IEEE float, big-endian.
+    F64Be,
+}
+
+impl Format {
+    /// All sample formats.
+    pub const ALL: [Format; 13] = [
+        Format::U8,
+        Format::I16,
+        Format::I16Be,
+        Format::I24,
+        Format::I24Be,
+        Format::I24In32,
+        Format::I24In32Be,
+        Format::I32,
+        Format::I32Be,
+        Format::F32,
+        Format::F32Be,
+        Format::F64,
+        Format::F64Be,
+    ];
+
+    /// Size of a single sample in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            Format::U8 => 1,
+            Format::I16 | Format::I16Be => 2,
+            Format::I24 | Format::I24Be => 3,
+            Format::I24In32 | Format::I24In32Be => 4,
+            Format::I32 | Format::I32Be | Format::F32 | Format::F32Be => 4,
+            Format::F64 | Format::F64Be => 8,
+        }
+    }
+
+    /// Required alignment of a sample in bytes.
+    ///
+    /// Packed 24-bit samples are only byte aligned.
+    pub fn align(self) -> usize {
+        match self {
+            Format::I24 | Format::I24Be => 1,
+            _ => self.size(),
+        }
+    }
+
+    /// Number of significant bits of a sample.
+    pub fn valid_bits(self) -> usize {
+        match self {
+            Format::I24In32 | Format::I24In32Be => 24,
+            _ => 8 * self.size(),
+        }
+    }
+
+    /// Whether the samples are floating point values.
+    pub fn is_float(self) -> bool {
+        matches!(
+            self,
+            Format::F32 | Format::F32Be | Format::F64 | Format::F64Be
+        )
+    }
+
+    /// Whether the samples are stored in big-endian byte order.
+    pub fn is_big_endian(self) -> bool {
+        matches!(
+            self,
+            Format::I16Be
+                | Format::I24Be
+                | Format::I24In32Be
+                | Format::I32Be
+                | Format::F32Be
+                | Format::F64Be
+        )
+    }
 }
 
+/// Sample types with a corresponding sample format.
+///
+/// Samples are stored in native byte order.
+///
+/// ## Safety
+///
+/// - The memory layout of the type **must** match `FORMAT`.
+pub unsafe trait Sample: Copy + Send + 'static {
+    const FORMAT: Format;
+}
+
+macro_rules! impl_sample {
+    ($ty:ty, $little:ident, $big:ident) => {
+        unsafe impl Sample for $ty {
+            const FORMAT: Format = if cfg!(target_endian = "big") {
+                Format::$big
+            } else {
+                Format::$little
+            };
+        }
+    };
+}
+
+unsafe impl Sample for u8 {
+    const FORMAT: Format = Format::U8;
+}
+impl_sample!(i16, I16, I16Be);
+impl_sample!(i32, I32, I32Be);
+impl_sample!(f32, F32, F32Be);
+impl_sample!(f64, F64, F64Be);
+
 /// Sample description.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct SampleDesc {
@@ -116,22 +331,27 @@ pub struct SampleDesc {
 
 /// Frame description.
 ///
-/// Consists of a channel mask and a sample description.
+/// Consists of a channel layout and a sample description.
 /// A frame is composed of one samples per channel.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct FrameDesc {
     /// Sample Format.
     pub format: Format,
     /// Sample Rate.
     pub sample_rate: usize,
-    /// Channel Mask.
-    pub channels: ChannelMask,
+    /// Channel Layout.
+    pub channels: ChannelLayout,
 }
 
 impl FrameDesc {
-    /// Number of channels for the channel mask.
+    /// Number of channels for the channel layout.
     pub fn num_channels(&self) -> usize {
-        self.channels.bits().count_ones() as _
+        self.channels.num_channels()
+    }
+
+    /// Size of a single frame in bytes.
+    pub fn frame_size(&self) -> usize {
+        self.num_channels() * self.format.size()
     }
 
     /// Sample descriptor.
@@ -143,6 +363,222 @@ impl FrameDesc {
     }
 }
 
+/// Inclusive range of sample rates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleRateRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl SampleRateRange {
+    /// Range consisting of a single sample rate.
+    pub fn fixed(sample_rate: usize) -> Self {
+        SampleRateRange {
+            min: sample_rate,
+            max: sample_rate,
+        }
+    }
+
+    pub fn contains(&self, sample_rate: usize) -> bool {
+        self.min <= sample_rate && sample_rate <= self.max
+    }
+}
+
+/// Format capabilities of a physical device for a sharing mode.
+///
+/// Formats, sample rates and channel layouts are listed independently.
+/// Not all combinations may be supported by the physical device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatCapabilities {
+    /// Supported sample formats.
+    pub formats: Vec<Format>,
+    /// Supported sample rates.
+    pub sample_rates: Vec<SampleRateRange>,
+    /// Supported channel layouts.
+    pub channels: Vec<ChannelLayout>,
+}
+
+impl FormatCapabilities {
+    /// Check if format, sample rate and channel layout are listed in the capabilities.
+    pub fn supports(&self, frame_desc: &FrameDesc) -> bool {
+        self.formats.contains(&frame_desc.format)
+            && self
+                .sample_rates
+                .iter()
+                .any(|range| range.contains(frame_desc.sample_rate))
+            && self.channels.contains(&frame_desc.channels)
+    }
+}
+
+bitflags::bitflags! {
+    /// Properties of a negotiated format differing from the desired format.
+    pub struct FormatMismatch: u32 {
+        /// Sample format is not supported.
+        const FORMAT = 0b001;
+        /// Sample rate is not supported.
+        const SAMPLE_RATE = 0b010;
+        /// Channel layout is not supported.
+        const CHANNELS = 0b100;
+    }
+}
+
+impl FormatMismatch {
+    /// Properties differing between two frame descriptions.
+    pub fn between(a: &FrameDesc, b: &FrameDesc) -> Self {
+        let mut mismatch = FormatMismatch::empty();
+        if a.format != b.format {
+            mismatch |= FormatMismatch::FORMAT;
+        }
+        if a.sample_rate != b.sample_rate {
+            mismatch |= FormatMismatch::SAMPLE_RATE;
+        }
+        if a.channels != b.channels {
+            mismatch |= FormatMismatch::CHANNELS;
+        }
+        mismatch
+    }
+}
+
+/// Result of a format negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiatedFormat {
+    /// The desired format is supported by the physical device.
+    Exact(FrameDesc),
+    /// The desired format is not supported, closest supported format of the physical device.
+    Closest {
+        frame_desc: FrameDesc,
+        /// Properties differing from the desired format.
+        reason: FormatMismatch,
+    },
+}
+
+impl NegotiatedFormat {
+    /// Negotiated frame description.
+    pub fn frame_desc(&self) -> FrameDesc {
+        match *self {
+            NegotiatedFormat::Exact(frame_desc) => frame_desc,
+            NegotiatedFormat::Closest { frame_desc, .. } => frame_desc,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(self, NegotiatedFormat::Exact(_))
+    }
+}
+
+/// Negotiate a format based on the format capabilities of a physical device.
+///
+/// Each property of the desired format is replaced by the closest listed property
+/// if not supported. Falls back to the default concurrent format if the resulting
+/// combination is still not supported.
+pub(crate) unsafe fn negotiate_format_from_capabilities<I: Instance + ?Sized>(
+    instance: &I,
+    physical_device: PhysicalDevice,
+    sharing: SharingMode,
+    frame_desc: FrameDesc,
+) -> Result<NegotiatedFormat> {
+    if instance.physical_device_supports_format(physical_device, sharing, frame_desc) {
+        return Ok(NegotiatedFormat::Exact(frame_desc));
+    }
+
+    let caps = instance.physical_device_format_capabilities(physical_device, sharing)?;
+
+    let format = if caps.formats.contains(&frame_desc.format) {
+        Some(frame_desc.format)
+    } else {
+        // Prefer same kind and byte order, followed by higher precision.
+        let desired = frame_desc.format;
+        caps.formats.iter().cloned().min_by_key(|format| {
+            (
+                format.is_float() != desired.is_float(),
+                format.is_big_endian() != desired.is_big_endian(),
+                format.valid_bits() < desired.valid_bits(),
+                (format.valid_bits() as isize - desired.valid_bits() as isize).abs(),
+            )
+        })
+    };
+
+    let sample_rate = caps
+        .sample_rates
+        .iter()
+        .map(|range| frame_desc.sample_rate.max(range.min).min(range.max))
+        .min_by_key(|&sample_rate| (sample_rate as isize - frame_desc.sample_rate as isize).abs());
+
+    let channels = if caps.channels.contains(&frame_desc.channels) {
+        Some(frame_desc.channels)
+    } else {
+        // Prefer same channel count, followed by more channels.
+        let num_channels = frame_desc.num_channels();
+        caps.channels.iter().cloned().min_by_key(|channels| {
+            (
+                channels.num_channels() < num_channels,
+                (channels.num_channels() as isize - num_channels as isize).abs(),
+            )
+        })
+    };
+
+    let closest = match (format, sample_rate, channels) {
+        (Some(format), Some(sample_rate), Some(channels)) => Some(FrameDesc {
+            format,
+            sample_rate,
+            channels,
+        }),
+        _ => None,
+    };
+
+    let closest = match closest {
+        Some(closest)
+            if instance.physical_device_supports_format(physical_device, sharing, closest) =>
+        {
+            closest
+        }
+        _ if sharing == SharingMode::Concurrent => {
+            instance.physical_device_default_concurrent_format(physical_device)?
+        }
+        _ => return Err(Error::Unsupported { source: None }),
+    };
+
+    Ok(NegotiatedFormat::Closest {
+        frame_desc: closest,
+        reason: FormatMismatch::between(&frame_desc, &closest),
+    })
+}
+
+/// Dispatch pending events of the instance event loop for run loops.
+///
+/// `supported` is cleared once the instance reports that it doesn't dispatch events itself,
+/// skipping further calls.
+pub(crate) unsafe fn dispatch_events<I: Instance + ?Sized>(
+    instance: &I,
+    supported: &mut bool,
+) -> Result<()> {
+    if !*supported {
+        return Ok(());
+    }
+    match instance.dispatch_pending() {
+        Err(Error::Validation { .. }) => {
+            *supported = false;
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Sample rates probed for instances not able to enumerate format capabilities.
+const PROBE_SAMPLE_RATES: [usize; 11] = [
+    8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000,
+];
+
+/// Common named channel layouts.
+pub(crate) const COMMON_CHANNEL_LAYOUTS: [ChannelMask; 6] = [
+    ChannelMask::MONO,
+    ChannelMask::STEREO,
+    ChannelMask::QUAD,
+    ChannelMask::SURROUND_5_1,
+    ChannelMask::SURROUND_5_1_SIDE,
+    ChannelMask::SURROUND_7_1,
+];
+
 /// Properties of the instance implementation.
 #[derive(Debug, Clone, Copy)]
 pub struct InstanceProperties {
@@ -155,6 +591,32 @@ pub struct InstanceProperties {
     /// Device sharing modes.
     pub sharing: SharingModeFlags,
 }
+/// Native error code reported by a backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeError {
+    /// PulseAudio error number (`PA_ERR_*`).
+    Pulse(i32),
+    /// Windows `HRESULT`.
+    HResult(i32),
+    /// OpenSL|ES result (`SL_RESULT_*`).
+    OpenSLES(u32),
+    /// AAudio result (`AAUDIO_ERROR_*`).
+    AAudio(i32),
+}
+
+impl error::Error for NativeError {}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            NativeError::Pulse(errno) => write!(fmt, "pulse error {}", errno),
+            NativeError::HResult(hr) => write!(fmt, "HRESULT 0x{:08X}", hr),
+            NativeError::OpenSLES(result) => write!(fmt, "OpenSL|ES result {}", result),
+            NativeError::AAudio(result) => write!(fmt, "AAudio error {}", result),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Error {
     /// Device Lost
@@ -167,9 +629,54 @@ pub enum Error {
 
     /// Internal implementation errors.
     Internal { cause: String },
+
+    /// Audio backend or sound server not available.
+    BackendUnavailable { source: Option<NativeError> },
+
+    /// Operation didn't complete in time.
+    Timeout { source: Option<NativeError> },
+
+    /// Requested configuration or operation is not supported.
+    Unsupported { source: Option<NativeError> },
+
+    /// Device is in use, e.g by an exclusive stream of another application.
+    DeviceBusy { source: Option<NativeError> },
+
+    /// Connection to the backend or device has been lost.
+    Disconnected { source: Option<NativeError> },
+
+    /// Operation would block, e.g no frames available for submitting.
+    WouldBlock,
 }
 
-impl error::Error for Error {}
+impl Error {
+    /// Native error code of the backend, if available.
+    pub fn native(&self) -> Option<NativeError> {
+        match *self {
+            Error::BackendUnavailable { source }
+            | Error::Timeout { source }
+            | Error::Unsupported { source }
+            | Error::DeviceBusy { source }
+            | Error::Disconnected { source } => source,
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::BackendUnavailable { source }
+            | Error::Timeout { source }
+            | Error::Unsupported { source }
+            | Error::DeviceBusy { source }
+            | Error::Disconnected { source } => source
+                .as_ref()
+                .map(|source| source as &(dyn error::Error + 'static)),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
@@ -179,6 +686,12 @@ impl fmt::Display for Error {
                 writeln!(fmt, "Validation error: {}", description)
             }
             Error::Internal { ref cause } => writeln!(fmt, "Internal: {}", cause),
+            Error::BackendUnavailable { .. } => writeln!(fmt, "Backend unavailable"),
+            Error::Timeout { .. } => writeln!(fmt, "Timeout"),
+            Error::Unsupported { .. } => writeln!(fmt, "Unsupported"),
+            Error::DeviceBusy { .. } => writeln!(fmt, "Device busy"),
+            Error::Disconnected { .. } => writeln!(fmt, "Disconnected"),
+            Error::WouldBlock => writeln!(fmt, "Would block"),
         }
     }
 }
@@ -201,30 +714,190 @@ pub enum Event {
     DefaultOutputDevice(Option<PhysicalDevice>),
 }
 
+bitflags::bitflags! {
+    /// Readiness events of a wait handle.
+    pub struct WaitFlags: u32 {
+        const READABLE = 0x1;
+        const WRITABLE = 0x2;
+    }
+}
+
+/// OS handle, which can be registered in an event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitHandle {
+    /// File descriptor, ready for the given events.
+    #[cfg(unix)]
+    Fd {
+        fd: std::os::unix::io::RawFd,
+        flags: WaitFlags,
+    },
+    /// Event object, signaled on readiness.
+    #[cfg(windows)]
+    Event(std::os::windows::io::RawHandle),
+}
+
+/// Handles to wait on before dispatching pending events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitHandles {
+    pub handles: Vec<WaitHandle>,
+    /// Maximum time to wait before dispatching, `None` for no timeout.
+    pub timeout: Option<Duration>,
+}
+
+/// Device stream buffering.
+///
+/// Requested buffering configuration, backends may adjust the values to their constraints.
+/// The granted configuration is exposed via `StreamProperties`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffering {
+    /// Backend specific default buffering.
+    Default,
+    /// Small buffers with a total latency of about 10ms.
+    ///
+    /// Suited for interactive applications like games or live processing.
+    LowLatency,
+    /// Large buffers reducing the wakeup frequency.
+    ///
+    /// Suited for non-interactive playback like music players.
+    PowerSaving,
+    /// Explicit period size and count.
+    Explicit {
+        /// Number of frames per period.
+        period_size: Frames,
+        /// Number of periods in the device buffer.
+        num_periods: usize,
+    },
+}
+
+impl Buffering {
+    /// Period size and count for the buffering request.
+    ///
+    /// Returns `None` for `Buffering::Default`.
+    pub fn periods(&self, sample_rate: usize) -> Option<(Frames, usize)> {
+        match *self {
+            Buffering::Default => None,
+            Buffering::LowLatency => Some((sample_rate / 200, 2)),
+            Buffering::PowerSaving => Some((sample_rate / 10, 4)),
+            Buffering::Explicit {
+                period_size,
+                num_periods,
+            } => Some((period_size, num_periods)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceDesc {
     pub physical_device: PhysicalDevice,
     pub sharing: SharingMode,
     pub sample_desc: SampleDesc,
+    pub buffering: Buffering,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Channels {
-    pub input: ChannelMask,
-    pub output: ChannelMask,
+    pub input: ChannelLayout,
+    pub output: ChannelLayout,
+}
+
+/// Dithering applied when reducing the sample resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dithering {
+    /// Round to the nearest sample value.
+    None,
+    /// Triangular probability density function dither with an amplitude of one LSB.
+    Triangular,
+}
+
+/// Sample format conversion between stream callback and device stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatConversion {
+    pub dithering: Dithering,
+}
+
+/// Interpolation quality of the sample rate conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResamplingQuality {
+    /// Linear interpolation between neighbouring frames.
+    Linear,
+    /// Blackman windowed sinc interpolation with `taps` filter taps per side.
+    Sinc { taps: usize },
+}
+
+/// Sample rate conversion between stream callback and device stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resampling {
+    pub quality: ResamplingQuality,
+}
+
+/// Mixing coefficients between channel layouts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelMixing {
+    /// Standard upmix and downmix coefficients derived from the speaker positions.
+    ///
+    /// Mono is duplicated into stereo, missing surround positions are folded down
+    /// with -3 dB into the nearest available speakers. Discrete layouts are mapped by index.
+    Standard,
+    /// User-supplied row-major mixing matrices with one row per target channel
+    /// and one column per source channel.
+    ///
+    /// `input` maps device channels to callback channels, `output` maps callback channels
+    /// to device channels. Matrices with mismatching dimensions fall back to `Standard`.
+    Matrix { input: Vec<f32>, output: Vec<f32> },
+}
+
+/// Fixed number of frames per stream callback invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSize {
+    pub frames: Frames,
+}
+
+impl BlockSize {
+    /// Maximum latency added by the internal buffering.
+    ///
+    /// In frames of the stream observed by the callback. Not included in
+    /// `Device::stream_properties`, add it to the device buffering for the total latency.
+    pub fn latency(&self) -> Frames {
+        self.frames.max(1) - 1
+    }
+}
+
+/// Opt-in conversion stages between stream callback and device stream.
+///
+/// Enabled stages let the stream callback observe the requested stream configuration,
+/// independent of the configuration of the device stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conversion {
+    /// Convert samples from and to the requested sample format.
+    pub format: Option<FormatConversion>,
+    /// Resample frames from and to the requested sample rate.
+    pub sample_rate: Option<Resampling>,
+    /// Remix frames from and to the requested channel layouts.
+    ///
+    /// Requires equal input and output layouts for duplex devices.
+    pub channels: Option<ChannelMixing>,
+    /// Invoke the stream callback with a constant number of frames.
+    ///
+    /// Applied after all other stages, adds up to `BlockSize::latency` frames of latency.
+    pub block_size: Option<BlockSize>,
 }
 
 /// Device Stream properties.
 #[derive(Debug, Clone, Copy)]
 pub struct StreamProperties {
-    pub channels: ChannelMask,
+    /// Sample format of the stream buffers.
+    pub format: Format,
+    pub channels: ChannelLayout,
     pub sample_rate: usize,
+    /// Number of frames per period.
     pub buffer_size: Frames,
+    /// Number of periods in the device buffer.
+    pub num_periods: usize,
 }
 
 impl StreamProperties {
     pub fn num_channels(&self) -> usize {
-        self.channels.bits().count_ones() as _
+        self.channels.num_channels()
     }
 }
 
@@ -251,6 +924,168 @@ pub struct Stream {
     pub buffers: StreamBuffers,
 }
 
+impl Stream {
+    /// Typed view over the interleaved input buffer.
+    ///
+    /// Fails with `Error::Validation` if the stream has no input or
+    /// `T` doesn't match the stream format.
+    ///
+    /// ## Validation
+    ///
+    /// - `buffers` **must** be valid for `properties`, as passed to the stream callback.
+    ///
+    /// # Safety
+    ///
+    /// - `buffers.input` **must** point to `buffers.frames` frames of `properties.num_channels()`
+    ///   samples, which can be read for the lifetime of the returned view.
+    pub unsafe fn input<T: Sample>(&self) -> Result<Interleaved<'_, T>> {
+        let input = self.buffers.input as *const T;
+        self.validate_buffer(input, "input")?;
+        let num_channels = self.properties.num_channels();
+        Ok(Interleaved {
+            samples: slice::from_raw_parts(input, self.buffers.frames * num_channels),
+            num_channels,
+        })
+    }
+
+    /// Typed view over the interleaved output buffer.
+    ///
+    /// Fails with `Error::Validation` if the stream has no output or
+    /// `T` doesn't match the stream format.
+    ///
+    /// ## Validation
+    ///
+    /// - `buffers` **must** be valid for `properties`, as passed to the stream callback.
+    ///
+    /// # Safety
+    ///
+    /// - `buffers.output` **must** point to `buffers.frames` frames of `properties.num_channels()`
+    ///   samples, which can be written for the lifetime of the returned view.
+    pub unsafe fn output<T: Sample>(&mut self) -> Result<InterleavedMut<'_, T>> {
+        let output = self.buffers.output as *mut T;
+        self.validate_buffer(output as *const T, "output")?;
+        let num_channels = self.properties.num_channels();
+        Ok(InterleavedMut {
+            samples: slice::from_raw_parts_mut(output, self.buffers.frames * num_channels),
+            num_channels,
+        })
+    }
+
+    fn validate_buffer<T: Sample>(&self, buffer: *const T, direction: &str) -> Result<()> {
+        if T::FORMAT != self.properties.format {
+            return Error::validation(format!(
+                "sample format {:?} doesn't match stream format {:?}",
+                T::FORMAT,
+                self.properties.format
+            ));
+        }
+        if buffer.is_null() || self.properties.num_channels() == 0 {
+            return Error::validation(format!("stream has no {} buffer", direction));
+        }
+        if buffer.align_offset(mem::align_of::<T>()) != 0 {
+            return Error::validation(format!(
+                "{} buffer is not aligned to its samples",
+                direction
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Typed view over interleaved frames.
+#[derive(Debug, Clone, Copy)]
+pub struct Interleaved<'a, T> {
+    samples: &'a [T],
+    num_channels: usize,
+}
+
+impl<'a, T> Interleaved<'a, T> {
+    /// All samples in interleaved order.
+    pub fn samples(&self) -> &'a [T] {
+        self.samples
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.samples.len() / self.num_channels
+    }
+
+    /// Samples of a single frame, one per channel.
+    pub fn frame(&self, frame: usize) -> Option<&'a [T]> {
+        let start = frame * self.num_channels;
+        self.samples.get(start..start + self.num_channels)
+    }
+
+    /// Iterate over all frames.
+    pub fn frames(&self) -> slice::ChunksExact<'a, T> {
+        self.samples.chunks_exact(self.num_channels)
+    }
+
+    /// Iterate over the samples of a single channel.
+    ///
+    /// Panics if `channel` is out of range.
+    pub fn channel(&self, channel: usize) -> iter::StepBy<slice::Iter<'a, T>> {
+        assert!(channel < self.num_channels, "channel out of range");
+        self.samples[channel..].iter().step_by(self.num_channels)
+    }
+}
+
+/// Mutable typed view over interleaved frames.
+#[derive(Debug)]
+pub struct InterleavedMut<'a, T> {
+    samples: &'a mut [T],
+    num_channels: usize,
+}
+
+impl<'a, T> InterleavedMut<'a, T> {
+    /// All samples in interleaved order.
+    pub fn samples(&self) -> &[T] {
+        self.samples
+    }
+
+    /// All samples in interleaved order.
+    pub fn samples_mut(&mut self) -> &mut [T] {
+        self.samples
+    }
+
+    /// Convert into the underlying sample slice.
+    pub fn into_samples(self) -> &'a mut [T] {
+        self.samples
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.samples.len() / self.num_channels
+    }
+
+    /// Samples of a single frame, one per channel.
+    pub fn frame_mut(&mut self, frame: usize) -> Option<&mut [T]> {
+        let start = frame * self.num_channels;
+        self.samples.get_mut(start..start + self.num_channels)
+    }
+
+    /// Iterate over all frames.
+    pub fn frames_mut(&mut self) -> slice::ChunksExactMut<'_, T> {
+        self.samples.chunks_exact_mut(self.num_channels)
+    }
+
+    /// Iterate over the samples of a single channel.
+    ///
+    /// Panics if `channel` is out of range.
+    pub fn channel_mut(&mut self, channel: usize) -> iter::StepBy<slice::IterMut<'_, T>> {
+        assert!(channel < self.num_channels, "channel out of range");
+        self.samples[channel..]
+            .iter_mut()
+            .step_by(self.num_channels)
+    }
+}
+
 pub type StreamCallback = Box<dyn FnMut(Stream) + Send>;
 
 pub trait Instance {
@@ -262,14 +1097,19 @@ pub trait Instance {
     type Session;
 
     /// Get instance properties.
-    unsafe fn properties() -> InstanceProperties;
+    unsafe fn properties(&self) -> InstanceProperties;
 
     /// Create an instance object.
     ///
+    /// Fails with `Error::BackendUnavailable` if the backend can't be initialized,
+    /// e.g no sound server is running.
+    ///
     /// ## Validation
     ///
     /// - The instance **must** outlive all its child objects.
-    unsafe fn create(name: &str) -> Self;
+    unsafe fn create(name: &str) -> Result<Self>
+    where
+        Self: Sized;
 
     /// Retrieve a list of physical devices of the current instance.
     ///
@@ -305,6 +1145,94 @@ pub trait Instance {
         frame_desc: FrameDesc,
     ) -> bool;
 
+    /// Get the format capabilities of a physical device.
+    ///
+    /// The default implementation probes `physical_device_supports_format` with
+    /// common sample rates and channel layouts. Each property is probed separately,
+    /// based on the default concurrent format of the physical device.
+    ///
+    /// ## Validation
+    ///
+    /// - `physical_device` **must** be a valid handle.
+    ///
+    /// # Safety
+    ///
+    /// - `physical_device` **must** have been enumerated by this instance.
+    /// - The instance **must** not be used concurrently from other threads while probing.
+    unsafe fn physical_device_format_capabilities(
+        &self,
+        physical_device: PhysicalDevice,
+        sharing: SharingMode,
+    ) -> Result<FormatCapabilities> {
+        let default_format = self.physical_device_default_concurrent_format(physical_device)?;
+        let supports =
+            |frame_desc| self.physical_device_supports_format(physical_device, sharing, frame_desc);
+
+        let formats = Format::ALL
+            .iter()
+            .cloned()
+            .filter(|&format| {
+                supports(FrameDesc {
+                    format,
+                    ..default_format
+                })
+            })
+            .collect();
+        let sample_rates = PROBE_SAMPLE_RATES
+            .iter()
+            .cloned()
+            .filter(|&sample_rate| {
+                supports(FrameDesc {
+                    sample_rate,
+                    ..default_format
+                })
+            })
+            .map(SampleRateRange::fixed)
+            .collect();
+
+        let mut channels = vec![default_format.channels];
+        channels.extend(
+            COMMON_CHANNEL_LAYOUTS
+                .iter()
+                .map(|&mask| ChannelLayout::Mask(mask))
+                .filter(|layout| *layout != default_format.channels),
+        );
+        channels.retain(|&channels| {
+            supports(FrameDesc {
+                channels,
+                ..default_format
+            })
+        });
+
+        Ok(FormatCapabilities {
+            formats,
+            sample_rates,
+            channels,
+        })
+    }
+
+    /// Negotiate a format with a physical device.
+    ///
+    /// Returns the desired format if supported, otherwise the closest format supported
+    /// by the physical device together with the mismatching properties.
+    ///
+    /// ## Validation
+    ///
+    /// - `physical_device` **must** be a valid handle.
+    ///
+    /// # Safety
+    ///
+    /// - `physical_device` **must** have been enumerated by this instance.
+    /// - The instance **must** not be used concurrently from other threads while negotiating.
+    unsafe fn negotiate_format(
+        &self,
+        physical_device: PhysicalDevice,
+        sharing: SharingMode,
+        frame_desc: FrameDesc,
+    ) -> Result<NegotiatedFormat> {
+        negotiate_format_from_capabilities(self, physical_device, sharing, frame_desc)
+    }
+
     /// Get default concurrent mode format.
     ///
     /// Returns the default format used for physical devices when
@@ -326,11 +1254,11 @@ pub trait Instance {
     /// ## Validation
     ///
     /// - `physical_device` **must** be a valid handle.
-    /// - If the device properties does not include `StreamFlags::INPUT`, the input channel mask must be empty.
-    /// - If the device properties does not include `StreamFlags::OUTPUT`, the output channel mask must be empty.
-    /// - If output channel mask is not empty, the format consisting of sample desc and output channel mask
+    /// - If the device properties does not include `StreamFlags::INPUT`, the input channel layout must be empty.
+    /// - If the device properties does not include `StreamFlags::OUTPUT`, the output channel layout must be empty.
+    /// - If output channel layout is not empty, the format consisting of sample desc and output channel layout
     ///   **must** be supported by this physical device.
-    /// - If input channel mask is not empty, the format consisting of sample desc and input channel mask
+    /// - If input channel layout is not empty, the format consisting of sample desc and input channel layout
     ///   **must** be supported by this physical device.
     unsafe fn create_device(
         &self,
@@ -339,6 +1267,60 @@ pub trait Instance {
         callback: StreamCallback,
     ) -> Result<Self::Device>;
 
+    /// Create a new logical device with conversion stages.
+    ///
+    /// Inserts the enabled `conversion` stages between the device stream and `callback`.
+    /// The device stream uses the requested sample format and rate if supported,
+    /// otherwise the ones supported by the physical device for enabled conversion stages.
+    ///
+    /// `Device::stream_properties` reports the properties of the device stream,
+    /// excluding the latency of the block stage (`BlockSize::latency`).
+    ///
+    /// ## Validation
+    ///
+    /// - Same as for `create_device`, except for the sample format and rate if the corresponding
+    ///   conversion stage is enabled.
+    ///
+    /// # Safety
+    ///
+    /// - `desc.physical_device` **must** have been enumerated by this instance.
+    /// - The instance **must** outlive the returned device.
+    unsafe fn create_device_with_conversion(
+        &self,
+        desc: DeviceDesc,
+        channels: Channels,
+        conversion: Conversion,
+        mut callback: StreamCallback,
+    ) -> Result<Self::Device> {
+        let mut device_desc = desc.clone();
+        let mut device_channels = channels;
+
+        // Stages closer to the device stream wrap the inner stages.
+        if let Some(block_size) = conversion.block_size {
+            callback = block::block_stage(block_size, callback);
+        }
+        if let Some(resampling) = conversion.sample_rate {
+            device_desc.sample_desc.sample_rate = resample::device_sample_rate(self, &desc)?;
+            callback = resample::resample_stage(desc.sample_desc.sample_rate, resampling, callback);
+        }
+        if let Some(mixing) = conversion.channels {
+            device_channels = remix::device_channels(self, &device_desc, channels)?;
+            let layout = if channels.output.is_empty() {
+                channels.input
+            } else {
+                channels.output
+            };
+            callback = remix::remix_stage(layout, mixing, callback);
+        }
+        if let Some(format_conversion) = conversion.format {
+            device_desc.sample_desc.format =
+                convert::device_format(self, &device_desc, device_channels)?;
+            callback = convert::format_stage(desc.sample_desc.format, format_conversion, callback);
+        }
+
+        self.create_device(device_desc, device_channels, callback)
+    }
+
     /// Create an audio session.
     ///
     /// Audio sessions are needed for ensuring realtime properties for audio streaming.
@@ -356,6 +1338,33 @@ pub trait Instance {
     unsafe fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
     where
         F: FnMut(Event) + Send + 'static;
+
+    /// Get the OS handles of the instance event loop.
+    ///
+    /// Once any handle is ready or the timeout elapsed, `dispatch_pending` must be called.
+    /// The handles may change after dispatching and need to be queried again.
+    /// Fails with `Error::Validation` if not supported by the instance.
+    ///
+    /// # Safety
+    ///
+    /// - The handles are owned by the instance, they **must** not be closed or used
+    ///   after the instance has been dropped.
+    unsafe fn wait_handles(&self) -> Result<WaitHandles> {
+        Error::validation("`wait_handles` not supported by this instance")
+    }
+
+    /// Dispatch pending events of the instance event loop without blocking.
+    ///
+    /// Events include `Event`s passed to the event callback, device streams are dispatched
+    /// by `Device::dispatch_pending`.
+    ///
+    /// # Safety
+    ///
+    /// - The instance **must** not be used concurrently from other threads while dispatching.
+    /// - **Must** not be called from within the event callback.
+    unsafe fn dispatch_pending(&self) -> Result<()> {
+        Error::validation("`dispatch_pending` not supported by this instance")
+    }
 }
 
 pub trait Device {
@@ -376,4 +1385,355 @@ pub trait Device {
     unsafe fn submit_buffers(&mut self, _timeout_ms: u32) -> Result<()> {
         Error::validation("`submit_buffers` not allowed for callback based instances")
     }
+
+    /// Number of frames, which can be submitted without waiting.
+    ///
+    /// Pending events of the audio engine are processed without blocking.
+    /// For duplex streams the minimum of readable and writable frames.
+    ///
+    /// ## Validation
+    ///
+    /// - **Must** only be called for devices, which corresponding instance streaming properties are `Polling`.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    unsafe fn available_frames(&mut self) -> Result<Frames> {
+        Error::validation("`available_frames` not allowed for callback based instances")
+    }
+
+    /// Submit stream buffers without waiting.
+    ///
+    /// Fails with `Error::WouldBlock` if no frames are available,
+    /// otherwise submits the available frames like `submit_buffers`.
+    ///
+    /// ## Validation
+    ///
+    /// - **Must** only be called for devices, which corresponding instance streaming properties are `Polling`.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    /// - The stream callback is invoked on the calling thread, it **must** not access the device.
+    unsafe fn try_submit_buffers(&mut self) -> Result<()> {
+        if self.available_frames()? == 0 {
+            return Err(Error::WouldBlock);
+        }
+        self.submit_buffers(0)
+    }
+
+    /// Poll the readiness of the device stream.
+    ///
+    /// Resolves with the number of frames, which can be submitted without waiting,
+    /// otherwise the waker of `cx` is woken once the device might be ready.
+    /// Fails with `Error::Validation` if not supported by the instance.
+    ///
+    /// Doesn't depend on a specific async runtime, waiting happens on backend internal threads.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    /// - **Must** only be called for devices, which corresponding instance streaming properties are `Polling`.
+    unsafe fn poll_ready(&mut self, _cx: &mut Context) -> Poll<Result<Frames>> {
+        Poll::Ready(Error::validation(
+            "`poll_ready` not supported by this instance",
+        ))
+    }
+
+    /// Get the OS handles signaling readiness of the device stream.
+    ///
+    /// Once any handle is ready or the timeout elapsed, `dispatch_pending` must be called
+    /// before querying the available frames. The handles may change after dispatching.
+    /// Fails with `Error::Validation` if not supported by the instance.
+    ///
+    /// # Safety
+    ///
+    /// - The handles are owned by the device, they **must** not be closed or used
+    ///   after the device has been dropped.
+    unsafe fn wait_handles(&self) -> Result<WaitHandles> {
+        Error::validation("`wait_handles` not supported by this instance")
+    }
+
+    /// Dispatch pending events of the device stream without blocking.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    /// - The stream callback may be invoked on the calling thread, it **must** not access the device.
+    unsafe fn dispatch_pending(&mut self) -> Result<()> {
+        Error::validation("`dispatch_pending` not supported by this instance")
+    }
+
+    /// Wait asynchronously until the device stream is ready, see `poll_ready`.
+    ///
+    /// # Safety
+    ///
+    /// - Same as for `poll_ready`, for every poll of the returned future.
+    unsafe fn ready(&mut self) -> Ready<'_, Self>
+    where
+        Self: Sized,
+    {
+        Ready { device: self }
+    }
+
+    /// Discard all pending audio of the device stream.
+    ///
+    /// Audio submitted for playback, but not yet played, will be dropped.
+    /// Recorded audio, not yet passed to the stream callback, will be dropped.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    /// - **Must** not be called from within the stream callback of the device.
+    unsafe fn flush(&mut self) -> Result<()> {
+        Error::validation("`flush` not supported by this instance")
+    }
+
+    /// Wait until all submitted audio has been played.
+    ///
+    /// Usually followed by `stop` for playing the tail of the stream.
+    ///
+    /// ## Validation
+    ///
+    /// - The device **must** have an output stream.
+    /// - The device **must** be started, a stopped stream would never drain.
+    ///
+    /// In polling mode draining only plays the frames already submitted,
+    /// no further `submit_buffers` calls are required.
+    ///
+    /// # Safety
+    ///
+    /// - The instance, which created the device, **must** still be alive.
+    /// - **Must** not be called from within the stream callback of the device.
+    ///   In callback mode the callback has to keep running for draining the stream.
+    unsafe fn drain(&self) -> Result<()> {
+        Error::validation("`drain` not supported by this instance")
+    }
+}
+
+/// Future resolving once the device stream is ready.
+///
+/// Created by `Device::ready`.
+pub struct Ready<'a, D: ?Sized> {
+    device: &'a mut D,
+}
+
+impl<D: Device + ?Sized> Future for Ready<'_, D> {
+    type Output = Result<Frames>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        unsafe { self.device.poll_ready(cx) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null;
+
+    /// Instance supporting exactly the formats listed in its capabilities.
+    struct Capabilities(FormatCapabilities);
+
+    const DEFAULT_FORMAT: FrameDesc = FrameDesc {
+        format: Format::F32,
+        sample_rate: 48_000,
+        channels: ChannelLayout::Mask(ChannelMask::STEREO),
+    };
+
+    impl Instance for Capabilities {
+        type Device = null::Device;
+        type Session = ();
+
+        unsafe fn properties(&self) -> InstanceProperties {
+            InstanceProperties {
+                driver_id: DriverId::Null,
+                stream_mode: StreamMode::Callback,
+                sharing: SharingModeFlags::all(),
+            }
+        }
+
+        unsafe fn create(_: &str) -> Result<Self> {
+            Error::validation("created by tests only")
+        }
+
+        unsafe fn enumerate_physical_devices(&self) -> Vec<PhysicalDevice> {
+            vec![0]
+        }
+
+        unsafe fn default_physical_input_device(&self) -> Option<PhysicalDevice> {
+            None
+        }
+
+        unsafe fn default_physical_output_device(&self) -> Option<PhysicalDevice> {
+            Some(0)
+        }
+
+        unsafe fn physical_device_properties(
+            &self,
+            _: PhysicalDevice,
+        ) -> Result<PhysicalDeviceProperties> {
+            Ok(PhysicalDeviceProperties {
+                device_name: "capabilities".into(),
+                streams: StreamFlags::OUTPUT,
+                form_factor: FormFactor::Unknown,
+            })
+        }
+
+        unsafe fn physical_device_supports_format(
+            &self,
+            _: PhysicalDevice,
+            _: SharingMode,
+            frame_desc: FrameDesc,
+        ) -> bool {
+            self.0.supports(&frame_desc)
+        }
+
+        unsafe fn physical_device_format_capabilities(
+            &self,
+            _: PhysicalDevice,
+            _: SharingMode,
+        ) -> Result<FormatCapabilities> {
+            Ok(self.0.clone())
+        }
+
+        unsafe fn physical_device_default_concurrent_format(
+            &self,
+            _: PhysicalDevice,
+        ) -> Result<FrameDesc> {
+            Ok(DEFAULT_FORMAT)
+        }
+
+        unsafe fn create_device(
+            &self,
+            _: DeviceDesc,
+            _: Channels,
+            _: StreamCallback,
+        ) -> Result<Self::Device> {
+            Error::validation("devices not supported")
+        }
+
+        unsafe fn create_session(&self, _: usize) -> Result<Self::Session> {
+            Ok(())
+        }
+
+        unsafe fn set_event_callback<F>(&mut self, _: Option<F>) -> Result<()>
+        where
+            F: FnMut(Event) + Send + 'static,
+        {
+            Ok(())
+        }
+    }
+
+    fn capabilities() -> Capabilities {
+        Capabilities(FormatCapabilities {
+            formats: vec![Format::I16, Format::F32],
+            sample_rates: vec![SampleRateRange {
+                min: 44_100,
+                max: 48_000,
+            }],
+            channels: vec![ChannelMask::STEREO.into(), ChannelMask::SURROUND_5_1.into()],
+        })
+    }
+
+    fn negotiate(
+        instance: &Capabilities,
+        sharing: SharingMode,
+        frame_desc: FrameDesc,
+    ) -> Result<NegotiatedFormat> {
+        unsafe { negotiate_format_from_capabilities(instance, 0, sharing, frame_desc) }
+    }
+
+    #[test]
+    fn negotiate_exact() {
+        let frame_desc = FrameDesc {
+            format: Format::I16,
+            sample_rate: 44_100,
+            channels: ChannelMask::SURROUND_5_1.into(),
+        };
+        let negotiated = negotiate(&capabilities(), SharingMode::Exclusive, frame_desc).unwrap();
+        assert_eq!(negotiated, NegotiatedFormat::Exact(frame_desc));
+    }
+
+    #[test]
+    fn negotiate_closest() {
+        let instance = capabilities();
+
+        // Float formats are preferred for float requests, rates are clamped,
+        // more channels are preferred over less.
+        let frame_desc = FrameDesc {
+            format: Format::F64,
+            sample_rate: 96_000,
+            channels: ChannelMask::MONO.into(),
+        };
+        let negotiated = negotiate(&instance, SharingMode::Exclusive, frame_desc).unwrap();
+        assert_eq!(
+            negotiated,
+            NegotiatedFormat::Closest {
+                frame_desc: FrameDesc {
+                    format: Format::F32,
+                    sample_rate: 48_000,
+                    channels: ChannelMask::STEREO.into(),
+                },
+                reason: FormatMismatch::all(),
+            }
+        );
+
+        // Integer formats are preferred for integer requests, even with less precision.
+        let frame_desc = FrameDesc {
+            format: Format::I24,
+            ..DEFAULT_FORMAT
+        };
+        let negotiated = negotiate(&instance, SharingMode::Exclusive, frame_desc).unwrap();
+        assert_eq!(
+            negotiated,
+            NegotiatedFormat::Closest {
+                frame_desc: FrameDesc {
+                    format: Format::I16,
+                    ..DEFAULT_FORMAT
+                },
+                reason: FormatMismatch::FORMAT,
+            }
+        );
+    }
+
+    #[test]
+    fn negotiate_fallback() {
+        let instance = Capabilities(FormatCapabilities {
+            formats: Vec::new(),
+            ..capabilities().0
+        });
+        let frame_desc = FrameDesc {
+            format: Format::I16,
+            sample_rate: 44_100,
+            channels: ChannelMask::MONO.into(),
+        };
+
+        // Concurrent mode falls back to the default format.
+        let negotiated = negotiate(&instance, SharingMode::Concurrent, frame_desc).unwrap();
+        assert_eq!(
+            negotiated,
+            NegotiatedFormat::Closest {
+                frame_desc: DEFAULT_FORMAT,
+                reason: FormatMismatch::all(),
+            }
+        );
+
+        match negotiate(&instance, SharingMode::Exclusive, frame_desc) {
+            Err(Error::Unsupported { .. }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn buffering_periods() {
+        assert_eq!(Buffering::Default.periods(48_000), None);
+        assert_eq!(Buffering::LowLatency.periods(48_000), Some((240, 2)));
+        assert_eq!(Buffering::PowerSaving.periods(44_100), Some((4_410, 4)));
+        let explicit = Buffering::Explicit {
+            period_size: 256,
+            num_periods: 3,
+        };
+        assert_eq!(explicit.periods(48_000), Some((256, 3)));
+        assert_eq!(explicit.periods(DEFAULT_SAMPLE_RATE), Some((256, 3)));
+    }
 }
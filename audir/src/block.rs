@@ -0,0 +1,224 @@
+//! Fixed block size adapter.
+
+use crate::api;
+use crate::convert;
+use std::{ptr, slice};
+
+/// Fill a buffer with silence of the given sample format.
+pub(crate) fn silence(format: api::Format, bytes: &mut [u8]) {
+    let value = match format {
+        api::Format::U8 => 0x80,
+        _ => 0,
+    };
+    bytes.iter_mut().for_each(|b| *b = value);
+}
+
+/// Wrap the stream callback with a fixed block size stage.
+///
+/// The callback is always invoked with exactly `block_size.frames` frames.
+/// Device stream frames are buffered internally, adding up to `block_size.latency()` frames of latency.
+pub(crate) fn block_stage(
+    block_size: api::BlockSize,
+    mut callback: api::StreamCallback,
+) -> api::StreamCallback {
+    let frames = block_size.frames.max(1);
+    let mut primed = false;
+    let mut input_fifo: Vec<u8> = Vec::new();
+    let mut output_fifo: Vec<u8> = Vec::new();
+    let mut input_block = Vec::new();
+    let mut output_block = Vec::new();
+
+    Box::new(move |stream: api::Stream| {
+        let format = stream.properties.format;
+        let frame_size = stream.properties.num_channels() * format.size();
+        if frame_size == 0 {
+            return callback(stream);
+        }
+
+        let has_input = !stream.buffers.input.is_null();
+        let has_output = !stream.buffers.output.is_null();
+        let block_len = frames * frame_size;
+        let device_len = stream.buffers.frames * frame_size;
+
+        let properties = api::StreamProperties {
+            buffer_size: frames,
+            ..stream.properties
+        };
+
+        if !primed {
+            primed = true;
+            // Duplex streams produce output only for complete input blocks,
+            // keep the output ahead by the maximum number of pending input frames.
+            if has_input && has_output {
+                output_fifo.resize(block_len - frame_size, 0);
+                silence(format, &mut output_fifo);
+            }
+        }
+
+        if has_input {
+            let src =
+                unsafe { slice::from_raw_parts(stream.buffers.input as *const u8, device_len) };
+            input_fifo.extend_from_slice(src);
+
+            while input_fifo.len() >= block_len {
+                let input = convert::scratch(&mut input_block, block_len);
+                input.copy_from_slice(&input_fifo[..block_len]);
+                input_fifo.drain(..block_len);
+
+                let output = if has_output {
+                    convert::scratch(&mut output_block, block_len).as_mut_ptr() as *mut ()
+                } else {
+                    ptr::null_mut()
+                };
+                callback(api::Stream {
+                    properties,
+                    buffers: api::StreamBuffers {
+                        frames,
+                        input: input.as_ptr() as _,
+                        output,
+                    },
+                });
+                if has_output {
+                    output_fifo.extend_from_slice(convert::scratch(&mut output_block, block_len));
+                }
+            }
+        } else if has_output {
+            while output_fifo.len() < device_len {
+                let output = convert::scratch(&mut output_block, block_len);
+                callback(api::Stream {
+                    properties,
+                    buffers: api::StreamBuffers {
+                        frames,
+                        input: ptr::null(),
+                        output: output.as_mut_ptr() as _,
+                    },
+                });
+                output_fifo.extend_from_slice(output);
+            }
+        }
+
+        if has_output {
+            if output_fifo.len() < device_len {
+                let len = output_fifo.len();
+                output_fifo.resize(device_len, 0);
+                silence(format, &mut output_fifo[len..]);
+            }
+            let dst =
+                unsafe { slice::from_raw_parts_mut(stream.buffers.output as *mut u8, device_len) };
+            dst.copy_from_slice(&output_fifo[..device_len]);
+            output_fifo.drain(..device_len);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const BLOCK_SIZE: api::BlockSize = api::BlockSize { frames: 64 };
+    const DEVICE_FRAMES: [api::Frames; 6] = [10, 100, 37, 64, 1, 200];
+
+    /// Drive a mono `f32` stream through the stage, returning the device output.
+    fn run(stage: &mut api::StreamCallback, input: Option<&[f32]>, output: bool) -> Vec<f32> {
+        let properties = api::StreamProperties {
+            format: api::Format::F32,
+            channels: api::ChannelMask::MONO.into(),
+            sample_rate: 48_000,
+            buffer_size: 0,
+            num_periods: 2,
+        };
+        let mut device_output = Vec::new();
+        let mut offset = 0;
+        for &frames in &DEVICE_FRAMES {
+            let mut buffer = vec![f32::NAN; if output { frames } else { 0 }];
+            stage(api::Stream {
+                properties: api::StreamProperties {
+                    buffer_size: frames,
+                    ..properties
+                },
+                buffers: api::StreamBuffers {
+                    frames,
+                    input: input.map_or(ptr::null(), |input| input[offset..].as_ptr() as _),
+                    output: if output {
+                        buffer.as_mut_ptr() as _
+                    } else {
+                        ptr::null_mut()
+                    },
+                },
+            });
+            offset += frames;
+            device_output.extend(buffer);
+        }
+        device_output
+    }
+
+    fn counter(len: usize) -> Vec<f32> {
+        (1..=len).map(|i| i as f32).collect()
+    }
+
+    #[test]
+    fn output_order() {
+        let mut next = 1.0;
+        let mut stage = block_stage(
+            BLOCK_SIZE,
+            Box::new(move |mut stream| {
+                assert_eq!(stream.buffers.frames, BLOCK_SIZE.frames);
+                let mut output = unsafe { stream.output::<f32>().unwrap() };
+                for sample in output.samples_mut() {
+                    *sample = next;
+                    next += 1.0;
+                }
+            }),
+        );
+
+        let output = run(&mut stage, None, true);
+        assert_eq!(output, counter(DEVICE_FRAMES.iter().sum()));
+    }
+
+    #[test]
+    fn input_order() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let blocks = received.clone();
+        let mut stage = block_stage(
+            BLOCK_SIZE,
+            Box::new(move |stream| {
+                assert_eq!(stream.buffers.frames, BLOCK_SIZE.frames);
+                let input = unsafe { stream.input::<f32>().unwrap() };
+                blocks.lock().unwrap().extend_from_slice(input.samples());
+            }),
+        );
+
+        let num_frames = DEVICE_FRAMES.iter().sum();
+        run(&mut stage, Some(&counter(num_frames)), false);
+
+        // Only complete blocks are passed to the callback.
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received.len(),
+            num_frames / BLOCK_SIZE.frames * BLOCK_SIZE.frames
+        );
+        assert_eq!(*received, counter(received.len()));
+    }
+
+    #[test]
+    fn duplex_latency() {
+        let mut stage = block_stage(
+            BLOCK_SIZE,
+            Box::new(|mut stream| {
+                let input = unsafe { stream.input::<f32>().unwrap() }.samples().to_vec();
+                let mut output = unsafe { stream.output::<f32>().unwrap() };
+                output.samples_mut().copy_from_slice(&input);
+            }),
+        );
+
+        let num_frames = DEVICE_FRAMES.iter().sum();
+        let output = run(&mut stage, Some(&counter(num_frames)), true);
+
+        // Looped back input, delayed by the primed silence.
+        let latency = BLOCK_SIZE.latency();
+        let mut expected = vec![0.0; latency];
+        expected.extend(counter(num_frames - latency));
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,322 @@
+//! Sample format conversion.
+
+use crate::api::{self, Result};
+use std::{mem, slice};
+
+/// Preferred device formats if the requested format isn't supported.
+const FALLBACK_FORMATS: [api::Format; 3] = [api::Format::F32, api::Format::I32, api::Format::I16];
+
+/// Interleaved sample format converter.
+///
+/// Samples are scaled to the value range of the target format and clipped if exceeding it.
+pub struct Converter {
+    dithering: api::Dithering,
+    seed: u32,
+}
+
+impl Converter {
+    pub fn new(dithering: api::Dithering) -> Self {
+        Converter {
+            dithering,
+            seed: 0x9E37_79B9,
+        }
+    }
+
+    /// Convert raw samples from `src_format` to `dst_format`.
+    ///
+    /// Converts as many samples as fit into both `src` and `dst`.
+    pub fn convert(
+        &mut self,
+        src_format: api::Format,
+        src: &[u8],
+        dst_format: api::Format,
+        dst: &mut [u8],
+    ) {
+        let src_samples = src.chunks_exact(src_format.size());
+        let dst_samples = dst.chunks_exact_mut(dst_format.size());
+
+        if src_format == dst_format {
+            for (src, dst) in src_samples.zip(dst_samples) {
+                dst.copy_from_slice(src);
+            }
+            return;
+        }
+
+        // Dither only if the target format can't represent the source values exactly.
+        let dither = self.dithering == api::Dithering::Triangular
+            && !dst_format.is_float()
+            && (src_format.is_float() || src_format.valid_bits() > dst_format.valid_bits());
+        let lsb = 1.0 / (1u64 << (dst_format.valid_bits() - 1)) as f64;
+
+        for (src, dst) in src_samples.zip(dst_samples) {
+            let mut value = read_sample(src_format, src);
+            if dither {
+                value += self.triangular_noise() * lsb;
+            }
+            write_sample(dst_format, value, dst);
+        }
+    }
+
+    /// Random value in `(-1, 1)` with triangular distribution.
+    fn triangular_noise(&mut self) -> f64 {
+        self.uniform() - self.uniform()
+    }
+
+    /// Random value in `[0, 1)`.
+    fn uniform(&mut self) -> f64 {
+        // xorshift32
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f64 / (u32::MAX as f64 + 1.0)
+    }
+}
+
+/// Read a sample, normalized to `[-1, 1)` for integer formats.
+fn read_sample(format: api::Format, bytes: &[u8]) -> f64 {
+    let bytes2 = || [bytes[0], bytes[1]];
+    let bytes4 = || [bytes[0], bytes[1], bytes[2], bytes[3]];
+    let bytes8 = || {
+        [
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]
+    };
+
+    let value = match format {
+        api::Format::U8 => bytes[0] as i32 - 0x80,
+        api::Format::I16 => i16::from_le_bytes(bytes2()) as i32,
+        api::Format::I16Be => i16::from_be_bytes(bytes2()) as i32,
+        api::Format::I24 => i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8,
+        api::Format::I24Be => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8,
+        api::Format::I24In32 => (i32::from_le_bytes(bytes4()) << 8) >> 8,
+        api::Format::I24In32Be => (i32::from_be_bytes(bytes4()) << 8) >> 8,
+        api::Format::I32 => i32::from_le_bytes(bytes4()),
+        api::Format::I32Be => i32::from_be_bytes(bytes4()),
+        api::Format::F32 => return f32::from_le_bytes(bytes4()) as f64,
+        api::Format::F32Be => return f32::from_be_bytes(bytes4()) as f64,
+        api::Format::F64 => return f64::from_le_bytes(bytes8()),
+        api::Format::F64Be => return f64::from_be_bytes(bytes8()),
+    };
+
+    value as f64 / (1u64 << (format.valid_bits() - 1)) as f64
+}
+
+/// Write a normalized sample, clipping integer formats to their value range.
+fn write_sample(format: api::Format, value: f64, bytes: &mut [u8]) {
+    let quantize = || {
+        let scale = (1u64 << (format.valid_bits() - 1)) as f64;
+        (value * scale).round().max(-scale).min(scale - 1.0) as i32
+    };
+
+    match format {
+        api::Format::U8 => bytes[0] = (quantize() + 0x80) as u8,
+        api::Format::I16 => bytes.copy_from_slice(&(quantize() as i16).to_le_bytes()),
+        api::Format::I16Be => bytes.copy_from_slice(&(quantize() as i16).to_be_bytes()),
+        api::Format::I24 => bytes.copy_from_slice(&quantize().to_le_bytes()[..3]),
+        api::Format::I24Be => bytes.copy_from_slice(&quantize().to_be_bytes()[1..]),
+        api::Format::I24In32 | api::Format::I32 => bytes.copy_from_slice(&quantize().to_le_bytes()),
+        api::Format::I24In32Be | api::Format::I32Be => {
+            bytes.copy_from_slice(&quantize().to_be_bytes())
+        }
+        api::Format::F32 => bytes.copy_from_slice(&(value as f32).to_le_bytes()),
+        api::Format::F32Be => bytes.copy_from_slice(&(value as f32).to_be_bytes()),
+        api::Format::F64 => bytes.copy_from_slice(&value.to_le_bytes()),
+        api::Format::F64Be => bytes.copy_from_slice(&value.to_be_bytes()),
+    }
+}
+
+/// Resize the scratch buffer to `len` bytes.
+///
+/// Backed by `u64` to satisfy the alignment of all sample formats.
+pub(crate) fn scratch(buffer: &mut Vec<u64>, len: usize) -> &mut [u8] {
+    let num_words = (len + mem::size_of::<u64>() - 1) / mem::size_of::<u64>();
+    if buffer.len() < num_words {
+        buffer.resize(num_words, 0);
+    }
+    unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, len) }
+}
+
+/// Wrap the stream callback with a format conversion stage.
+///
+/// The callback observes streams in `format`, regardless of the device stream format.
+pub(crate) fn format_stage(
+    format: api::Format,
+    conversion: api::FormatConversion,
+    mut callback: api::StreamCallback,
+) -> api::StreamCallback {
+    let mut converter = Converter::new(conversion.dithering);
+    let mut input = Vec::new();
+    let mut output = Vec::new();
+
+    Box::new(move |stream: api::Stream| {
+        let device_format = stream.properties.format;
+        if device_format == format {
+            return callback(stream);
+        }
+
+        let num_samples = stream.buffers.frames * stream.properties.num_channels();
+        let mut buffers = stream.buffers;
+
+        if !stream.buffers.input.is_null() {
+            let src = unsafe {
+                slice::from_raw_parts(
+                    stream.buffers.input as *const u8,
+                    num_samples * device_format.size(),
+                )
+            };
+            let dst = scratch(&mut input, num_samples * format.size());
+            converter.convert(device_format, src, format, dst);
+            buffers.input = dst.as_ptr() as _;
+        }
+        if !stream.buffers.output.is_null() {
+            buffers.output = scratch(&mut output, num_samples * format.size()).as_mut_ptr() as _;
+        }
+
+        callback(api::Stream {
+            properties: api::StreamProperties {
+                format,
+                ..stream.properties
+            },
+            buffers,
+        });
+
+        if !stream.buffers.output.is_null() {
+            let src = scratch(&mut output, num_samples * format.size());
+            let dst = unsafe {
+                slice::from_raw_parts_mut(
+                    stream.buffers.output as *mut u8,
+                    num_samples * device_format.size(),
+                )
+            };
+            converter.convert(format, src, device_format, dst);
+        }
+    })
+}
+
+/// Select the device stream format for a format conversion stage.
+///
+/// Keeps the requested format if supported by the physical device.
+pub(crate) unsafe fn device_format<I: api::Instance + ?Sized>(
+    instance: &I,
+    desc: &api::DeviceDesc,
+    channels: api::Channels,
+) -> Result<api::Format> {
+    let default_format =
+        instance.physical_device_default_concurrent_format(desc.physical_device)?;
+    let sample_rate = if desc.sample_desc.sample_rate == api::DEFAULT_SAMPLE_RATE {
+        default_format.sample_rate
+    } else {
+        desc.sample_desc.sample_rate
+    };
+
+    let supports = |format| {
+        [channels.input, channels.output]
+            .iter()
+            .filter(|layout| !layout.is_empty())
+            .all(|&channels| {
+                instance.physical_device_supports_format(
+                    desc.physical_device,
+                    desc.sharing,
+                    api::FrameDesc {
+                        format,
+                        channels,
+                        sample_rate,
+                    },
+                )
+            })
+    };
+
+    let mut candidates = vec![desc.sample_desc.format, default_format.format];
+    candidates.extend_from_slice(&FALLBACK_FORMATS);
+    candidates
+        .into_iter()
+        .find(|&format| supports(format))
+        .ok_or(api::Error::Unsupported { source: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Normalized values, which are exactly representable in all formats.
+    const SAMPLES: [f64; 7] = [-1.0, -0.5, -0.25, 0.0, 0.25, 0.5, 0.75];
+
+    fn encode(format: api::Format, samples: &[f64]) -> Vec<u8> {
+        let mut bytes = vec![0; samples.len() * format.size()];
+        for (&value, dst) in samples.iter().zip(bytes.chunks_exact_mut(format.size())) {
+            write_sample(format, value, dst);
+        }
+        bytes
+    }
+
+    fn decode(format: api::Format, bytes: &[u8]) -> Vec<f64> {
+        bytes
+            .chunks_exact(format.size())
+            .map(|src| read_sample(format, src))
+            .collect()
+    }
+
+    /// Extreme normalized values of the format, exceeding the clipping range for floats.
+    fn value_range(format: api::Format) -> (f64, f64) {
+        if format.is_float() {
+            (-1.5, 1.5)
+        } else {
+            (-1.0, 1.0 - 1.0 / (1u64 << (format.valid_bits() - 1)) as f64)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut converter = Converter::new(api::Dithering::None);
+        for &src_format in &api::Format::ALL {
+            let src = encode(src_format, &SAMPLES);
+            for &dst_format in &api::Format::ALL {
+                let mut dst = vec![0; SAMPLES.len() * dst_format.size()];
+                converter.convert(src_format, &src, dst_format, &mut dst);
+                assert_eq!(
+                    decode(dst_format, &dst),
+                    SAMPLES,
+                    "{:?} -> {:?}",
+                    src_format,
+                    dst_format
+                );
+
+                let mut back = vec![0; src.len()];
+                converter.convert(dst_format, &dst, src_format, &mut back);
+                assert_eq!(
+                    back, src,
+                    "{:?} -> {:?} -> {:?}",
+                    src_format, dst_format, src_format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn clipping() {
+        let mut converter = Converter::new(api::Dithering::None);
+        for &src_format in &api::Format::ALL {
+            let (src_min, src_max) = value_range(src_format);
+            let src = encode(src_format, &[src_min, src_max]);
+            for &dst_format in &api::Format::ALL {
+                let mut dst = vec![0; 2 * dst_format.size()];
+                converter.convert(src_format, &src, dst_format, &mut dst);
+
+                let (dst_min, dst_max) = value_range(dst_format);
+                // Float formats aren't clipped, only rounded to their precision.
+                let expected = if dst_format.is_float() {
+                    decode(dst_format, &encode(dst_format, &[src_min, src_max]))
+                } else {
+                    vec![src_min.max(dst_min), src_max.min(dst_max)]
+                };
+                assert_eq!(
+                    decode(dst_format, &dst),
+                    expected,
+                    "{:?} -> {:?}",
+                    src_format,
+                    dst_format
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Runtime selection of the instance implementation.
+//!
+//! Dispatches to one of the backends compiled for the current target.
+
+use crate::api::{self, Instance as _, Result};
+use crate::null;
+use std::task::{Context, Poll};
+
+#[cfg(target_os = "linux")]
+use crate::pulse;
+#[cfg(windows)]
+use crate::wasapi;
+#[cfg(target_os = "android")]
+use crate::{aaudio, opensles};
+
+macro_rules! dispatch {
+    ($ty:ident, $value:expr, $inner:ident => $body:expr) => {
+        match $value {
+            #[cfg(windows)]
+            $ty::Wasapi($inner) => $body,
+            #[cfg(target_os = "linux")]
+            $ty::Pulse($inner) => $body,
+            #[cfg(target_os = "android")]
+            $ty::AAudio($inner) => $body,
+            #[cfg(target_os = "android")]
+            $ty::OpenSLES($inner) => $body,
+            $ty::Null($inner) => $body,
+        }
+    };
+}
+
+/// Drivers compiled for the current target.
+///
+/// Ordered by preference, `Null` is always available as last option.
+pub fn available_drivers() -> Vec<api::DriverId> {
+    vec![
+        #[cfg(windows)]
+        api::DriverId::Wasapi,
+        #[cfg(target_os = "linux")]
+        api::DriverId::PulseAudio,
+        #[cfg(target_os = "android")]
+        api::DriverId::AAudio,
+        #[cfg(target_os = "android")]
+        api::DriverId::OpenSLES,
+        api::DriverId::Null,
+    ]
+}
+
+/// Instance dispatching to a backend selected at runtime.
+pub enum Instance {
+    #[cfg(windows)]
+    Wasapi(wasapi::Instance),
+    #[cfg(target_os = "linux")]
+    Pulse(pulse::Instance),
+    #[cfg(target_os = "android")]
+    AAudio(aaudio::Instance),
+    #[cfg(target_os = "android")]
+    OpenSLES(opensles::Instance),
+    Null(null::Instance),
+}
+
+pub enum Device {
+    #[cfg(windows)]
+    Wasapi(wasapi::Device),
+    #[cfg(target_os = "linux")]
+    Pulse(pulse::Device),
+    #[cfg(target_os = "android")]
+    AAudio(aaudio::Device),
+    #[cfg(target_os = "android")]
+    OpenSLES(opensles::Device),
+    Null(null::Device),
+}
+
+pub enum Session {
+    #[cfg(windows)]
+    Wasapi(<wasapi::Instance as api::Instance>::Session),
+    #[cfg(target_os = "linux")]
+    Pulse(<pulse::Instance as api::Instance>::Session),
+    #[cfg(target_os = "android")]
+    AAudio(<aaudio::Instance as api::Instance>::Session),
+    #[cfg(target_os = "android")]
+    OpenSLES(<opensles::Instance as api::Instance>::Session),
+    Null(<null::Instance as api::Instance>::Session),
+}
+
+impl Instance {
+    /// Create an instance for a specific driver.
+    ///
+    /// ## Validation
+    ///
+    /// - `driver` **must** be one of the `available_drivers`.
+    ///
+    /// # Safety
+    ///
+    /// - `driver` **must** be available, the backend libraries are loaded without further checks.
+    pub unsafe fn create_with_driver(driver: api::DriverId, name: &str) -> Result<Self> {
+        match driver {
+            #[cfg(windows)]
+            api::DriverId::Wasapi => wasapi::Instance::create(name).map(Instance::Wasapi),
+            #[cfg(target_os = "linux")]
+            api::DriverId::PulseAudio => pulse::Instance::create(name).map(Instance::Pulse),
+            #[cfg(target_os = "android")]
+            api::DriverId::AAudio => aaudio::Instance::create(name).map(Instance::AAudio),
+            #[cfg(target_os = "android")]
+            api::DriverId::OpenSLES => opensles::Instance::create(name).map(Instance::OpenSLES),
+            api::DriverId::Null => null::Instance::create(name).map(Instance::Null),
+            #[allow(unreachable_patterns)]
+            _ => api::Error::validation(format!("driver {:?} not available", driver)),
+        }
+    }
+
+    /// Driver of the selected backend.
+    pub fn driver_id(&self) -> api::DriverId {
+        match self {
+            #[cfg(windows)]
+            Instance::Wasapi(_) => api::DriverId::Wasapi,
+            #[cfg(target_os = "linux")]
+            Instance::Pulse(_) => api::DriverId::PulseAudio,
+            #[cfg(target_os = "android")]
+            Instance::AAudio(_) => api::DriverId::AAudio,
+            #[cfg(target_os = "android")]
+            Instance::OpenSLES(_) => api::DriverId::OpenSLES,
+            Instance::Null(_) => api::DriverId::Null,
+        }
+    }
+}
+
+impl api::Instance for Instance {
+    type Device = Device;
+    type Session = Session;
+
+    unsafe fn properties(&self) -> api::InstanceProperties {
+        dispatch!(Instance, self, instance => instance.properties())
+    }
+
+    /// Create an instance with the most preferred available driver.
+    ///
+    /// Drivers failing to initialize are skipped, falling back to the `Null` driver.
+    unsafe fn create(name: &str) -> Result<Self> {
+        let mut error = None;
+        for driver in available_drivers() {
+            match Instance::create_with_driver(driver, name) {
+                Ok(instance) => return Ok(instance),
+                Err(err) => error = Some(err),
+            }
+        }
+        Err(error.unwrap_or(api::Error::BackendUnavailable { source: None }))
+    }
+
+    unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
+        dispatch!(Instance, self, instance => instance.enumerate_physical_devices())
+    }
+
+    unsafe fn default_physical_input_device(&self) -> Option<api::PhysicalDevice> {
+        dispatch!(Instance, self, instance => instance.default_physical_input_device())
+    }
+
+    unsafe fn default_physical_output_device(&self) -> Option<api::PhysicalDevice> {
+        dispatch!(Instance, self, instance => instance.default_physical_output_device())
+    }
+
+    unsafe fn physical_device_properties(
+        &self,
+        physical_device: api::PhysicalDevice,
+    ) -> Result<api::PhysicalDeviceProperties> {
+        dispatch!(Instance, self, instance => instance.physical_device_properties(physical_device))
+    }
+
+    unsafe fn physical_device_supports_format(
+        &self,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+        frame_desc: api::FrameDesc,
+    ) -> bool {
+        dispatch!(Instance, self, instance => {
+            instance.physical_device_supports_format(physical_device, sharing, frame_desc)
+        })
+    }
+
+    unsafe fn physical_device_format_capabilities(
+        &self,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+    ) -> Result<api::FormatCapabilities> {
+        dispatch!(Instance, self, instance => {
+            instance.physical_device_format_capabilities(physical_device, sharing)
+        })
+    }
+
+    unsafe fn negotiate_format(
+        &self,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+        frame_desc: api::FrameDesc,
+    ) -> Result<api::NegotiatedFormat> {
+        dispatch!(Instance, self, instance => {
+            instance.negotiate_format(physical_device, sharing, frame_desc)
+        })
+    }
+
+    unsafe fn physical_device_default_concurrent_format(
+        &self,
+        physical_device: api::PhysicalDevice,
+    ) -> Result<api::FrameDesc> {
+        dispatch!(Instance, self, instance => {
+            instance.physical_device_default_concurrent_format(physical_device)
+        })
+    }
+
+    unsafe fn create_device(
+        &self,
+        desc: api::DeviceDesc,
+        channels: api::Channels,
+        callback: api::StreamCallback,
+    ) -> Result<Device> {
+        match self {
+            #[cfg(windows)]
+            Instance::Wasapi(instance) => instance
+                .create_device(desc, channels, callback)
+                .map(Device::Wasapi),
+            #[cfg(target_os = "linux")]
+            Instance::Pulse(instance) => instance
+                .create_device(desc, channels, callback)
+                .map(Device::Pulse),
+            #[cfg(target_os = "android")]
+            Instance::AAudio(instance) => instance
+                .create_device(desc, channels, callback)
+                .map(Device::AAudio),
+            #[cfg(target_os = "android")]
+            Instance::OpenSLES(instance) => instance
+                .create_device(desc, channels, callback)
+                .map(Device::OpenSLES),
+            Instance::Null(instance) => instance
+                .create_device(desc, channels, callback)
+                .map(Device::Null),
+        }
+    }
+
+    unsafe fn create_device_with_conversion(
+        &self,
+        desc: api::DeviceDesc,
+        channels: api::Channels,
+        conversion: api::Conversion,
+        callback: api::StreamCallback,
+    ) -> Result<Device> {
+        match self {
+            #[cfg(windows)]
+            Instance::Wasapi(instance) => instance
+                .create_device_with_conversion(desc, channels, conversion, callback)
+                .map(Device::Wasapi),
+            #[cfg(target_os = "linux")]
+            Instance::Pulse(instance) => instance
+                .create_device_with_conversion(desc, channels, conversion, callback)
+                .map(Device::Pulse),
+            #[cfg(target_os = "android")]
+            Instance::AAudio(instance) => instance
+                .create_device_with_conversion(desc, channels, conversion, callback)
+                .map(Device::AAudio),
+            #[cfg(target_os = "android")]
+            Instance::OpenSLES(instance) => instance
+                .create_device_with_conversion(desc, channels, conversion, callback)
+                .map(Device::OpenSLES),
+            Instance::Null(instance) => instance
+                .create_device_with_conversion(desc, channels, conversion, callback)
+                .map(Device::Null),
+        }
+    }
+
+    unsafe fn create_session(&self, sample_rate: usize) -> Result<Session> {
+        match self {
+            #[cfg(windows)]
+            Instance::Wasapi(instance) => instance.create_session(sample_rate).map(Session::Wasapi),
+            #[cfg(target_os = "linux")]
+            Instance::Pulse(instance) => instance.create_session(sample_rate).map(Session::Pulse),
+            #[cfg(target_os = "android")]
+            Instance::AAudio(instance) => instance.create_session(sample_rate).map(Session::AAudio),
+            #[cfg(target_os = "android")]
+            Instance::OpenSLES(instance) => {
+                instance.create_session(sample_rate).map(Session::OpenSLES)
+            }
+            Instance::Null(instance) => instance.create_session(sample_rate).map(Session::Null),
+        }
+    }
+
+    unsafe fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
+    where
+        F: FnMut(api::Event) + Send + 'static,
+    {
+        dispatch!(Instance, self, instance => instance.set_event_callback(callback))
+    }
+
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        dispatch!(Instance, self, instance => instance.wait_handles())
+    }
+
+    unsafe fn dispatch_pending(&self) -> Result<()> {
+        dispatch!(Instance, self, instance => instance.dispatch_pending())
+    }
+}
+
+impl api::Device for Device {
+    unsafe fn start(&self) {
+        dispatch!(Device, self, device => device.start())
+    }
+
+    unsafe fn stop(&self) {
+        dispatch!(Device, self, device => device.stop())
+    }
+
+    unsafe fn stream_properties(&self) -> api::StreamProperties {
+        dispatch!(Device, self, device => device.stream_properties())
+    }
+
+    unsafe fn submit_buffers(&mut self, timeout_ms: u32) -> Result<()> {
+        dispatch!(Device, self, device => device.submit_buffers(timeout_ms))
+    }
+
+    unsafe fn available_frames(&mut self) -> Result<api::Frames> {
+        dispatch!(Device, self, device => device.available_frames())
+    }
+
+    unsafe fn try_submit_buffers(&mut self) -> Result<()> {
+        dispatch!(Device, self, device => device.try_submit_buffers())
+    }
+
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        dispatch!(Device, self, device => device.wait_handles())
+    }
+
+    unsafe fn dispatch_pending(&mut self) -> Result<()> {
+        dispatch!(Device, self, device => device.dispatch_pending())
+    }
+
+    unsafe fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<api::Frames>> {
+        dispatch!(Device, self, device => device.poll_ready(cx))
+    }
+
+    unsafe fn flush(&mut self) -> Result<()> {
+        dispatch!(Device, self, device => device.flush())
+    }
+
+    unsafe fn drain(&self) -> Result<()> {
+        dispatch!(Device, self, device => device.drain())
+    }
+}
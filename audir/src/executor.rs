@@ -0,0 +1,275 @@
+//! Executors for streaming devices without driving the stream from the application.
+
+use crate::api::{self, Device as _, Result};
+use std::marker::PhantomData;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Maximum time a single `submit_buffers` call waits for buffers.
+///
+/// Bounds the latency for processing commands of a running executor.
+const SUBMIT_TIMEOUT_MS: u32 = 100;
+
+enum Command {
+    Start,
+    Stop,
+    Exit,
+}
+
+/// Executor streaming a device.
+///
+/// Devices of polling instances are driven by a dedicated thread with an audio session,
+/// devices of callback based instances are forwarded to the internal executor of the backend.
+/// In both cases the stream callback is invoked without further involvement of the application,
+/// matching the behavior of `StreamMode::Callback`.
+///
+/// The executor owns the device and borrows its instance, which stays usable while the executor runs.
+/// Executor threads dispatch pending instance events, invoking the event callback of the instance.
+pub struct Executor<'a, I: api::Instance> {
+    properties: api::StreamProperties,
+    inner: Inner<I>,
+    _instance: PhantomData<&'a I>,
+}
+
+enum Inner<I: api::Instance> {
+    Callback(I::Device),
+    /// The device is owned by the executor thread.
+    Polling {
+        commands: mpsc::Sender<Command>,
+        thread: Option<thread::JoinHandle<()>>,
+        /// Error which terminated the executor thread.
+        error: Arc<Mutex<Option<api::Error>>>,
+    },
+}
+
+/// Instance reference passed to the executor thread.
+struct InstanceRef<I>(*const I);
+
+// The instance is shared with the executor thread, which is joined before the borrow ends.
+unsafe impl<I: Sync> Send for InstanceRef<I> {}
+
+impl<'a, I> Executor<'a, I>
+where
+    I: api::Instance + Sync + 'static,
+    I::Device: Send + 'static,
+{
+    /// Spawn an executor for a device.
+    ///
+    /// The device is stopped initially.
+    /// For polling instances the device is moved to an executor thread, which creates
+    /// an audio session with the stream sample rate. Failures on session creation are returned.
+    /// The device is dropped once the executor finished.
+    ///
+    /// # Safety
+    ///
+    /// - `device` **must** be created from `instance`.
+    /// - The executor **must** be dropped or joined, not leaked, as the executor thread
+    ///   accesses `instance` until then.
+    pub unsafe fn spawn(instance: &'a I, device: I::Device) -> Result<Self> {
+        let properties = device.stream_properties();
+        if instance.properties().stream_mode == api::StreamMode::Callback {
+            return Ok(Executor {
+                properties,
+                inner: Inner::Callback(device),
+                _instance: PhantomData,
+            });
+        }
+
+        let error = Arc::new(Mutex::new(None));
+        let (commands, command_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+
+        let thread_error = error.clone();
+        let instance_ref = InstanceRef(instance as *const I);
+        let thread = thread::Builder::new()
+            .name("audir-executor".into())
+            .spawn(move || {
+                let instance = &*instance_ref.0;
+                let mut device = device;
+
+                let _session = match instance.create_session(properties.sample_rate) {
+                    Ok(session) => {
+                        let _ = ready_tx.send(Ok(()));
+                        session
+                    }
+                    Err(err) => {
+                        let _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                };
+
+                if let Err(err) = run(instance, &mut device, command_rx) {
+                    log::error!("executor terminated: {}", err);
+                    *thread_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(err);
+                }
+            })
+            .map_err(|err| api::Error::Internal {
+                cause: format!("failed to spawn executor thread: {}", err),
+            })?;
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(Executor {
+                properties,
+                inner: Inner::Polling {
+                    commands,
+                    thread: Some(thread),
+                    error,
+                },
+                _instance: PhantomData,
+            }),
+            Ok(Err(err)) => {
+                let _ = thread.join();
+                Err(err)
+            }
+            Err(_) => {
+                let _ = thread.join();
+                Err(api::Error::Internal {
+                    cause: "executor thread panicked".into(),
+                })
+            }
+        }
+    }
+}
+
+impl<I: api::Instance> Executor<'_, I> {
+    /// Stream properties of the executed device.
+    pub fn stream_properties(&self) -> api::StreamProperties {
+        self.properties
+    }
+
+    /// Start streaming.
+    ///
+    /// Fails with the error, which terminated the executor thread.
+    pub fn start(&self) -> Result<()> {
+        self.send(Command::Start)
+    }
+
+    /// Stop streaming.
+    ///
+    /// Fails with the error, which terminated the executor thread.
+    pub fn stop(&self) -> Result<()> {
+        self.send(Command::Stop)
+    }
+
+    /// Stop streaming and wait for the executor to finish.
+    ///
+    /// Returns the error, which terminated the executor thread.
+    pub fn join(mut self) -> Result<()> {
+        self.terminate()
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        match self.inner {
+            Inner::Callback(ref device) => {
+                unsafe {
+                    match command {
+                        Command::Start => device.start(),
+                        Command::Stop | Command::Exit => device.stop(),
+                    }
+                }
+                Ok(())
+            }
+            Inner::Polling {
+                ref commands,
+                ref error,
+                ..
+            } => {
+                if let Some(err) = error.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+                    return Err(err);
+                }
+                commands.send(command).map_err(|_| api::Error::Internal {
+                    cause: "executor thread terminated".into(),
+                })
+            }
+        }
+    }
+
+    fn terminate(&mut self) -> Result<()> {
+        match self.inner {
+            Inner::Callback(ref device) => {
+                unsafe { device.stop() };
+                Ok(())
+            }
+            Inner::Polling {
+                ref commands,
+                ref mut thread,
+                ref error,
+            } => {
+                let thread = match thread.take() {
+                    Some(thread) => thread,
+                    None => return Ok(()),
+                };
+                let _ = commands.send(Command::Exit);
+                if thread.join().is_err() {
+                    return Err(api::Error::Internal {
+                        cause: "executor thread panicked".into(),
+                    });
+                }
+                match error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl<I: api::Instance> Drop for Executor<'_, I> {
+    fn drop(&mut self) {
+        let _ = self.terminate();
+    }
+}
+
+/// Run loop of the executor thread.
+///
+/// Buffers are only submitted and instance events dispatched while the device is started,
+/// a stopped executor blocks on the next command.
+unsafe fn run<I: api::Instance>(
+    instance: &I,
+    device: &mut I::Device,
+    commands: mpsc::Receiver<Command>,
+) -> Result<()> {
+    let mut running = false;
+    let mut dispatch = true;
+    loop {
+        let command = if running {
+            match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => Some(Command::Exit),
+            }
+        } else {
+            Some(commands.recv().unwrap_or(Command::Exit))
+        };
+
+        match command {
+            Some(Command::Start) if !running => {
+                device.start();
+                running = true;
+            }
+            Some(Command::Stop) if running => {
+                device.stop();
+                running = false;
+            }
+            Some(Command::Exit) => {
+                if running {
+                    device.stop();
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if running {
+            match api::dispatch_events(instance, &mut dispatch)
+                .and_then(|()| device.submit_buffers(SUBMIT_TIMEOUT_MS))
+            {
+                Ok(()) | Err(api::Error::Timeout { .. }) => {}
+                Err(err) => {
+                    device.stop();
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
@@ -12,7 +12,18 @@ pub mod aaudio;
 
 pub mod null;
 
+pub mod dynamic;
+
+pub mod safe;
+
 pub(crate) mod api;
+mod block;
+pub mod convert;
+pub mod executor;
 mod handle;
+pub mod polling;
+pub mod remix;
+pub mod resample;
+mod ring;
 
 pub use crate::api::*;
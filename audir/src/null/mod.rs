@@ -1,13 +1,25 @@
-use crate::{api, api::Result};
+use crate::{api, api::Result, block, convert};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const NULL_DEVICE: api::PhysicalDevice = 0;
+
+/// Timer period for streams without sample rate.
+const DEFAULT_PERIOD: Duration = Duration::from_millis(10);
+
+/// Period size for streams without buffering request.
+const DEFAULT_BUFFER_SIZE: api::Frames = 480;
+
 pub struct Instance;
 
 impl api::Instance for Instance {
     type Device = Device;
     type Session = ();
 
-    unsafe fn properties() -> api::InstanceProperties {
+    unsafe fn properties(&self) -> api::InstanceProperties {
         api::InstanceProperties {
             driver_id: api::DriverId::Null,
             stream_mode: api::StreamMode::Callback,
@@ -15,8 +27,8 @@ impl api::Instance for Instance {
         }
     }
 
-    unsafe fn create(_: &str) -> Self {
-        Instance
+    unsafe fn create(_: &str) -> Result<Self> {
+        Ok(Instance)
     }
 
     unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
@@ -51,6 +63,24 @@ impl api::Instance for Instance {
         true
     }
 
+    unsafe fn physical_device_format_capabilities(
+        &self,
+        _: api::PhysicalDevice,
+        _: api::SharingMode,
+    ) -> Result<api::FormatCapabilities> {
+        Ok(api::FormatCapabilities {
+            formats: api::Format::ALL.to_vec(),
+            sample_rates: vec![api::SampleRateRange {
+                min: 1,
+                max: usize::MAX,
+            }],
+            channels: api::COMMON_CHANNEL_LAYOUTS
+                .iter()
+                .map(|&mask| mask.into())
+                .collect(),
+        })
+    }
+
     unsafe fn physical_device_default_concurrent_format(
         &self,
         _: api::PhysicalDevice,
@@ -58,17 +88,43 @@ impl api::Instance for Instance {
         Ok(api::FrameDesc {
             format: api::Format::F32,
             sample_rate: 0,
-            channels: api::ChannelMask::empty(),
+            channels: api::ChannelLayout::empty(),
         })
     }
 
     unsafe fn create_device(
         &self,
-        _: api::DeviceDesc,
-        _: api::Channels,
-        _: api::StreamCallback,
+        desc: api::DeviceDesc,
+        channels: api::Channels,
+        callback: api::StreamCallback,
     ) -> Result<Self::Device> {
-        Ok(Device)
+        if channels.input.is_empty() && channels.output.is_empty() {
+            return api::Error::validation("input and output channel masks are empty");
+        }
+
+        let sample_rate = desc.sample_desc.sample_rate;
+        let (buffer_size, num_periods) = match desc.buffering.periods(sample_rate) {
+            Some((buffer_size, num_periods)) if buffer_size > 0 => (buffer_size, num_periods),
+            _ => (DEFAULT_BUFFER_SIZE, 1),
+        };
+        let properties = api::StreamProperties {
+            format: desc.sample_desc.format,
+            channels: if channels.output.is_empty() {
+                channels.input
+            } else {
+                channels.output
+            },
+            sample_rate,
+            buffer_size,
+            num_periods,
+        };
+
+        Ok(Device {
+            properties,
+            stream: StreamThread::spawn(properties, channels, callback)?,
+            deadline: None,
+            timer: None,
+        })
     }
 
     unsafe fn create_session(&self, _sample_rate: usize) -> Result<Self::Session> {
@@ -83,22 +139,281 @@ impl api::Instance for Instance {
     }
 }
 
-pub struct Device;
+/// Duration of a stream period.
+fn period(properties: &api::StreamProperties) -> Duration {
+    if properties.sample_rate == 0 {
+        DEFAULT_PERIOD
+    } else {
+        Duration::from_secs(properties.buffer_size as _) / properties.sample_rate as u32
+    }
+}
+
+/// Stream thread state.
+#[derive(Default)]
+struct StreamState {
+    running: bool,
+    exit: bool,
+}
+
+type SharedStreamState = Arc<(Mutex<StreamState>, Condvar)>;
+
+/// Lock state shared with a device thread, which holds no broken state on panics.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Thread invoking the stream callback once per period while the device is started.
+///
+/// Input buffers are silent, output buffers are discarded.
+struct StreamThread {
+    shared: SharedStreamState,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl StreamThread {
+    fn spawn(
+        properties: api::StreamProperties,
+        channels: api::Channels,
+        callback: api::StreamCallback,
+    ) -> Result<Self> {
+        let shared = SharedStreamState::default();
+        let thread_shared = shared.clone();
+        let thread = thread::Builder::new()
+            .name("audir-null-stream".into())
+            .spawn(move || stream_loop(&thread_shared, properties, channels, callback))
+            .map_err(|err| api::Error::Internal {
+                cause: format!("failed to spawn stream thread: {}", err),
+            })?;
+
+        Ok(StreamThread {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    fn set_running(&self, running: bool) {
+        lock(&self.shared.0).running = running;
+        self.shared.1.notify_one();
+    }
+}
+
+impl Drop for StreamThread {
+    fn drop(&mut self) {
+        lock(&self.shared.0).exit = true;
+        self.shared.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stream_loop(
+    shared: &SharedStreamState,
+    properties: api::StreamProperties,
+    channels: api::Channels,
+    mut callback: api::StreamCallback,
+) {
+    let format = properties.format;
+    let frames = properties.buffer_size;
+    let period = period(&properties);
+
+    let mut input = Vec::new();
+    let input_len = frames * channels.input.num_channels() * format.size();
+    block::silence(format, convert::scratch(&mut input, input_len));
+    let mut output = Vec::new();
+    let output_len = frames * channels.output.num_channels() * format.size();
+
+    let mut deadline = None;
+    let mut state = lock(&shared.0);
+    loop {
+        if state.exit {
+            return;
+        }
+        if !state.running {
+            deadline = None;
+            state = shared.1.wait(state).unwrap_or_else(PoisonError::into_inner);
+            continue;
+        }
+
+        let now = Instant::now();
+        let next = *deadline.get_or_insert(now);
+        if now < next {
+            state = shared
+                .1
+                .wait_timeout(state, next - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+        // Don't accumulate periods missed by the callback.
+        deadline = Some((next + period).max(now));
+        drop(state);
+
+        let buffers = api::StreamBuffers {
+            frames,
+            input: if input_len > 0 {
+                convert::scratch(&mut input, input_len).as_ptr() as _
+            } else {
+                std::ptr::null()
+            },
+            output: if output_len > 0 {
+                convert::scratch(&mut output, output_len).as_mut_ptr() as _
+            } else {
+                std::ptr::null_mut()
+            },
+        };
+        let stream = api::Stream {
+            properties,
+            buffers,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(|| callback(stream))).is_err() {
+            log::error!("stream callback panicked");
+            return;
+        }
+
+        state = lock(&shared.0);
+    }
+}
+
+/// Timer thread state.
+#[derive(Default)]
+struct TimerState {
+    /// Waker of the task waiting for readiness and the time to wake it.
+    wake: Option<(Instant, Waker)>,
+    exit: bool,
+}
+
+type SharedTimerState = Arc<(Mutex<TimerState>, Condvar)>;
+
+/// Thread waking tasks waiting for readiness of the next period.
+struct Timer {
+    shared: SharedTimerState,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    fn spawn() -> std::io::Result<Self> {
+        let shared = SharedTimerState::default();
+        let thread_shared = shared.clone();
+        let thread = thread::Builder::new()
+            .name("audir-null-timer".into())
+            .spawn(move || timer_loop(&thread_shared))?;
+
+        Ok(Timer {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wake `waker` at `deadline`, replacing the pending wake.
+    fn wake_at(&self, deadline: Instant, waker: Waker) {
+        lock(&self.shared.0).wake = Some((deadline, waker));
+        self.shared.1.notify_one();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        lock(&self.shared.0).exit = true;
+        self.shared.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn timer_loop(shared: &SharedTimerState) {
+    let mut state = lock(&shared.0);
+    loop {
+        if state.exit {
+            return;
+        }
+
+        let deadline = match state.wake {
+            Some((deadline, _)) => deadline,
+            None => {
+                state = shared.1.wait(state).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+        if now < deadline {
+            state = shared
+                .1
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            continue;
+        }
+
+        if let Some((_, waker)) = state.wake.take() {
+            drop(state);
+            waker.wake();
+            state = lock(&shared.0);
+        }
+    }
+}
+
+pub struct Device {
+    properties: api::StreamProperties,
+    stream: StreamThread,
+    /// Time at which the next period becomes ready.
+    deadline: Option<Instant>,
+    /// Wakes tasks waiting for readiness, spawned on the first pending readiness poll.
+    timer: Option<Timer>,
+}
 
 impl api::Device for Device {
-    unsafe fn start(&self) {}
+    unsafe fn start(&self) {
+        self.stream.set_running(true);
+    }
 
-    unsafe fn stop(&self) {}
+    unsafe fn stop(&self) {
+        self.stream.set_running(false);
+    }
 
     unsafe fn stream_properties(&self) -> api::StreamProperties {
-        api::StreamProperties {
-            channels: api::ChannelMask::empty(),
-            sample_rate: 0,
-            buffer_size: 0,
-        }
+        self.properties
     }
 
     unsafe fn submit_buffers(&mut self, _: u32) -> api::Result<()> {
         Ok(())
     }
+
+    unsafe fn flush(&mut self) -> api::Result<()> {
+        Ok(())
+    }
+
+    /// Ready once per period, driven by a timer.
+    unsafe fn poll_ready(&mut self, cx: &mut Context) -> Poll<api::Result<api::Frames>> {
+        let now = Instant::now();
+        let period = period(&self.properties);
+        let deadline = *self.deadline.get_or_insert(now);
+
+        if now >= deadline {
+            // Don't accumulate periods missed by the application.
+            self.deadline = Some((deadline + period).max(now));
+            return Poll::Ready(Ok(self.properties.buffer_size.max(1)));
+        }
+
+        if self.timer.is_none() {
+            match Timer::spawn() {
+                Ok(timer) => self.timer = Some(timer),
+                Err(err) => {
+                    return Poll::Ready(Err(api::Error::Internal {
+                        cause: format!("failed to spawn timer thread: {}", err),
+                    }))
+                }
+            }
+        }
+        if let Some(ref timer) = self.timer {
+            timer.wake_at(deadline, cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    unsafe fn drain(&self) -> api::Result<()> {
+        Ok(())
+    }
 }
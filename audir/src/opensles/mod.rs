@@ -1,22 +1,105 @@
 use crate::{api, api::Result};
 use audir_sles as sles;
 use std::os::raw::c_void;
+use std::panic;
 use std::ptr;
 
-const BUFFER_NUM_FRAMES: usize = 1024; // TODO: random
-const BUFFER_CHAIN_SIZE: usize = 3; // TODO
+const DEFAULT_BUFFER_NUM_FRAMES: usize = 1024; // TODO: random
+const DEFAULT_BUFFER_CHAIN_SIZE: usize = 3; // TODO
 
 const DEFAULT_PHYSICAL_DEVICE: api::PhysicalDevice = 0;
-
-fn map_channel_mask(mask: api::ChannelMask) -> sles::SLuint32 {
-    let mut channels = 0;
-    if mask.contains(api::ChannelMask::FRONT_LEFT) {
-        channels |= sles::SL_SPEAKER_FRONT_LEFT;
+const DEFAULT_SAMPLE_RATE: usize = 48_000;
+
+/// Speaker positions in channel mask order.
+const SPEAKER_POSITIONS: [(api::ChannelMask, sles::SLuint32); 18] = [
+    (api::ChannelMask::FRONT_LEFT, sles::SL_SPEAKER_FRONT_LEFT),
+    (api::ChannelMask::FRONT_RIGHT, sles::SL_SPEAKER_FRONT_RIGHT),
+    (
+        api::ChannelMask::FRONT_CENTER,
+        sles::SL_SPEAKER_FRONT_CENTER,
+    ),
+    (
+        api::ChannelMask::LOW_FREQUENCY,
+        sles::SL_SPEAKER_LOW_FREQUENCY,
+    ),
+    (api::ChannelMask::BACK_LEFT, sles::SL_SPEAKER_BACK_LEFT),
+    (api::ChannelMask::BACK_RIGHT, sles::SL_SPEAKER_BACK_RIGHT),
+    (
+        api::ChannelMask::FRONT_LEFT_OF_CENTER,
+        sles::SL_SPEAKER_FRONT_LEFT_OF_CENTER,
+    ),
+    (
+        api::ChannelMask::FRONT_RIGHT_OF_CENTER,
+        sles::SL_SPEAKER_FRONT_RIGHT_OF_CENTER,
+    ),
+    (api::ChannelMask::BACK_CENTER, sles::SL_SPEAKER_BACK_CENTER),
+    (api::ChannelMask::SIDE_LEFT, sles::SL_SPEAKER_SIDE_LEFT),
+    (api::ChannelMask::SIDE_RIGHT, sles::SL_SPEAKER_SIDE_RIGHT),
+    (api::ChannelMask::TOP_CENTER, sles::SL_SPEAKER_TOP_CENTER),
+    (
+        api::ChannelMask::TOP_FRONT_LEFT,
+        sles::SL_SPEAKER_TOP_FRONT_LEFT,
+    ),
+    (
+        api::ChannelMask::TOP_FRONT_CENTER,
+        sles::SL_SPEAKER_TOP_FRONT_CENTER,
+    ),
+    (
+        api::ChannelMask::TOP_FRONT_RIGHT,
+        sles::SL_SPEAKER_TOP_FRONT_RIGHT,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_LEFT,
+        sles::SL_SPEAKER_TOP_BACK_LEFT,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_CENTER,
+        sles::SL_SPEAKER_TOP_BACK_CENTER,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_RIGHT,
+        sles::SL_SPEAKER_TOP_BACK_RIGHT,
+    ),
+];
+
+/// Channel index mask flag for non-positional channels (Android 7.0+).
+const SL_ANDROID_SPEAKER_NON_POSITIONAL: sles::SLuint32 = 0x8000_0000;
+
+/// Maximum number of non-positional channels (`AUDIO_CHANNEL_COUNT_MAX`).
+const MAX_DISCRETE_CHANNELS: usize = 30;
+
+fn map_channel_layout(channels: api::ChannelLayout) -> Result<sles::SLuint32> {
+    match channels {
+        api::ChannelLayout::Mask(mask) => Ok(SPEAKER_POSITIONS
+            .iter()
+            .filter(|(channel, _)| mask.contains(*channel))
+            .fold(0, |channels, (_, speaker)| channels | speaker)),
+        api::ChannelLayout::Discrete(num_channels) if num_channels > MAX_DISCRETE_CHANNELS => {
+            api::Error::validation(format!(
+                "unsupported number of channels ({}), OpenSLES supports at most {}",
+                num_channels, MAX_DISCRETE_CHANNELS
+            ))
+        }
+        api::ChannelLayout::Discrete(num_channels) => {
+            Ok(SL_ANDROID_SPEAKER_NON_POSITIONAL | ((1 << num_channels) - 1))
+        }
     }
-    if mask.contains(api::ChannelMask::FRONT_RIGHT) {
-        channels |= sles::SL_SPEAKER_FRONT_RIGHT;
+}
+
+fn check(result: sles::SLresult) -> Result<()> {
+    let source = Some(api::NativeError::OpenSLES(result as _));
+    match result {
+        sles::SL_RESULT_SUCCESS => Ok(()),
+        sles::SL_RESULT_CONTENT_UNSUPPORTED | sles::SL_RESULT_FEATURE_UNSUPPORTED => {
+            Err(api::Error::Unsupported { source })
+        }
+        sles::SL_RESULT_RESOURCE_ERROR | sles::SL_RESULT_RESOURCE_LOST => {
+            Err(api::Error::DeviceBusy { source })
+        }
+        _ => Err(api::Error::Internal {
+            cause: format!("OpenSLES error: {}", result),
+        }),
     }
-    channels
 }
 
 struct CallbackData {
@@ -24,6 +107,7 @@ struct CallbackData {
     cur_buffer: usize,
     callback: api::StreamCallback,
     frame_desc: api::FrameDesc,
+    buffer_num_frames: usize,
 }
 
 pub struct Instance {
@@ -35,7 +119,7 @@ impl api::Instance for Instance {
     type Device = Device;
     type Session = ();
 
-    unsafe fn properties() -> api::InstanceProperties {
+    unsafe fn properties(&self) -> api::InstanceProperties {
         api::InstanceProperties {
             driver_id: api::DriverId::OpenSLES,
             stream_mode: api::StreamMode::Callback,
@@ -43,9 +127,13 @@ impl api::Instance for Instance {
         }
     }
 
-    unsafe fn create(_name: &str) -> Self {
+    unsafe fn create(_name: &str) -> Result<Self> {
+        let unavailable = |result: sles::SLresult| api::Error::BackendUnavailable {
+            source: Some(api::NativeError::OpenSLES(result as _)),
+        };
+
         let mut instance = ptr::null();
-        sles::slCreateEngine(
+        let result = sles::slCreateEngine(
             &mut instance,
             0,
             ptr::null(),
@@ -53,16 +141,28 @@ impl api::Instance for Instance {
             ptr::null(),
             ptr::null_mut(),
         );
-        ((**instance).Realize).unwrap()(instance, sles::SL_BOOLEAN_FALSE as _);
+        if result != sles::SL_RESULT_SUCCESS as _ {
+            return Err(unavailable(result));
+        }
+
+        let result = ((**instance).Realize).unwrap()(instance, sles::SL_BOOLEAN_FALSE as _);
+        if result != sles::SL_RESULT_SUCCESS as _ {
+            ((**instance).Destroy).unwrap()(instance);
+            return Err(unavailable(result));
+        }
 
         let mut engine = ptr::null();
-        ((**instance).GetInterface).unwrap()(
+        let result = ((**instance).GetInterface).unwrap()(
             instance,
             sles::SL_IID_ENGINE,
             &mut engine as *mut _ as _,
         );
+        if result != sles::SL_RESULT_SUCCESS as _ {
+            ((**instance).Destroy).unwrap()(instance);
+            return Err(unavailable(result));
+        }
 
-        Instance { instance, engine }
+        Ok(Instance { instance, engine })
     }
 
     unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
@@ -81,7 +181,9 @@ impl api::Instance for Instance {
         &self,
         physical_device: api::PhysicalDevice,
     ) -> Result<api::PhysicalDeviceProperties> {
-        assert_eq!(physical_device, DEFAULT_PHYSICAL_DEVICE);
+        if physical_device != DEFAULT_PHYSICAL_DEVICE {
+            return api::Error::validation(format!("invalid physical device: {}", physical_device));
+        }
 
         Ok(api::PhysicalDeviceProperties {
             device_name: "default".into(),
@@ -92,18 +194,31 @@ impl api::Instance for Instance {
 
     unsafe fn physical_device_supports_format(
         &self,
-        _physical_device: api::PhysicalDevice,
-        _sharing: api::SharingMode,
-        _frame_desc: api::FrameDesc,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+        frame_desc: api::FrameDesc,
     ) -> bool {
-        todo!()
+        physical_device == DEFAULT_PHYSICAL_DEVICE
+            && sharing == api::SharingMode::Concurrent
+            && matches!(frame_desc.format, api::Format::F32 | api::Format::I32)
+            && frame_desc.sample_rate > 0
+            && frame_desc.num_channels() > 0
     }
 
     unsafe fn physical_device_default_concurrent_format(
         &self,
-        _physical_device: api::PhysicalDevice,
+        physical_device: api::PhysicalDevice,
     ) -> Result<api::FrameDesc> {
-        todo!()
+        if physical_device != DEFAULT_PHYSICAL_DEVICE {
+            return api::Error::validation(format!("invalid physical device: {}", physical_device));
+        }
+
+        // OpenSLES doesn't expose the native format of the output mix.
+        Ok(api::FrameDesc {
+            format: api::Format::F32,
+            channels: api::ChannelMask::STEREO.into(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        })
     }
 
     unsafe fn create_device(
@@ -112,23 +227,56 @@ impl api::Instance for Instance {
         channels: api::Channels,
         callback: api::StreamCallback,
     ) -> Result<Self::Device> {
-        assert_eq!(desc.physical_device, DEFAULT_PHYSICAL_DEVICE);
-        assert_eq!(desc.sharing, api::SharingMode::Concurrent);
+        if desc.physical_device != DEFAULT_PHYSICAL_DEVICE {
+            return api::Error::validation(format!(
+                "invalid physical device: {}",
+                desc.physical_device
+            ));
+        }
+        if desc.sharing != api::SharingMode::Concurrent {
+            return api::Error::validation("exclusive sharing mode not supported");
+        }
+        if !matches!(desc.sample_desc.format, api::Format::F32 | api::Format::I32) {
+            return api::Error::validation(format!(
+                "unsupported format: {:?}",
+                desc.sample_desc.format
+            ));
+        }
+
+        let sles_channels = map_channel_layout(channels.output)?;
+        let num_channels = channels.output.num_channels();
+        if num_channels == 0 {
+            return api::Error::validation("output channel layout is empty");
+        }
+
+        let (buffer_num_frames, buffer_chain_size) = desc
+            .buffering
+            .periods(desc.sample_desc.sample_rate)
+            .unwrap_or((DEFAULT_BUFFER_NUM_FRAMES, DEFAULT_BUFFER_CHAIN_SIZE));
+        if buffer_num_frames == 0 || buffer_chain_size == 0 {
+            return api::Error::validation(format!(
+                "invalid buffering: {} periods of {} frames",
+                buffer_chain_size, buffer_num_frames
+            ));
+        }
 
         let mut mix = ptr::null();
-        ((**self.engine).CreateOutputMix).unwrap()(
+        check(((**self.engine).CreateOutputMix).unwrap()(
             self.engine,
             &mut mix,
             0,
             ptr::null(),
             ptr::null(),
-        );
-        ((**mix).Realize).unwrap()(mix, sles::SL_BOOLEAN_FALSE as _);
+        ))?;
+        if let Err(err) = check(((**mix).Realize).unwrap()(mix, sles::SL_BOOLEAN_FALSE as _)) {
+            ((**mix).Destroy).unwrap()(mix);
+            return Err(err);
+        }
 
         let mut audio_player = ptr::null();
         let mut locator_source = sles::SLDataLocator_AndroidSimpleBufferQueue {
             locatorType: sles::SL_DATALOCATOR_ANDROIDSIMPLEBUFFERQUEUE as _,
-            numBuffers: BUFFER_CHAIN_SIZE as _,
+            numBuffers: buffer_chain_size as _,
         };
 
         let mut create_player = |format| {
@@ -146,24 +294,18 @@ impl api::Instance for Instance {
             };
             let ids = [sles::SL_IID_BUFFERQUEUE];
             let requirements = [sles::SL_BOOLEAN_TRUE];
-            println!(
-                "{}",
-                ((**self.engine).CreateAudioPlayer).unwrap()(
-                    self.engine,
-                    &mut audio_player,
-                    &mut source,
-                    &mut sink,
-                    1,
-                    ids.as_ptr(),
-                    requirements.as_ptr() as _,
-                )
-            );
+            ((**self.engine).CreateAudioPlayer).unwrap()(
+                self.engine,
+                &mut audio_player,
+                &mut source,
+                &mut sink,
+                1,
+                ids.as_ptr(),
+                requirements.as_ptr() as _,
+            )
         };
 
-        let sles_channels = map_channel_mask(channels.output);
-        let num_channels = sles_channels.count_ones();
-
-        match desc.sample_desc.format {
+        let result = match desc.sample_desc.format {
             api::Format::F32 => {
                 let mut format_source = sles::SLAndroidDataFormat_PCM_EX {
                     formatType: sles::SL_ANDROID_DATAFORMAT_PCM_EX as _,
@@ -176,9 +318,9 @@ impl api::Instance for Instance {
                     representation: sles::SL_ANDROID_PCM_REPRESENTATION_FLOAT as _,
                 };
 
-                create_player(&mut format_source as *mut _ as _);
+                create_player(&mut format_source as *mut _ as _)
             }
-            api::Format::U32 => {
+            api::Format::I32 => {
                 let mut format_source = sles::SLDataFormat_PCM {
                     formatType: sles::SL_DATAFORMAT_PCM as _,
                     numChannels: num_channels as _,
@@ -189,31 +331,44 @@ impl api::Instance for Instance {
                     endianness: sles::SL_BYTEORDER_LITTLEENDIAN as _, // TODO
                 };
 
-                create_player(&mut format_source as *mut _ as _);
+                create_player(&mut format_source as *mut _ as _)
             }
-
-            _ => unimplemented!(),
+            _ => unreachable!(),
+        };
+        if let Err(err) = check(result) {
+            ((**mix).Destroy).unwrap()(mix);
+            return Err(err);
         }
 
-        ((**audio_player).Realize).unwrap()(audio_player, sles::SL_BOOLEAN_FALSE as _);
-
         let mut queue: sles::SLAndroidSimpleBufferQueueItf = ptr::null();
-        ((**audio_player).GetInterface).unwrap()(
-            audio_player,
-            sles::SL_IID_BUFFERQUEUE,
-            &mut queue as *mut _ as _,
-        );
-
         let mut state: sles::SLPlayItf = ptr::null();
-        ((**audio_player).GetInterface).unwrap()(
+        let result = check(((**audio_player).Realize).unwrap()(
             audio_player,
-            sles::SL_IID_PLAY,
-            &mut state as *mut _ as _,
-        );
+            sles::SL_BOOLEAN_FALSE as _,
+        ))
+        .and_then(|()| {
+            check(((**audio_player).GetInterface).unwrap()(
+                audio_player,
+                sles::SL_IID_BUFFERQUEUE,
+                &mut queue as *mut _ as _,
+            ))
+        })
+        .and_then(|()| {
+            check(((**audio_player).GetInterface).unwrap()(
+                audio_player,
+                sles::SL_IID_PLAY,
+                &mut state as *mut _ as _,
+            ))
+        });
+        if let Err(err) = result {
+            ((**audio_player).Destroy).unwrap()(audio_player);
+            ((**mix).Destroy).unwrap()(mix);
+            return Err(err);
+        }
 
-        let buffers = (0..BUFFER_CHAIN_SIZE)
+        let buffers = (0..buffer_chain_size)
             .map(|_| {
-                let buffer_size = num_channels as usize * BUFFER_NUM_FRAMES;
+                let buffer_size = num_channels * buffer_num_frames;
                 let mut buffer = Vec::<u32>::with_capacity(buffer_size);
                 buffer.set_len(buffer_size);
                 buffer
@@ -231,30 +386,40 @@ impl api::Instance for Instance {
             cur_buffer: 0,
             callback,
             frame_desc,
+            buffer_num_frames,
         });
         let data = Box::into_raw(data); // TODO: destroy
 
         extern "C" fn write_cb(queue: sles::SLAndroidSimpleBufferQueueItf, user: *mut c_void) {
             unsafe {
                 let data = &mut *(user as *mut CallbackData);
-                data.cur_buffer = (data.cur_buffer + 1) % data.buffers.len();
+                let num_buffers = data.buffers.len();
+                data.cur_buffer = (data.cur_buffer + 1) % num_buffers;
                 let buffer = &mut data.buffers[data.cur_buffer];
 
                 let stream = api::Stream {
                     properties: api::StreamProperties {
+                        format: data.frame_desc.format,
                         channels: data.frame_desc.channels,
                         sample_rate: data.frame_desc.sample_rate,
-                        buffer_size: BUFFER_NUM_FRAMES,
+                        buffer_size: data.buffer_num_frames,
+                        num_periods: num_buffers,
                     },
                     buffers: api::StreamBuffers {
                         output: buffer.as_mut_ptr() as _,
                         input: ptr::null(),
-                        frames: buffer.len()
-                            / data.frame_desc.channels.bits().count_ones() as usize,
+                        frames: buffer.len() / data.frame_desc.num_channels(),
                     },
                 };
 
-                (data.callback)(stream); // TODO: sizeof u32
+                // Unwinding into OpenSLES is undefined behaviour, output silence instead.
+                let callback = &mut data.callback;
+                if panic::catch_unwind(panic::AssertUnwindSafe(|| callback(stream))).is_err() {
+                    log::error!("stream callback panicked");
+                    for sample in buffer.iter_mut() {
+                        *sample = 0;
+                    }
+                }
                 ((**queue).Enqueue).unwrap()(
                     queue,
                     buffer.as_mut_ptr() as _,
@@ -263,10 +428,16 @@ impl api::Instance for Instance {
             }
         }
 
-        dbg!(
-            "{:?}",
-            (**queue).RegisterCallback.unwrap()(queue, Some(write_cb), data as _)
-        );
+        if let Err(err) = check((**queue).RegisterCallback.unwrap()(
+            queue,
+            Some(write_cb),
+            data as _,
+        )) {
+            drop(Box::from_raw(data));
+            ((**audio_player).Destroy).unwrap()(audio_player);
+            ((**mix).Destroy).unwrap()(mix);
+            return Err(err);
+        }
 
         // Enqueue one frame to get the ball rolling
         write_cb(queue, data as _);
@@ -276,6 +447,8 @@ impl api::Instance for Instance {
             state,
             queue,
             frame_desc,
+            buffer_num_frames,
+            buffer_chain_size,
         })
     }
 
@@ -297,28 +470,34 @@ pub struct Device {
     state: sles::SLPlayItf,
     queue: sles::SLAndroidSimpleBufferQueueItf,
     frame_desc: api::FrameDesc,
+    buffer_num_frames: usize,
+    buffer_chain_size: usize,
 }
 
 impl api::Device for Device {
     unsafe fn start(&self) {
-        dbg!(((**self.state).SetPlayState).unwrap()(
-            self.state,
-            sles::SL_PLAYSTATE_PLAYING as _
-        ));
+        let result =
+            ((**self.state).SetPlayState).unwrap()(self.state, sles::SL_PLAYSTATE_PLAYING as _);
+        if let Err(err) = check(result) {
+            log::error!("failed to start device: {}", err);
+        }
     }
 
     unsafe fn stop(&self) {
-        dbg!(((**self.state).SetPlayState).unwrap()(
-            self.state,
-            sles::SL_PLAYSTATE_STOPPED as _
-        ));
+        let result =
+            ((**self.state).SetPlayState).unwrap()(self.state, sles::SL_PLAYSTATE_STOPPED as _);
+        if let Err(err) = check(result) {
+            log::error!("failed to stop device: {}", err);
+        }
     }
 
     unsafe fn stream_properties(&self) -> api::StreamProperties {
         api::StreamProperties {
+            format: self.frame_desc.format,
             channels: self.frame_desc.channels,
             sample_rate: self.frame_desc.sample_rate,
-            buffer_size: BUFFER_NUM_FRAMES,
+            buffer_size: self.buffer_num_frames,
+            num_periods: self.buffer_chain_size,
         }
     }
 }
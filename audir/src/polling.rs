@@ -0,0 +1,486 @@
+//! Polling adapter for devices of callback based instances.
+
+use crate::api::{self, Result};
+use crate::block;
+use crate::convert;
+use crate::ring::Ring;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{ptr, slice};
+
+/// Number of device periods buffered per direction.
+const RING_PERIODS: usize = 4;
+
+/// Minimum number of frames buffered per direction.
+const MIN_RING_FRAMES: usize = 1024;
+
+/// Ring buffers between the backend executor and the polling thread.
+struct Rings {
+    /// Produced by the backend executor, consumed by the polling thread.
+    input: Option<Ring>,
+    /// Produced by the polling thread, consumed by the backend executor.
+    output: Option<Ring>,
+    /// Guards the readiness checks of `submit_buffers` against missed notifications.
+    lock: Mutex<()>,
+    /// Notified by the backend executor after exchanging frames.
+    exchanged: Condvar,
+}
+
+impl Rings {
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wake up a `submit_buffers` call waiting for the ring buffers.
+    fn notify(&self) {
+        drop(self.lock());
+        self.exchanged.notify_one();
+    }
+}
+
+/// Device, which streams via `submit_buffers` regardless of the instance stream mode.
+///
+/// Devices of polling instances are passed through.
+/// For callback based instances the stream callback is invoked by `submit_buffers`
+/// on the calling thread, exchanging frames with the backend executor via lock-free ring buffers.
+/// The backend executor fills missing output frames with silence and drops excess input frames.
+pub struct PollingDevice<D: api::Device> {
+    inner: Inner<D>,
+}
+
+enum Inner<D> {
+    Polling(D),
+    Callback { device: D, adapter: Adapter },
+}
+
+/// Polling side of the ring buffers.
+struct Adapter {
+    properties: api::StreamProperties,
+    rings: Arc<Rings>,
+    callback: api::StreamCallback,
+    input: Vec<u64>,
+    output: Vec<u64>,
+}
+
+impl Adapter {
+    fn frame_size(&self) -> usize {
+        self.properties.num_channels() * self.properties.format.size()
+    }
+
+    /// Number of frames, which can be read from and written to the ring buffers.
+    fn available_frames(&self) -> api::Frames {
+        let frame_size = self.frame_size();
+        let readable = self.rings.input.as_ref().map(|ring| ring.readable());
+        let writable = self.rings.output.as_ref().map(|ring| ring.writable());
+        let len = match (readable, writable) {
+            (Some(readable), Some(writable)) => readable.min(writable),
+            (Some(len), None) | (None, Some(len)) => len,
+            (None, None) => 0,
+        };
+        len / frame_size
+    }
+
+    /// Number of frames `submit_buffers` waits for, a device period if the ring buffers can hold it.
+    fn period_frames(&self) -> api::Frames {
+        let frame_size = self.frame_size();
+        let capacity = self
+            .rings
+            .input
+            .iter()
+            .chain(self.rings.output.iter())
+            .map(|ring| ring.capacity() / frame_size)
+            .min()
+            .unwrap_or(0);
+        self.properties.buffer_size.min(capacity).max(1)
+    }
+
+    /// Invoke the stream callback for `frames` frames of the ring buffers.
+    unsafe fn process(&mut self, frames: api::Frames) {
+        let len = frames * self.frame_size();
+        let mut buffers = api::StreamBuffers {
+            frames,
+            input: ptr::null(),
+            output: ptr::null_mut(),
+        };
+        if let Some(ring) = self.rings.input.as_ref() {
+            let buffer = convert::scratch(&mut self.input, len);
+            ring.pop(buffer);
+            buffers.input = buffer.as_ptr() as _;
+        }
+        if self.rings.output.is_some() {
+            buffers.output = convert::scratch(&mut self.output, len).as_mut_ptr() as _;
+        }
+
+        (self.callback)(api::Stream {
+            properties: self.properties,
+            buffers,
+        });
+
+        if let Some(ring) = self.rings.output.as_ref() {
+            ring.push(convert::scratch(&mut self.output, len));
+        }
+    }
+}
+
+impl<D: api::Device> PollingDevice<D> {
+    /// Create a new logical device.
+    ///
+    /// See `Instance::create_device` for more details.
+    ///
+    /// ## Validation
+    ///
+    /// - See `Instance::create_device`.
+    ///
+    /// # Safety
+    ///
+    /// - `instance` **must** outlive the returned device.
+    /// - `desc.physical_device` **must** have been enumerated by `instance`.
+    pub unsafe fn create<I>(
+        instance: &I,
+        desc: api::DeviceDesc,
+        channels: api::Channels,
+        callback: api::StreamCallback,
+    ) -> Result<Self>
+    where
+        I: api::Instance<Device = D>,
+    {
+        if instance.properties().stream_mode == api::StreamMode::Polling {
+            let device = instance.create_device(desc, channels, callback)?;
+            return Ok(PollingDevice {
+                inner: Inner::Polling(device),
+            });
+        }
+
+        // The ring buffers are only known after device creation.
+        let shared = Arc::new(Mutex::new(None::<Arc<Rings>>));
+        let device_rings = shared.clone();
+        let mut rings = None;
+        let device_callback = Box::new(move |stream: api::Stream| {
+            let format = stream.properties.format;
+            let len = stream.buffers.frames * stream.properties.num_channels() * format.size();
+            // Doesn't block the backend executor, retried on the next callback if contended.
+            if rings.is_none() {
+                rings = device_rings.try_lock().ok().and_then(|rings| rings.clone());
+            }
+            let rings = rings.as_deref();
+
+            if !stream.buffers.input.is_null() {
+                if let Some(ring) = rings.and_then(|rings| rings.input.as_ref()) {
+                    let src = slice::from_raw_parts(stream.buffers.input as *const u8, len);
+                    ring.push(src);
+                }
+            }
+            if !stream.buffers.output.is_null() {
+                let dst = slice::from_raw_parts_mut(stream.buffers.output as *mut u8, len);
+                let read = match rings.and_then(|rings| rings.output.as_ref()) {
+                    Some(ring) => ring.pop(dst),
+                    None => 0,
+                };
+                block::silence(format, &mut dst[read..]);
+            }
+            if let Some(rings) = rings {
+                rings.notify();
+            }
+        });
+
+        let device = instance.create_device(desc, channels, device_callback)?;
+        let properties = device.stream_properties();
+        let frame_size = properties.num_channels() * properties.format.size();
+        if frame_size == 0 {
+            return api::Error::validation("input and output channel layouts are empty");
+        }
+
+        let capacity = (properties.buffer_size * RING_PERIODS).max(MIN_RING_FRAMES) * frame_size;
+        let ring = |layout: api::ChannelLayout| {
+            if layout.is_empty() {
+                None
+            } else {
+                Some(Ring::new(capacity))
+            }
+        };
+        let rings = Arc::new(Rings {
+            input: ring(channels.input),
+            output: ring(channels.output),
+            lock: Mutex::new(()),
+            exchanged: Condvar::new(),
+        });
+        *shared.lock().unwrap_or_else(PoisonError::into_inner) = Some(rings.clone());
+
+        Ok(PollingDevice {
+            inner: Inner::Callback {
+                device,
+                adapter: Adapter {
+                    properties,
+                    rings,
+                    callback,
+                    input: Vec::new(),
+                    output: Vec::new(),
+                },
+            },
+        })
+    }
+
+    /// Underlying device.
+    pub fn raw(&self) -> &D {
+        match self.inner {
+            Inner::Polling(ref device) | Inner::Callback { ref device, .. } => device,
+        }
+    }
+}
+
+impl<D: api::Device> api::Device for PollingDevice<D> {
+    unsafe fn start(&self) {
+        self.raw().start()
+    }
+
+    unsafe fn stop(&self) {
+        self.raw().stop()
+    }
+
+    unsafe fn stream_properties(&self) -> api::StreamProperties {
+        self.raw().stream_properties()
+    }
+
+    /// Submit stream buffers.
+    ///
+    /// For callback based instances, waits until a device period can be read from or written to
+    /// the ring buffers. Fails with `Error::Timeout` if not ready within `timeout_ms`.
+    unsafe fn submit_buffers(&mut self, timeout_ms: u32) -> Result<()> {
+        let adapter = match self.inner {
+            Inner::Polling(ref mut device) => return device.submit_buffers(timeout_ms),
+            Inner::Callback {
+                ref mut adapter, ..
+            } => adapter,
+        };
+
+        let deadline = if timeout_ms == !0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(timeout_ms as _))
+        };
+
+        let frames = {
+            let mut guard = adapter.rings.lock();
+            loop {
+                let frames = adapter.available_frames();
+                if frames >= adapter.period_frames() {
+                    break frames;
+                }
+
+                guard = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(api::Error::Timeout { source: None });
+                        }
+                        let (guard, _) = adapter
+                            .rings
+                            .exchanged
+                            .wait_timeout(guard, deadline - now)
+                            .unwrap_or_else(PoisonError::into_inner);
+                        guard
+                    }
+                    None => adapter
+                        .rings
+                        .exchanged
+                        .wait(guard)
+                        .unwrap_or_else(PoisonError::into_inner),
+                };
+            }
+        };
+
+        adapter.process(frames);
+        Ok(())
+    }
+
+    unsafe fn available_frames(&mut self) -> Result<api::Frames> {
+        match self.inner {
+            Inner::Polling(ref mut device) => device.available_frames(),
+            Inner::Callback { ref adapter, .. } => Ok(adapter.available_frames()),
+        }
+    }
+
+    /// Submit stream buffers without waiting.
+    ///
+    /// For callback based instances, submits the available frames of the ring buffers,
+    /// even if less than a device period.
+    unsafe fn try_submit_buffers(&mut self) -> Result<()> {
+        let adapter = match self.inner {
+            Inner::Polling(ref mut device) => return device.try_submit_buffers(),
+            Inner::Callback {
+                ref mut adapter, ..
+            } => adapter,
+        };
+
+        match adapter.available_frames() {
+            0 => Err(api::Error::WouldBlock),
+            frames => {
+                adapter.process(frames);
+                Ok(())
+            }
+        }
+    }
+
+    /// Not supported for callback based instances.
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        match self.inner {
+            Inner::Polling(ref device) => device.wait_handles(),
+            Inner::Callback { .. } => {
+                api::Error::validation("`wait_handles` not supported for callback based instances")
+            }
+        }
+    }
+
+    /// Not supported for callback based instances.
+    unsafe fn dispatch_pending(&mut self) -> Result<()> {
+        match self.inner {
+            Inner::Polling(ref mut device) => device.dispatch_pending(),
+            Inner::Callback { .. } => api::Error::validation(
+                "`dispatch_pending` not supported for callback based instances",
+            ),
+        }
+    }
+
+    /// Poll the readiness of the device stream.
+    ///
+    /// Not supported for callback based instances.
+    unsafe fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<api::Frames>> {
+        match self.inner {
+            Inner::Polling(ref mut device) => device.poll_ready(cx),
+            Inner::Callback { .. } => Poll::Ready(api::Error::validation(
+                "`poll_ready` not supported for callback based instances",
+            )),
+        }
+    }
+
+    /// Discard all pending audio of the device stream.
+    ///
+    /// Frames buffered in the ring buffers aren't affected.
+    unsafe fn flush(&mut self) -> Result<()> {
+        match self.inner {
+            Inner::Polling(ref mut device) | Inner::Callback { ref mut device, .. } => {
+                device.flush()
+            }
+        }
+    }
+
+    unsafe fn drain(&self) -> Result<()> {
+        self.raw().drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Device as _, Instance as _};
+    use crate::null;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    const PERIOD_SIZE: api::Frames = 256;
+
+    unsafe fn create(
+        instance: &null::Instance,
+        channels: api::Channels,
+        callback: api::StreamCallback,
+    ) -> PollingDevice<null::Device> {
+        let desc = api::DeviceDesc {
+            physical_device: instance.default_physical_output_device().unwrap(),
+            sharing: api::SharingMode::Concurrent,
+            sample_desc: api::SampleDesc {
+                format: api::Format::F32,
+                sample_rate: 48_000,
+            },
+            buffering: api::Buffering::Explicit {
+                period_size: PERIOD_SIZE,
+                num_periods: 2,
+            },
+        };
+        PollingDevice::create(instance, desc, channels, callback).unwrap()
+    }
+
+    #[test]
+    fn null_output() {
+        unsafe {
+            let instance = null::Instance::create("audir").unwrap();
+            let frames = Arc::new(AtomicUsize::new(0));
+            let counter = frames.clone();
+            let mut device = create(
+                &instance,
+                api::Channels {
+                    input: api::ChannelLayout::empty(),
+                    output: api::ChannelMask::STEREO.into(),
+                },
+                Box::new(move |mut stream| {
+                    assert!(stream.buffers.input.is_null());
+                    stream.output::<f32>().unwrap().samples_mut().fill(0.5);
+                    counter.fetch_add(stream.buffers.frames, Ordering::Relaxed);
+                }),
+            );
+
+            device.start();
+            // The first submission fills the ring buffer, later ones wait for the device.
+            for _ in 0..4 {
+                device.submit_buffers(1_000).unwrap();
+            }
+            device.stop();
+
+            assert!(frames.load(Ordering::Relaxed) >= MIN_RING_FRAMES + 3 * PERIOD_SIZE);
+        }
+    }
+
+    #[test]
+    fn null_input() {
+        unsafe {
+            let instance = null::Instance::create("audir").unwrap();
+            let frames = Arc::new(AtomicUsize::new(0));
+            let silent = Arc::new(AtomicBool::new(true));
+            let (counter, silence) = (frames.clone(), silent.clone());
+            let mut device = create(
+                &instance,
+                api::Channels {
+                    input: api::ChannelMask::MONO.into(),
+                    output: api::ChannelLayout::empty(),
+                },
+                Box::new(move |stream| {
+                    assert!(stream.buffers.output.is_null());
+                    let input = stream.input::<f32>().unwrap();
+                    if input.samples().iter().any(|&sample| sample != 0.0) {
+                        silence.store(false, Ordering::Relaxed);
+                    }
+                    counter.fetch_add(stream.buffers.frames, Ordering::Relaxed);
+                }),
+            );
+
+            device.start();
+            for _ in 0..4 {
+                device.submit_buffers(1_000).unwrap();
+            }
+            device.stop();
+
+            assert!(frames.load(Ordering::Relaxed) >= 4 * PERIOD_SIZE);
+            assert!(silent.load(Ordering::Relaxed));
+        }
+    }
+
+    #[test]
+    fn null_timeout() {
+        unsafe {
+            let instance = null::Instance::create("audir").unwrap();
+            let mut device = create(
+                &instance,
+                api::Channels {
+                    input: api::ChannelMask::MONO.into(),
+                    output: api::ChannelLayout::empty(),
+                },
+                Box::new(|_| {}),
+            );
+
+            // Stopped devices don't produce any input.
+            match device.submit_buffers(20) {
+                Err(api::Error::Timeout { .. }) => {}
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+}
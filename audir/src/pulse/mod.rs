@@ -1,49 +1,539 @@
-use crate::{api, api::Result, handle::Handle};
+mod poll;
+
+use crate::{api, api::Result, block, convert, handle::Handle};
 use libpulse_sys as pulse;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::ffi::c_void;
-use std::ffi::CStr;
-use std::ptr;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use std::{mem, ptr, slice};
+
+/// Sink or source of a physical device.
+#[derive(Clone)]
+struct Endpoint {
+    /// Pulse sink or source index.
+    index: u32,
+    /// Pulse sink or source name.
+    name: CString,
+    sample_spec: pulse::pa_sample_spec,
+    channels: api::ChannelLayout,
+    pcm: PcmCapabilities,
+}
+
+/// PCM stream capabilities of a sink or source.
+///
+/// Pulse converts samples, sample rates and channel layouts in concurrent mode,
+/// therefore any combination of these properties is supported.
+#[derive(Clone)]
+struct PcmCapabilities {
+    formats: Vec<api::Format>,
+    sample_rates: Vec<api::SampleRateRange>,
+    max_channels: usize,
+}
+
+impl PcmCapabilities {
+    /// Capabilities of an endpoint from its format infos.
+    ///
+    /// Properties not restricted by the PCM format infos allow all values supported by pulse.
+    unsafe fn from_format_infos(infos: *mut *mut pulse::pa_format_info, num_infos: u8) -> Self {
+        let infos = if infos.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts(infos, num_infos as _)
+        };
+
+        let mut formats = Vec::new();
+        let mut sample_rates = Vec::new();
+        let mut max_channels = 0;
+
+        let pcm_infos = infos
+            .iter()
+            .cloned()
+            .filter(|&info| pulse::pa_format_info_is_pcm(info) != 0)
+            .collect::<Vec<_>>();
+
+        for &info in &pcm_infos {
+            let key = b"format.sample_format\0".as_ptr() as _;
+            match pulse::pa_format_info_get_prop_type(info, key) {
+                pulse::PA_PROP_TYPE_STRING => {
+                    let mut value = ptr::null_mut();
+                    if pulse::pa_format_info_get_prop_string(info, key, &mut value) == 0 {
+                        formats.extend(map_sample_format(pulse::pa_parse_sample_format(value)));
+                        pulse::pa_xfree(value as _);
+                    }
+                }
+                pulse::PA_PROP_TYPE_STRING_ARRAY => {
+                    let mut values = ptr::null_mut();
+                    let mut num_values = 0;
+                    if pulse::pa_format_info_get_prop_string_array(
+                        info,
+                        key,
+                        &mut values,
+                        &mut num_values,
+                    ) == 0
+                    {
+                        for i in 0..num_values as usize {
+                            formats.extend(map_sample_format(pulse::pa_parse_sample_format(
+                                *values.add(i),
+                            )));
+                        }
+                        pulse::pa_format_info_free_string_array(values, num_values);
+                    }
+                }
+                _ => formats.extend(FORMATS.iter().map(|&(format, _)| format)),
+            }
 
+            let key = b"format.rate\0".as_ptr() as _;
+            match pulse::pa_format_info_get_prop_type(info, key) {
+                pulse::PA_PROP_TYPE_INT => {
+                    let mut rate = 0;
+                    if pulse::pa_format_info_get_prop_int(info, key, &mut rate) == 0 {
+                        sample_rates.push(api::SampleRateRange::fixed(rate as _));
+                    }
+                }
+                pulse::PA_PROP_TYPE_INT_RANGE => {
+                    let (mut min, mut max) = (0, 0);
+                    if pulse::pa_format_info_get_prop_int_range(info, key, &mut min, &mut max) == 0
+                    {
+                        sample_rates.push(api::SampleRateRange {
+                            min: min as _,
+                            max: max as _,
+                        });
+                    }
+                }
+                pulse::PA_PROP_TYPE_INT_ARRAY => {
+                    let mut values = ptr::null_mut();
+                    let mut num_values = 0;
+                    if pulse::pa_format_info_get_prop_int_array(
+                        info,
+                        key,
+                        &mut values,
+                        &mut num_values,
+                    ) == 0
+                    {
+                        let rates = slice::from_raw_parts(values, num_values as _);
+                        sample_rates.extend(
+                            rates
+                                .iter()
+                                .map(|&rate| api::SampleRateRange::fixed(rate as _)),
+                        );
+                        pulse::pa_xfree(values as _);
+                    }
+                }
+                _ => sample_rates.push(api::SampleRateRange {
+                    min: 1,
+                    max: pulse::PA_RATE_MAX as _,
+                }),
+            }
+
+            let key = b"format.channels\0".as_ptr() as _;
+            let mut channels = pulse::PA_CHANNELS_MAX as i32;
+            if pulse::pa_format_info_get_prop_type(info, key) == pulse::PA_PROP_TYPE_INT {
+                pulse::pa_format_info_get_prop_int(info, key, &mut channels);
+            }
+            max_channels = max_channels.max(channels as usize);
+        }
+
+        if pcm_infos.is_empty() {
+            formats.extend(FORMATS.iter().map(|&(format, _)| format));
+            sample_rates.push(api::SampleRateRange {
+                min: 1,
+                max: pulse::PA_RATE_MAX as _,
+            });
+            max_channels = pulse::PA_CHANNELS_MAX as _;
+        }
+
+        let mut unique_formats = Vec::with_capacity(formats.len());
+        for format in formats {
+            if !unique_formats.contains(&format) {
+                unique_formats.push(format);
+            }
+        }
+        sample_rates.dedup();
+
+        PcmCapabilities {
+            formats: unique_formats,
+            sample_rates,
+            max_channels,
+        }
+    }
+
+    fn supports(&self, frame_desc: &api::FrameDesc) -> bool {
+        self.formats.contains(&frame_desc.format)
+            && self
+                .sample_rates
+                .iter()
+                .any(|range| range.contains(frame_desc.sample_rate))
+            && frame_desc.num_channels() <= self.max_channels
+    }
+}
+
+/// Physical device composed of the sink and source of a card.
+///
+/// Sinks and sources without a card (e.g network or null sinks) and monitor sources
+/// are exposed as separate physical devices.
+///
+/// Physical devices without any endpoint have been removed. These are kept alive
+/// to ensure handles stay valid and will be reused if the device gets added again.
 struct PhysicalDevice {
     device_name: String,
-    streams: api::StreamFlags,
-    sample_spec: pulse::pa_sample_spec,
-    channels: api::ChannelMask,
+    sink: Option<Endpoint>,
+    source: Option<Endpoint>,
 }
 
 type PhysicalDeviceMap = HashMap<String, Handle<PhysicalDevice>>;
 
 impl PhysicalDevice {
+    fn streams(&self) -> api::StreamFlags {
+        let mut streams = api::StreamFlags::empty();
+        if self.sink.is_some() {
+            streams |= api::StreamFlags::OUTPUT;
+        }
+        if self.source.is_some() {
+            streams |= api::StreamFlags::INPUT;
+        }
+        streams
+    }
+
+    /// Primary endpoint of the device, preferring the sink.
+    fn endpoint(&self) -> Result<&Endpoint> {
+        match (&self.sink, &self.source) {
+            (Some(endpoint), _) | (None, Some(endpoint)) => Ok(endpoint),
+            (None, None) => Err(api::Error::DeviceLost),
+        }
+    }
+
     fn default_format(&self) -> Result<api::FrameDesc> {
-        let format = match self.sample_spec.format {
-            pulse::pa_sample_format_t::F32le => api::Format::F32,
-            pulse::pa_sample_format_t::S16le => api::Format::I16,
-            format => {
+        let endpoint = self.endpoint()?;
+
+        let format = match map_sample_format(endpoint.sample_spec.format) {
+            Some(format) => format,
+            None => {
                 return Err(api::Error::Internal {
-                    cause: format!("unhandled format: {:?}", format),
+                    cause: format!("unhandled format: {:?}", endpoint.sample_spec.format),
                 })
             }
         };
 
         Ok(api::FrameDesc {
             format,
-            channels: self.channels,
-            sample_rate: self.sample_spec.rate as _,
+            channels: endpoint.channels,
+            sample_rate: endpoint.sample_spec.rate as _,
+        })
+    }
+
+    /// Format capabilities of the primary endpoint.
+    ///
+    /// Channel layouts consist of the native layout of the endpoint followed
+    /// by the common layouts within the channel limit.
+    fn format_capabilities(&self) -> Result<api::FormatCapabilities> {
+        let endpoint = self.endpoint()?;
+
+        let mut channels = vec![endpoint.channels];
+        channels.extend(
+            api::COMMON_CHANNEL_LAYOUTS
+                .iter()
+                .map(|&mask| api::ChannelLayout::Mask(mask))
+                .filter(|layout| {
+                    *layout != endpoint.channels
+                        && layout.num_channels() <= endpoint.pcm.max_channels
+                }),
+        );
+
+        Ok(api::FormatCapabilities {
+            formats: endpoint.pcm.formats.clone(),
+            sample_rates: endpoint.pcm.sample_rates.clone(),
+            channels,
         })
     }
 }
 
-fn map_channels(channel_map: &pulse::pa_channel_map) -> api::ChannelMask {
+/// Speaker positions in channel mask order.
+const CHANNEL_POSITIONS: [(api::ChannelMask, pulse::pa_channel_position_t); 18] = [
+    (
+        api::ChannelMask::FRONT_LEFT,
+        pulse::PA_CHANNEL_POSITION_FRONT_LEFT,
+    ),
+    (
+        api::ChannelMask::FRONT_RIGHT,
+        pulse::PA_CHANNEL_POSITION_FRONT_RIGHT,
+    ),
+    (
+        api::ChannelMask::FRONT_CENTER,
+        pulse::PA_CHANNEL_POSITION_FRONT_CENTER,
+    ),
+    (
+        api::ChannelMask::LOW_FREQUENCY,
+        pulse::PA_CHANNEL_POSITION_LFE,
+    ),
+    (
+        api::ChannelMask::BACK_LEFT,
+        pulse::PA_CHANNEL_POSITION_REAR_LEFT,
+    ),
+    (
+        api::ChannelMask::BACK_RIGHT,
+        pulse::PA_CHANNEL_POSITION_REAR_RIGHT,
+    ),
+    (
+        api::ChannelMask::FRONT_LEFT_OF_CENTER,
+        pulse::PA_CHANNEL_POSITION_FRONT_LEFT_OF_CENTER,
+    ),
+    (
+        api::ChannelMask::FRONT_RIGHT_OF_CENTER,
+        pulse::PA_CHANNEL_POSITION_FRONT_RIGHT_OF_CENTER,
+    ),
+    (
+        api::ChannelMask::BACK_CENTER,
+        pulse::PA_CHANNEL_POSITION_REAR_CENTER,
+    ),
+    (
+        api::ChannelMask::SIDE_LEFT,
+        pulse::PA_CHANNEL_POSITION_SIDE_LEFT,
+    ),
+    (
+        api::ChannelMask::SIDE_RIGHT,
+        pulse::PA_CHANNEL_POSITION_SIDE_RIGHT,
+    ),
+    (
+        api::ChannelMask::TOP_CENTER,
+        pulse::PA_CHANNEL_POSITION_TOP_CENTER,
+    ),
+    (
+        api::ChannelMask::TOP_FRONT_LEFT,
+        pulse::PA_CHANNEL_POSITION_TOP_FRONT_LEFT,
+    ),
+    (
+        api::ChannelMask::TOP_FRONT_CENTER,
+        pulse::PA_CHANNEL_POSITION_TOP_FRONT_CENTER,
+    ),
+    (
+        api::ChannelMask::TOP_FRONT_RIGHT,
+        pulse::PA_CHANNEL_POSITION_TOP_FRONT_RIGHT,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_LEFT,
+        pulse::PA_CHANNEL_POSITION_TOP_REAR_LEFT,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_CENTER,
+        pulse::PA_CHANNEL_POSITION_TOP_REAR_CENTER,
+    ),
+    (
+        api::ChannelMask::TOP_BACK_RIGHT,
+        pulse::PA_CHANNEL_POSITION_TOP_REAR_RIGHT,
+    ),
+];
+
+/// Channel layout of a pulse channel map.
+///
+/// Maps with positions outside of the speaker set (e.g aux channels) or
+/// duplicated positions are reported as discrete channels.
+fn map_channels(channel_map: &pulse::pa_channel_map) -> api::ChannelLayout {
+    let num_channels = channel_map.channels as usize;
     let mut channels = api::ChannelMask::empty();
-    for i in 0..channel_map.channels {
-        channels |= match channel_map.map[i as usize] {
-            pulse::PA_CHANNEL_POSITION_FRONT_LEFT => api::ChannelMask::FRONT_LEFT,
-            pulse::PA_CHANNEL_POSITION_FRONT_RIGHT => api::ChannelMask::FRONT_RIGHT,
-            pos => panic!("unsupported {:?}", pos),
+    for i in 0..num_channels {
+        let mask = match channel_map.map[i] {
+            pulse::PA_CHANNEL_POSITION_MONO => api::ChannelMask::MONO,
+            pos => match CHANNEL_POSITIONS
+                .iter()
+                .find(|(_, position)| *position == pos)
+            {
+                Some(&(mask, _)) => mask,
+                None => return api::ChannelLayout::Discrete(num_channels),
+            },
+        };
+        if channels.intersects(mask) {
+            return api::ChannelLayout::Discrete(num_channels);
+        }
+        channels |= mask;
+    }
+    api::ChannelLayout::Mask(channels)
+}
+
+/// Pulse channel map for a channel layout.
+///
+/// Speaker positions are in channel mask order, discrete channels are mapped to aux channels.
+unsafe fn map_channel_layout(channels: api::ChannelLayout) -> pulse::pa_channel_map {
+    let mut channel_map = pulse::pa_channel_map {
+        channels: 0,
+        map: [pulse::PA_CHANNEL_POSITION_INVALID; pulse::PA_CHANNELS_MAX as usize],
+    };
+    match channels {
+        // Pulse distinguishes mono from a single front center speaker.
+        api::ChannelLayout::Mask(api::ChannelMask::MONO) => {
+            channel_map.map[0] = pulse::PA_CHANNEL_POSITION_MONO;
+            channel_map.channels = 1;
+        }
+        api::ChannelLayout::Mask(mask) => {
+            for &(position_mask, position) in CHANNEL_POSITIONS.iter() {
+                if mask.contains(position_mask) {
+                    channel_map.map[channel_map.channels as usize] = position;
+                    channel_map.channels += 1;
+                }
+            }
+        }
+        api::ChannelLayout::Discrete(num_channels) => {
+            pulse::pa_channel_map_init_auto(
+                &mut channel_map,
+                num_channels as _,
+                pulse::PA_CHANNEL_MAP_AUX,
+            );
+        }
+    }
+    channel_map
+}
+
+type EventCallback = Box<dyn FnMut(api::Event) + Send>;
+
+#[derive(Default)]
+struct PhysicalDevices {
+    map: PhysicalDeviceMap,
+    /// Name of the server's default sink.
+    default_sink: Option<CString>,
+    /// Name of the server's default source.
+    default_source: Option<CString>,
+}
+
+impl PhysicalDevices {
+    /// Insert a sink or source into the physical device list.
+    ///
+    /// Endpoints are grouped by their card. If the card already has an endpoint with
+    /// the same direction, the new endpoint will be exposed as separate device.
+    ///
+    /// Returns the physical device if it has been newly added.
+    fn insert_endpoint(
+        &mut self,
+        card: u32,
+        description: &CStr,
+        endpoint: Endpoint,
+        stream: api::StreamFlags,
+    ) -> Option<Handle<PhysicalDevice>> {
+        let name = endpoint.name.to_string_lossy().into_owned();
+        let card_key = format!("card #{}", card);
+
+        let key = match self.map.get(&card_key) {
+            _ if card == pulse::PA_INVALID_INDEX => name,
+            Some(device) => {
+                let slot = if stream == api::StreamFlags::OUTPUT {
+                    &device.sink
+                } else {
+                    &device.source
+                };
+                match slot {
+                    Some(other) if other.name != endpoint.name => name,
+                    _ => card_key,
+                }
+            }
+            None => card_key,
         };
+
+        let mut device = *self.map.entry(key).or_insert_with(|| {
+            Handle::new(PhysicalDevice {
+                device_name: description.to_string_lossy().into_owned(),
+                sink: None,
+                source: None,
+            })
+        });
+
+        let added = device.streams().is_empty();
+        if stream == api::StreamFlags::OUTPUT {
+            device.sink = Some(endpoint);
+        } else {
+            device.source = Some(endpoint);
+        }
+
+        if added {
+            Some(device)
+        } else {
+            None
+        }
+    }
+
+    /// Remove a sink or source from the physical device list.
+    ///
+    /// Returns the physical device if it doesn't have any endpoints left.
+    fn remove_endpoint(
+        &mut self,
+        index: u32,
+        stream: api::StreamFlags,
+    ) -> Option<Handle<PhysicalDevice>> {
+        for device in self.map.values_mut() {
+            let slot = if stream == api::StreamFlags::OUTPUT {
+                &mut device.sink
+            } else {
+                &mut device.source
+            };
+
+            if slot.as_ref().map(|endpoint| endpoint.index) == Some(index) {
+                *slot = None;
+                if device.streams().is_empty() {
+                    return Some(*device);
+                }
+                break;
+            }
+        }
+
+        None
+    }
+
+    /// Physical device of a handle.
+    ///
+    /// Endpoints are modified by the subscribe callback, access is bound to the locked list.
+    fn get(&self, physical_device: api::PhysicalDevice) -> &PhysicalDevice {
+        unsafe { &*(physical_device as *const PhysicalDevice) }
+    }
+
+    /// Find the physical device of a sink or source by name.
+    fn find_endpoint(&self, name: &CStr, stream: api::StreamFlags) -> Option<api::PhysicalDevice> {
+        self.map
+            .values()
+            .find(|device| {
+                let slot = if stream == api::StreamFlags::OUTPUT {
+                    &device.sink
+                } else {
+                    &device.source
+                };
+                slot.as_ref().map(|endpoint| endpoint.name.as_c_str()) == Some(name)
+            })
+            .map(|device| device.raw())
+    }
+}
+
+/// Instance state shared with the context callbacks.
+struct State {
+    physical_devices: Mutex<PhysicalDevices>,
+    event_callback: Mutex<Option<EventCallback>>,
+}
+
+impl State {
+    /// Lock the physical device list.
+    ///
+    /// Called from context callbacks, which must not panic on poisoned locks.
+    fn physical_devices(&self) -> MutexGuard<'_, PhysicalDevices> {
+        self.physical_devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Pass an event to the user callback.
+    ///
+    /// Panics of the callback are caught as these must not unwind into pulse.
+    fn emit(&self, event: api::Event) {
+        let mut callback = self
+            .event_callback
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(ref mut callback) = *callback {
+            if panic::catch_unwind(AssertUnwindSafe(|| callback(event))).is_err() {
+                log::error!("event callback panicked");
+            }
+        }
     }
-    channels
 }
 
 extern "C" fn sink_info_cb(
@@ -57,29 +547,36 @@ extern "C" fn sink_info_cb(
     }
 
     let info = unsafe { &*info };
-    let physical_devices = unsafe { &mut *(user as *mut PhysicalDeviceMap) };
+    let state = unsafe { &*(user as *const State) };
 
-    let name = unsafe { CStr::from_ptr(info.name).to_string_lossy().into_owned() };
-    let device_name = unsafe {
-        CStr::from_ptr(info.description)
-            .to_string_lossy()
-            .into_owned()
+    let name = unsafe { CStr::from_ptr(info.name) };
+    if map_sample_format(info.sample_spec.format).is_none() {
+        log::warn!(
+            "skipping {:?}: unsupported sample format {:?}",
+            name,
+            info.sample_spec.format
+        );
+        return;
+    }
+
+    let endpoint = Endpoint {
+        index: info.index,
+        name: name.to_owned(),
+        sample_spec: info.sample_spec,
+        channels: map_channels(&info.channel_map),
+        pcm: unsafe { PcmCapabilities::from_format_infos(info.formats, info.n_formats) },
     };
-    physical_devices
-        .entry(name)
-        .and_modify(|device| {
-            assert_eq!(device.sample_spec, info.sample_spec); // TODO: is this right?
+    let description = unsafe { CStr::from_ptr(info.description) };
 
-            device.streams |= api::StreamFlags::OUTPUT;
-        })
-        .or_insert_with(|| {
-            Handle::new(PhysicalDevice {
-                device_name,
-                streams: api::StreamFlags::OUTPUT,
-                sample_spec: info.sample_spec,
-                channels: map_channels(&info.channel_map),
-            })
-        });
+    let added = state.physical_devices().insert_endpoint(
+        info.card,
+        description,
+        endpoint,
+        api::StreamFlags::OUTPUT,
+    );
+    if let Some(device) = added {
+        state.emit(api::Event::Added(device.raw()));
+    }
 }
 
 extern "C" fn source_info_cb(
@@ -93,54 +590,268 @@ extern "C" fn source_info_cb(
     }
 
     let info = unsafe { &*info };
-    let physical_devices = unsafe { &mut *(user as *mut PhysicalDeviceMap) };
+    let state = unsafe { &*(user as *const State) };
+
+    let name = unsafe { CStr::from_ptr(info.name) };
+    if map_sample_format(info.sample_spec.format).is_none() {
+        log::warn!(
+            "skipping {:?}: unsupported sample format {:?}",
+            name,
+            info.sample_spec.format
+        );
+        return;
+    }
+
+    let endpoint = Endpoint {
+        index: info.index,
+        name: name.to_owned(),
+        sample_spec: info.sample_spec,
+        channels: map_channels(&info.channel_map),
+        pcm: unsafe { PcmCapabilities::from_format_infos(info.formats, info.n_formats) },
+    };
+    let description = unsafe { CStr::from_ptr(info.description) };
+
+    // Monitors are exposed as standalone devices.
+    let card = if info.monitor_of_sink != pulse::PA_INVALID_INDEX {
+        pulse::PA_INVALID_INDEX
+    } else {
+        info.card
+    };
+
+    let added = state.physical_devices().insert_endpoint(
+        card,
+        description,
+        endpoint,
+        api::StreamFlags::INPUT,
+    );
+    if let Some(device) = added {
+        state.emit(api::Event::Added(device.raw()));
+    }
+}
+
+extern "C" fn server_info_cb(
+    _context: *mut pulse::pa_context,
+    info: *const pulse::pa_server_info,
+    user: *mut c_void,
+) {
+    if info.is_null() {
+        return;
+    }
+
+    let info = unsafe { &*info };
+    let state = unsafe { &*(user as *const State) };
 
-    let name = unsafe {
-        CStr::from_ptr(info.description)
-            .to_string_lossy()
-            .into_owned()
+    let as_name = |name: *const std::os::raw::c_char| {
+        if name.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(name) }.to_owned())
+        }
     };
-    let device_name = unsafe {
-        CStr::from_ptr(info.description)
-            .to_string_lossy()
-            .into_owned()
+    let default_sink = as_name(info.default_sink_name);
+    let default_source = as_name(info.default_source_name);
+
+    let mut events = Vec::new();
+    {
+        let mut physical_devices = state.physical_devices();
+
+        if physical_devices.default_sink != default_sink {
+            let device = default_sink
+                .as_ref()
+                .and_then(|name| physical_devices.find_endpoint(name, api::StreamFlags::OUTPUT));
+            physical_devices.default_sink = default_sink;
+            events.push(api::Event::DefaultOutputDevice(device));
+        }
+
+        if physical_devices.default_source != default_source {
+            let device = default_source
+                .as_ref()
+                .and_then(|name| physical_devices.find_endpoint(name, api::StreamFlags::INPUT));
+            physical_devices.default_source = default_source;
+            events.push(api::Event::DefaultInputDevice(device));
+        }
+    }
+
+    for event in events {
+        state.emit(event);
+    }
+}
+
+extern "C" fn subscribe_cb(
+    context: *mut pulse::pa_context,
+    ty: pulse::pa_subscription_event_type_t,
+    index: u32,
+    user: *mut c_void,
+) {
+    let state = unsafe { &*(user as *const State) };
+
+    let facility = ty & pulse::PA_SUBSCRIPTION_EVENT_FACILITY_MASK;
+    let removed =
+        ty & pulse::PA_SUBSCRIPTION_EVENT_TYPE_MASK == pulse::PA_SUBSCRIPTION_EVENT_REMOVE;
+
+    let stream = match facility {
+        pulse::PA_SUBSCRIPTION_EVENT_SINK => api::StreamFlags::OUTPUT,
+        pulse::PA_SUBSCRIPTION_EVENT_SOURCE => api::StreamFlags::INPUT,
+        pulse::PA_SUBSCRIPTION_EVENT_SERVER => {
+            unsafe {
+                let operation =
+                    pulse::pa_context_get_server_info(context, Some(server_info_cb), user);
+                if !operation.is_null() {
+                    pulse::pa_operation_unref(operation);
+                }
+            }
+            return;
+        }
+        _ => return,
     };
-    physical_devices
-        .entry(name)
-        .and_modify(|device| {
-            assert_eq!(device.sample_spec, info.sample_spec); // TODO: is this right?
 
-            device.streams |= api::StreamFlags::INPUT;
-        })
-        .or_insert_with(|| {
-            Handle::new(PhysicalDevice {
-                device_name,
-                streams: api::StreamFlags::INPUT,
-                sample_spec: info.sample_spec,
-                channels: map_channels(&info.channel_map),
-            })
-        });
+    if removed {
+        let device = state.physical_devices().remove_endpoint(index, stream);
+        if let Some(device) = device {
+            state.emit(api::Event::Removed(device.raw()));
+        }
+        return;
+    }
+
+    // New or changed endpoint, (re-)query the endpoint properties.
+    unsafe {
+        let operation = if stream == api::StreamFlags::OUTPUT {
+            pulse::pa_context_get_sink_info_by_index(context, index, Some(sink_info_cb), user)
+        } else {
+            pulse::pa_context_get_source_info_by_index(context, index, Some(source_info_cb), user)
+        };
+        if !operation.is_null() {
+            pulse::pa_operation_unref(operation);
+        }
+    }
+}
+
+/// Buffer attributes and stream flags for the requested buffering.
+///
+/// Playback streams are configured with `minreq` as period and `tlength` as total buffer length.
+/// Record streams deliver fragments with the size of a period.
+unsafe fn buffer_attr(
+    buffering: api::Buffering,
+    sample_spec: &pulse::pa_sample_spec,
+) -> (pulse::pa_buffer_attr, pulse::pa_stream_flags_t) {
+    match buffering.periods(sample_spec.rate as _) {
+        Some((period_size, num_periods)) => {
+            let period = (period_size * pulse::pa_frame_size(sample_spec)) as u32;
+            let attribs = pulse::pa_buffer_attr {
+                maxlength: !0,
+                tlength: period * num_periods as u32,
+                prebuf: !0,
+                minreq: period,
+                fragsize: period,
+            };
+            (attribs, pulse::PA_STREAM_ADJUST_LATENCY)
+        }
+        None => {
+            let attribs = pulse::pa_buffer_attr {
+                maxlength: !0,
+                tlength: !0,
+                prebuf: !0,
+                minreq: !0,
+                fragsize: !0,
+            };
+            (attribs, pulse::PA_STREAM_NOFLAGS)
+        }
+    }
 }
 
-fn map_format(format: api::Format) -> pulse::pa_sample_format_t {
-    match format {
-        api::Format::I16 => pulse::pa_sample_format_t::S16le,
-        api::Format::F32 => pulse::pa_sample_format_t::F32le,
-        _ => unimplemented!(),
+/// Timeout for connecting to the pulse server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of mainloop iterations per readiness poll.
+const MAX_DISPATCH_ITERATIONS: usize = 4;
+
+/// Map a pulse error number to the corresponding error.
+fn map_error(errno: i32) -> api::Error {
+    let source = Some(api::NativeError::Pulse(errno));
+    match errno {
+        e if e == pulse::PA_ERR_BUSY as i32 => api::Error::DeviceBusy { source },
+        e if e == pulse::PA_ERR_TIMEOUT as i32 => api::Error::Timeout { source },
+        e if e == pulse::PA_ERR_NOTSUPPORTED as i32 || e == pulse::PA_ERR_NOTIMPLEMENTED as i32 => {
+            api::Error::Unsupported { source }
+        }
+        e if e == pulse::PA_ERR_CONNECTIONREFUSED as i32
+            || e == pulse::PA_ERR_INVALIDSERVER as i32
+            || e == pulse::PA_ERR_ACCESS as i32
+            || e == pulse::PA_ERR_AUTHKEY as i32
+            || e == pulse::PA_ERR_VERSION as i32 =>
+        {
+            api::Error::BackendUnavailable { source }
+        }
+        e if e == pulse::PA_ERR_CONNECTIONTERMINATED as i32
+            || e == pulse::PA_ERR_KILLED as i32
+            || e == pulse::PA_ERR_NOENTITY as i32 =>
+        {
+            api::Error::Disconnected { source }
+        }
+        _ => api::Error::Internal {
+            cause: unsafe { CStr::from_ptr(pulse::pa_strerror(errno)) }
+                .to_string_lossy()
+                .into_owned(),
+        },
     }
 }
 
-pub struct Instance {
+/// Sample formats supported by pulse.
+const FORMATS: [(api::Format, pulse::pa_sample_format_t); 11] = [
+    (api::Format::U8, pulse::pa_sample_format_t::U8),
+    (api::Format::I16, pulse::pa_sample_format_t::S16le),
+    (api::Format::I16Be, pulse::pa_sample_format_t::S16be),
+    (api::Format::I24, pulse::pa_sample_format_t::S24le),
+    (api::Format::I24Be, pulse::pa_sample_format_t::S24be),
+    (api::Format::I24In32, pulse::pa_sample_format_t::S24_32le),
+    (api::Format::I24In32Be, pulse::pa_sample_format_t::S24_32be),
+    (api::Format::I32, pulse::pa_sample_format_t::S32le),
+    (api::Format::I32Be, pulse::pa_sample_format_t::S32be),
+    (api::Format::F32, pulse::pa_sample_format_t::F32le),
+    (api::Format::F32Be, pulse::pa_sample_format_t::F32be),
+];
+
+fn map_format(format: api::Format) -> Option<pulse::pa_sample_format_t> {
+    FORMATS
+        .iter()
+        .find(|(f, _)| *f == format)
+        .map(|&(_, sample_format)| sample_format)
+}
+
+fn map_sample_format(sample_format: pulse::pa_sample_format_t) -> Option<api::Format> {
+    FORMATS
+        .iter()
+        .find(|(_, f)| *f == sample_format)
+        .map(|&(format, _)| format)
+}
+
+/// Mainloop with a context connected to the server.
+///
+/// Mainloops aren't thread-safe, the instance and each device use a separate connection.
+struct Connection {
     mainloop: *mut pulse::pa_mainloop,
     context: *mut pulse::pa_context,
-    physical_devices: PhysicalDeviceMap,
+    /// Records the mainloop file descriptors for waiting outside of the mainloop.
+    poll: Box<poll::PollState>,
+}
+
+pub struct Instance {
+    /// Locked for dispatching, as executors dispatch instance events from their thread.
+    connection: Mutex<Connection>,
+    /// Application name, used for the connections of devices.
+    name: CString,
+    state: Box<State>,
 }
 
+// The connection is only accessed through the instance, the state is locked.
+unsafe impl Send for Instance {}
+unsafe impl Sync for Instance {}
+
 impl api::Instance for Instance {
     type Device = Device;
     type Session = (); // TODO
 
-    unsafe fn properties() -> api::InstanceProperties {
+    unsafe fn properties(&self) -> api::InstanceProperties {
         api::InstanceProperties {
             driver_id: api::DriverId::PulseAudio,
             stream_mode: api::StreamMode::Polling,
@@ -148,101 +859,141 @@ impl api::Instance for Instance {
         }
     }
 
-    unsafe fn create(name: &str) -> Self {
-        let name = std::ffi::CString::new(name).unwrap();
-        let mainloop = pulse::pa_mainloop_new();
-        let api = pulse::pa_mainloop_get_api(mainloop);
-        let context = pulse::pa_context_new(api, name.as_ptr() as *const _);
-        pulse::pa_context_connect(context, ptr::null(), 0, ptr::null());
+    unsafe fn create(name: &str) -> Result<Self> {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return api::Error::validation("instance name must not contain nul bytes"),
+        };
+        let connection = Connection::connect(&name)?;
+        let context = connection.context;
 
-        loop {
-            pulse::pa_mainloop_iterate(mainloop, true as _, ptr::null_mut());
-            let state = pulse::pa_context_get_state(context);
-            if state == pulse::PA_CONTEXT_READY {
-                break;
-            }
-        }
+        let state = Box::new(State {
+            physical_devices: Mutex::new(PhysicalDevices::default()),
+            event_callback: Mutex::new(None),
+        });
+        let user = &*state as *const State as *mut c_void;
 
-        let mut physical_devices = PhysicalDeviceMap::new();
+        // Dropping the instance on failure clears the subscribe callback and disconnects.
+        let mut instance = Instance {
+            connection: Mutex::new(connection),
+            name,
+            state,
+        };
+        let connection = instance
+            .connection
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // output devices
+        let operation = pulse::pa_context_get_sink_info_list(context, Some(sink_info_cb), user);
+        connection.await_operation(operation)?;
 
         // input devices
-        let operation = pulse::pa_context_get_sink_info_list(
-            context,
-            Some(sink_info_cb),
-            &mut physical_devices as *mut _ as _,
-        );
-        Self::await_operation(mainloop, operation);
+        let operation = pulse::pa_context_get_source_info_list(context, Some(source_info_cb), user);
+        connection.await_operation(operation)?;
 
-        // output devices
-        let operation = pulse::pa_context_get_source_info_list(
+        // default devices
+        let operation = pulse::pa_context_get_server_info(context, Some(server_info_cb), user);
+        connection.await_operation(operation)?;
+
+        // Keep the physical devices and defaults up to date.
+        // Events are dispatched while iterating the instance mainloop, i.e by `dispatch_pending`.
+        pulse::pa_context_set_subscribe_callback(context, Some(subscribe_cb), user);
+        let operation = pulse::pa_context_subscribe(
             context,
-            Some(source_info_cb),
-            &mut physical_devices as *mut _ as _,
+            pulse::PA_SUBSCRIPTION_MASK_SINK
+                | pulse::PA_SUBSCRIPTION_MASK_SOURCE
+                | pulse::PA_SUBSCRIPTION_MASK_SERVER,
+            None,
+            ptr::null_mut(),
         );
-        Self::await_operation(mainloop, operation);
+        connection.await_operation(operation)?;
 
-        Instance {
-            mainloop,
-            context,
-            physical_devices,
-        }
+        Ok(instance)
     }
 
     unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
-        self.physical_devices
+        self.state
+            .physical_devices()
+            .map
             .values()
+            .filter(|device| !device.streams().is_empty())
             .map(|device| device.raw())
             .collect()
     }
 
     unsafe fn default_physical_input_device(&self) -> Option<api::PhysicalDevice> {
-        self.physical_devices
-            .get("default")
-            .filter(|device| device.streams.contains(api::StreamFlags::INPUT))
-            .map(|device| device.raw())
+        let physical_devices = self.state.physical_devices();
+        physical_devices
+            .default_source
+            .as_ref()
+            .and_then(|name| physical_devices.find_endpoint(name, api::StreamFlags::INPUT))
     }
 
     unsafe fn default_physical_output_device(&self) -> Option<api::PhysicalDevice> {
-        self.physical_devices
-            .get("default")
-            .filter(|device| device.streams.contains(api::StreamFlags::OUTPUT))
-            .map(|device| device.raw())
+        let physical_devices = self.state.physical_devices();
+        physical_devices
+            .default_sink
+            .as_ref()
+            .and_then(|name| physical_devices.find_endpoint(name, api::StreamFlags::OUTPUT))
     }
 
     unsafe fn physical_device_properties(
         &self,
         physical_device: api::PhysicalDevice,
     ) -> Result<api::PhysicalDeviceProperties> {
-        let physical_device = Handle::<PhysicalDevice>::from_raw(physical_device);
+        let physical_devices = self.state.physical_devices();
+        let physical_device = physical_devices.get(physical_device);
 
         Ok(api::PhysicalDeviceProperties {
             device_name: physical_device.device_name.clone(),
-            streams: physical_device.streams,
+            streams: physical_device.streams(),
             form_factor: api::FormFactor::Unknown, // TODO?
         })
     }
 
     unsafe fn physical_device_supports_format(
         &self,
-        _physical_device: api::PhysicalDevice,
+        physical_device: api::PhysicalDevice,
         sharing: api::SharingMode,
-        _frame_desc: api::FrameDesc,
+        frame_desc: api::FrameDesc,
     ) -> bool {
         if sharing == api::SharingMode::Exclusive {
             // concurrent only
             return false;
         }
 
-        // TODO: supporting everything?
-        return true;
+        let physical_devices = self.state.physical_devices();
+        match physical_devices.get(physical_device).endpoint() {
+            Ok(endpoint) => endpoint.pcm.supports(&frame_desc),
+            Err(_) => false,
+        }
+    }
+
+    unsafe fn physical_device_format_capabilities(
+        &self,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+    ) -> Result<api::FormatCapabilities> {
+        if sharing == api::SharingMode::Exclusive {
+            // concurrent only
+            return Ok(api::FormatCapabilities::default());
+        }
+
+        self.state
+            .physical_devices()
+            .get(physical_device)
+            .format_capabilities()
     }
 
     unsafe fn physical_device_default_concurrent_format(
         &self,
         physical_device: api::PhysicalDevice,
     ) -> Result<api::FrameDesc> {
-        let physical_device = Handle::<PhysicalDevice>::from_raw(physical_device);
-        physical_device.default_format()
+        self.state
+            .physical_devices()
+            .get(physical_device)
+            .default_format()
     }
 
     unsafe fn create_device(
@@ -251,155 +1002,750 @@ impl api::Instance for Instance {
         channels: api::Channels,
         callback: api::StreamCallback,
     ) -> Result<Self::Device> {
-        let stream = if !channels.output.is_empty() {
-            let spec = pulse::pa_sample_spec {
-                format: map_format(desc.sample_desc.format),
-                channels: channels.output.bits().count_ones() as _,
-                rate: desc.sample_desc.sample_rate as _,
-            };
+        // Endpoints may change while connecting, which iterates the mainloop.
+        let (sink, source) = {
+            let physical_devices = self.state.physical_devices();
+            let physical_device = physical_devices.get(desc.physical_device);
+            (physical_device.sink.clone(), physical_device.source.clone())
+        };
 
-            let stream = dbg!(pulse::pa_stream_new(
-                self.context,
-                b"audir\0".as_ptr() as _,
-                &spec,
-                ptr::null()
-            )); // TODO: name, channel map
+        // Streams are driven by a separate mainloop, allowing devices to move between threads.
+        let connection = Connection::connect(&self.name)?;
 
-            // TODO
-            let attribs = pulse::pa_buffer_attr {
-                maxlength: !0,
-                tlength: !0,
-                prebuf: !0,
-                minreq: !0,
-                fragsize: !0,
-            };
+        let stream = match (channels.input.is_empty(), channels.output.is_empty()) {
+            (true, false) => DeviceStream::Output {
+                stream: connection.connect_playback(&desc, sink.as_ref(), channels.output)?,
+                cur_buffer: ptr::null_mut(),
+            },
+            (false, true) => DeviceStream::Input {
+                stream: connection.connect_record(&desc, source.as_ref(), channels.input)?,
+                scratch: Vec::new(),
+            },
+            (false, false) => {
+                if channels.input != channels.output {
+                    return api::Error::validation(
+                        "duplex streams require matching input and output channels",
+                    );
+                }
 
-            dbg!(pulse::pa_stream_connect_playback(
-                stream,
-                ptr::null(),
-                &attribs,
-                0,
-                ptr::null(),
-                ptr::null_mut(),
-            ));
+                let input = connection.connect_record(&desc, source.as_ref(), channels.input)?;
+                let output =
+                    match connection.connect_playback(&desc, sink.as_ref(), channels.output) {
+                        Ok(output) => output,
+                        Err(err) => {
+                            pulse::pa_stream_disconnect(input);
+                            pulse::pa_stream_unref(input);
+                            return Err(err);
+                        }
+                    };
 
-            loop {
-                let state = dbg!(pulse::pa_stream_get_state(stream));
-                if state == pulse::PA_STREAM_READY {
-                    break;
+                DeviceStream::Duplex {
+                    input,
+                    output,
+                    cur_buffer: ptr::null_mut(),
+                    input_buffer: Vec::new(),
+                    scratch: Vec::new(),
                 }
-                pulse::pa_mainloop_iterate(self.mainloop, true as _, ptr::null_mut());
             }
-
-            stream
-        } else {
-            todo!()
+            (true, true) => {
+                return api::Error::validation("input and output channel masks are empty")
+            }
         };
 
-        let sample_spec = &*pulse::pa_stream_get_sample_spec(stream);
+        let sample_spec = &*pulse::pa_stream_get_sample_spec(stream.raw());
         let frame_size = pulse::pa_frame_size(sample_spec);
 
-        Ok(Device {
-            mainloop: self.mainloop,
+        let device = Device {
+            connection,
             stream,
-            cur_buffer: ptr::null_mut(),
+            format: desc.sample_desc.format,
             frame_size,
             callback,
-        })
+            cork_error: Cell::new(None),
+            waker: Box::new(Cell::new(None)),
+            waiter: None,
+        };
+
+        // Wake tasks waiting for readiness when dispatched by the mainloop.
+        let user = &*device.waker as *const Cell<Option<Waker>> as *mut c_void;
+        match device.stream {
+            DeviceStream::Input { stream, .. } => {
+                pulse::pa_stream_set_read_callback(stream, Some(poll::stream_ready_cb), user);
+            }
+            DeviceStream::Output { stream, .. } => {
+                pulse::pa_stream_set_write_callback(stream, Some(poll::stream_ready_cb), user);
+            }
+            DeviceStream::Duplex { input, output, .. } => {
+                pulse::pa_stream_set_read_callback(input, Some(poll::stream_ready_cb), user);
+                pulse::pa_stream_set_write_callback(output, Some(poll::stream_ready_cb), user);
+            }
+        }
+
+        Ok(device)
     }
 
     unsafe fn create_session(&self, _sample_rate: usize) -> Result<Self::Session> {
         Ok(())
     }
 
-    unsafe fn set_event_callback<F>(&mut self, _callback: Option<F>) -> Result<()>
+    unsafe fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
     where
         F: FnMut(api::Event) + Send + 'static,
     {
-        unimplemented!()
+        *self
+            .state
+            .event_callback
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            callback.map(|callback| Box::new(callback) as EventCallback);
+        Ok(())
+    }
+
+    /// File descriptors of the instance mainloop, delivering device events.
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        Ok(self.connection().poll.wait_handles())
+    }
+
+    unsafe fn dispatch_pending(&self) -> Result<()> {
+        let connection = self.connection();
+        connection
+            .poll
+            .dispatch_pending(connection.mainloop, MAX_DISPATCH_ITERATIONS)
     }
 }
 
 impl Instance {
-    unsafe fn await_operation(
-        mainloop: *mut pulse::pa_mainloop,
-        operation: *mut pulse::pa_operation,
-    ) {
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl std::ops::Drop for Instance {
+    fn drop(&mut self) {
+        unsafe {
+            pulse::pa_context_set_subscribe_callback(
+                self.connection().context,
+                None,
+                ptr::null_mut(),
+            );
+        }
+    }
+}
+
+impl Connection {
+    /// Create a mainloop and connect a new context to the server.
+    unsafe fn connect(name: &CStr) -> Result<Self> {
+        let mainloop = pulse::pa_mainloop_new();
+        if mainloop.is_null() {
+            return Err(api::Error::BackendUnavailable { source: None });
+        }
+        let api = pulse::pa_mainloop_get_api(mainloop);
+        let context = pulse::pa_context_new(api, name.as_ptr());
+        if context.is_null() {
+            pulse::pa_mainloop_free(mainloop);
+            return Err(api::Error::BackendUnavailable { source: None });
+        }
+
+        // Record the mainloop file descriptors for waiting outside of the mainloop.
+        let poll = Box::new(poll::PollState::new());
+        poll.install(mainloop);
+
+        // Dropping the connection on failure disconnects the context and frees the mainloop.
+        let connection = Connection {
+            mainloop,
+            context,
+            poll,
+        };
+
+        if pulse::pa_context_connect(context, ptr::null(), pulse::PA_CONTEXT_NOFLAGS, ptr::null())
+            < 0
+        {
+            return Err(api::Error::BackendUnavailable {
+                source: Some(api::NativeError::Pulse(pulse::pa_context_errno(context))),
+            });
+        }
+        connection.await_context()?;
+
+        Ok(connection)
+    }
+
+    unsafe fn create_stream(
+        &self,
+        desc: &api::DeviceDesc,
+        channels: api::ChannelLayout,
+    ) -> Result<*mut pulse::pa_stream> {
+        if channels.num_channels() > pulse::PA_CHANNELS_MAX as usize {
+            return api::Error::validation(format!(
+                "unsupported number of channels ({}), pulse supports at most {}",
+                channels.num_channels(),
+                pulse::PA_CHANNELS_MAX
+            ));
+        }
+
+        let format = match map_format(desc.sample_desc.format) {
+            Some(format) => format,
+            None => {
+                return api::Error::validation(format!(
+                    "unsupported format ({:?})",
+                    desc.sample_desc.format
+                ))
+            }
+        };
+
+        let spec = pulse::pa_sample_spec {
+            format,
+            channels: channels.num_channels() as _,
+            rate: desc.sample_desc.sample_rate as _,
+        };
+        let channel_map = map_channel_layout(channels);
+
+        // TODO: name
+        let stream =
+            pulse::pa_stream_new(self.context, b"audir\0".as_ptr() as _, &spec, &channel_map);
+        if stream.is_null() {
+            return Err(api::Error::Internal {
+                cause: "failed to create stream".into(),
+            });
+        }
+
+        Ok(stream)
+    }
+
+    unsafe fn connect_playback(
+        &self,
+        desc: &api::DeviceDesc,
+        sink: Option<&Endpoint>,
+        channels: api::ChannelLayout,
+    ) -> Result<*mut pulse::pa_stream> {
+        let sink = match sink {
+            Some(sink) => sink,
+            None => return api::Error::validation("physical device has no output stream"),
+        };
+
+        let stream = self.create_stream(desc, channels)?;
+        let (attribs, flags) =
+            buffer_attr(desc.buffering, &*pulse::pa_stream_get_sample_spec(stream));
+
+        pulse::pa_stream_connect_playback(
+            stream,
+            sink.name.as_ptr(),
+            &attribs,
+            pulse::PA_STREAM_START_CORKED | flags,
+            ptr::null(),
+            ptr::null_mut(),
+        );
+        self.await_stream(stream)?;
+
+        Ok(stream)
+    }
+
+    unsafe fn connect_record(
+        &self,
+        desc: &api::DeviceDesc,
+        source: Option<&Endpoint>,
+        channels: api::ChannelLayout,
+    ) -> Result<*mut pulse::pa_stream> {
+        let source = match source {
+            Some(source) => source,
+            None => return api::Error::validation("physical device has no input stream"),
+        };
+
+        let stream = self.create_stream(desc, channels)?;
+        let (attribs, flags) =
+            buffer_attr(desc.buffering, &*pulse::pa_stream_get_sample_spec(stream));
+
+        pulse::pa_stream_connect_record(
+            stream,
+            source.name.as_ptr(),
+            &attribs,
+            pulse::PA_STREAM_START_CORKED | flags,
+        );
+        self.await_stream(stream)?;
+
+        Ok(stream)
+    }
+
+    /// Wait until the context is connected to the server.
+    unsafe fn await_context(&self) -> Result<()> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            match pulse::pa_context_get_state(self.context) {
+                pulse::PA_CONTEXT_READY => return Ok(()),
+                pulse::PA_CONTEXT_FAILED | pulse::PA_CONTEXT_TERMINATED => {
+                    return Err(api::Error::BackendUnavailable {
+                        source: Some(api::NativeError::Pulse(pulse::pa_context_errno(
+                            self.context,
+                        ))),
+                    });
+                }
+                _ => {}
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(api::Error::Timeout { source: None });
+            }
+            let timeout_us = (deadline - now).as_micros() as i32;
+            if pulse::pa_mainloop_prepare(self.mainloop, timeout_us) < 0
+                || pulse::pa_mainloop_poll(self.mainloop) < 0
+                || pulse::pa_mainloop_dispatch(self.mainloop) < 0
+            {
+                return Err(api::Error::BackendUnavailable { source: None });
+            }
+        }
+    }
+
+    unsafe fn await_stream(&self, stream: *mut pulse::pa_stream) -> Result<()> {
+        loop {
+            match pulse::pa_stream_get_state(stream) {
+                pulse::PA_STREAM_READY => return Ok(()),
+                pulse::PA_STREAM_FAILED | pulse::PA_STREAM_TERMINATED => {
+                    pulse::pa_stream_unref(stream);
+                    return Err(map_error(pulse::pa_context_errno(self.context)));
+                }
+                _ => {}
+            }
+            pulse::pa_mainloop_iterate(self.mainloop, true as _, ptr::null_mut());
+        }
+    }
+
+    unsafe fn await_operation(&self, operation: *mut pulse::pa_operation) -> Result<()> {
+        if operation.is_null() {
+            return Err(api::Error::Internal {
+                cause: "failed to start operation".into(),
+            });
+        }
+
         loop {
             let state = pulse::pa_operation_get_state(operation);
             if state != pulse::PA_OPERATION_RUNNING {
                 pulse::pa_operation_unref(operation);
-                break;
+                return Ok(());
+            }
+            if pulse::pa_mainloop_iterate(self.mainloop, true as _, ptr::null_mut()) < 0 {
+                pulse::pa_operation_cancel(operation);
+                pulse::pa_operation_unref(operation);
+                return Err(api::Error::Disconnected { source: None });
             }
-            pulse::pa_mainloop_iterate(mainloop, true as _, ptr::null_mut());
         }
     }
 }
 
+impl std::ops::Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            pulse::pa_context_disconnect(self.context);
+            pulse::pa_context_unref(self.context);
+            pulse::pa_mainloop_free(self.mainloop);
+        }
+    }
+}
+
+enum DeviceStream {
+    Input {
+        stream: *mut pulse::pa_stream,
+        /// Aligned buffer handed out for holes and misaligned fragments of the record stream.
+        scratch: Vec<u64>,
+    },
+    Output {
+        stream: *mut pulse::pa_stream,
+        cur_buffer: *mut c_void,
+    },
+    /// Record and playback stream on the same physical device.
+    ///
+    /// Recorded fragments are queued in `input_buffer` until the playback stream
+    /// is writable, absorbing scheduling differences between both streams.
+    Duplex {
+        input: *mut pulse::pa_stream,
+        output: *mut pulse::pa_stream,
+        cur_buffer: *mut c_void,
+        input_buffer: Vec<u8>,
+        /// Aligned copy of the input frames handed out from `input_buffer`.
+        scratch: Vec<u64>,
+    },
+}
+
+impl DeviceStream {
+    fn streams(&self) -> Vec<*mut pulse::pa_stream> {
+        match *self {
+            DeviceStream::Input { stream, .. } | DeviceStream::Output { stream, .. } => {
+                vec![stream]
+            }
+            DeviceStream::Duplex { input, output, .. } => vec![input, output],
+        }
+    }
+
+    /// Stream used for querying stream properties.
+    fn raw(&self) -> *mut pulse::pa_stream {
+        match *self {
+            DeviceStream::Input { stream, .. } | DeviceStream::Output { stream, .. } => stream,
+            DeviceStream::Duplex { output, .. } => output,
+        }
+    }
+}
+
+/// Move all readable fragments of a record stream into `buffer`.
+///
+/// Holes are filled with silence. The buffer is limited to `max_len` bytes, dropping the oldest data.
+unsafe fn read_fragments(stream: *mut pulse::pa_stream, buffer: &mut Vec<u8>, max_len: usize) {
+    while pulse::pa_stream_readable_size(stream) > 0 {
+        let mut data = ptr::null();
+        let mut size = 0;
+        if pulse::pa_stream_peek(stream, &mut data, &mut size) < 0 || size == 0 {
+            break;
+        }
+
+        if data.is_null() {
+            buffer.resize(buffer.len() + size, 0);
+        } else {
+            buffer.extend_from_slice(slice::from_raw_parts(data as *const u8, size));
+        }
+        pulse::pa_stream_drop(stream);
+    }
+
+    if buffer.len() > max_len {
+        let excess = buffer.len() - max_len;
+        buffer.drain(..excess);
+    }
+}
+
 pub struct Device {
-    mainloop: *mut pulse::pa_mainloop,
-    stream: *mut pulse::pa_stream,
-    cur_buffer: *mut c_void,
+    connection: Connection,
+    stream: DeviceStream,
+    format: api::Format,
     frame_size: usize,
     callback: api::StreamCallback,
+    /// Failure of the last `start` or `stop`, reported by the next stream operation.
+    cork_error: Cell<Option<api::Error>>,
+    /// Waker of the task waiting for readiness, woken by the stream callbacks.
+    waker: Box<Cell<Option<Waker>>>,
+    /// Waits for the mainloop file descriptors, spawned on the first pending readiness poll.
+    waiter: Option<poll::Waiter>,
+}
+
+// The connection is only accessed through the device.
+unsafe impl Send for Device {}
+
+impl std::ops::Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            for stream in self.stream.streams() {
+                poll::clear_stream_callbacks(stream);
+                pulse::pa_stream_disconnect(stream);
+                pulse::pa_stream_unref(stream);
+            }
+        }
+    }
+}
+
+extern "C" fn stream_success_cb(_stream: *mut pulse::pa_stream, success: i32, user: *mut c_void) {
+    unsafe {
+        *(user as *mut i32) = success;
+    }
 }
 
 impl Device {
-    unsafe fn acquire_buffers(&mut self, timeout_ms: u32) -> Result<api::StreamBuffers> {
-        let mut size = loop {
-            let size = pulse::pa_stream_writable_size(self.stream);
-            if size > 0 {
-                break size;
+    /// Run a stream operation and wait for its completion.
+    unsafe fn await_stream_operation<F>(&self, operation: F) -> Result<()>
+    where
+        F: FnOnce(pulse::pa_stream_success_cb_t, *mut c_void) -> *mut pulse::pa_operation,
+    {
+        let mut success = 0;
+        let operation = operation(Some(stream_success_cb), &mut success as *mut i32 as _);
+        if operation.is_null() {
+            return Err(api::Error::Internal {
+                cause: "failed to start stream operation".into(),
+            });
+        }
+        self.connection.await_operation(operation)?;
+
+        if success != 0 {
+            Ok(())
+        } else {
+            Err(api::Error::Internal {
+                cause: "stream operation failed".into(),
+            })
+        }
+    }
+
+    unsafe fn cork(&self, cork: bool) -> Result<()> {
+        for stream in self.stream.streams() {
+            self.await_stream_operation(|cb, user| {
+                pulse::pa_stream_cork(stream, cork as _, cb, user)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Fail with the error of the last `start` or `stop`.
+    fn cork_result(&self) -> Result<()> {
+        match self.cork_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a single mainloop iteration, waiting at most until `deadline`.
+    ///
+    /// Fails with `Error::Timeout` once the deadline passed, without deadline waits infinitely.
+    unsafe fn iterate(&self, deadline: Option<Instant>) -> Result<()> {
+        let timeout_us = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(api::Error::Timeout { source: None });
+                }
+                (deadline - now).as_micros().min(c_int::MAX as _) as c_int
             }
+            None => -1,
+        };
 
-            pulse::pa_mainloop_prepare(self.mainloop, timeout_ms as _); // TODO: timeout
-            pulse::pa_mainloop_poll(self.mainloop);
-            pulse::pa_mainloop_dispatch(self.mainloop);
+        let mainloop = self.connection.mainloop;
+        if pulse::pa_mainloop_prepare(mainloop, timeout_us) < 0
+            || pulse::pa_mainloop_poll(mainloop) < 0
+            || pulse::pa_mainloop_dispatch(mainloop) < 0
+        {
+            return Err(api::Error::Disconnected { source: None });
+        }
+        Ok(())
+    }
+
+    /// Run a single mainloop iteration without blocking.
+    ///
+    /// Returns the number of dispatched events.
+    unsafe fn dispatch(&self) -> Result<c_int> {
+        self.connection
+            .poll
+            .dispatch(self.connection.mainloop)
+            .ok_or(api::Error::Disconnected { source: None })
+    }
+
+    /// Number of frames, which can be submitted without waiting, without dispatching events.
+    unsafe fn pending_frames(&mut self) -> Result<api::Frames> {
+        let frame_size = self.frame_size;
+        let size = match self.stream {
+            DeviceStream::Input { stream, .. } => pulse::pa_stream_readable_size(stream),
+            DeviceStream::Output { stream, .. } => pulse::pa_stream_writable_size(stream),
+            DeviceStream::Duplex {
+                input,
+                output,
+                ref mut input_buffer,
+                ..
+            } => {
+                let max_len = (*pulse::pa_stream_get_buffer_attr(output)).tlength as usize;
+                read_fragments(input, input_buffer, max_len);
+                let writable = pulse::pa_stream_writable_size(output);
+                if writable == !0 {
+                    writable
+                } else {
+                    writable.min(input_buffer.len())
+                }
+            }
         };
 
-        let mut data = ptr::null_mut();
-        pulse::pa_stream_begin_write(self.stream, &mut data, &mut size);
-        self.cur_buffer = data;
-        Ok(api::StreamBuffers {
-            input: ptr::null(),
-            output: data as _,
-            frames: (size / self.frame_size) as _,
-        })
+        // Size queries fail with `(size_t) -1`.
+        if size == !0 {
+            return Err(api::Error::Disconnected { source: None });
+        }
+        Ok(size / frame_size)
+    }
+
+    unsafe fn acquire_buffers(&mut self, timeout_ms: u32) -> Result<api::StreamBuffers> {
+        self.cork_result()?;
+        let frame_size = self.frame_size;
+        let deadline = if timeout_ms == !0 {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_millis(timeout_ms as _))
+        };
+
+        match self.stream {
+            DeviceStream::Input { stream, .. } => {
+                while pulse::pa_stream_readable_size(stream) == 0 {
+                    self.iterate(deadline)?;
+                }
+
+                let mut data = ptr::null();
+                let mut size = 0;
+                pulse::pa_stream_peek(stream, &mut data, &mut size);
+
+                if let DeviceStream::Input {
+                    ref mut scratch, ..
+                } = self.stream
+                {
+                    if data.is_null() && size > 0 {
+                        // Hole in the record stream, fill with silence.
+                        let buffer = convert::scratch(scratch, size);
+                        block::silence(self.format, buffer);
+                        data = buffer.as_ptr() as _;
+                    } else if data.align_offset(mem::align_of::<u64>()) != 0 {
+                        // Typed buffer views require aligned samples.
+                        let buffer = convert::scratch(scratch, size);
+                        buffer.copy_from_slice(slice::from_raw_parts(data as *const u8, size));
+                        data = buffer.as_ptr() as _;
+                    }
+                }
+
+                Ok(api::StreamBuffers {
+                    input: data as _,
+                    output: ptr::null_mut(),
+                    frames: (size / frame_size) as _,
+                })
+            }
+            DeviceStream::Output { stream, .. } => {
+                let mut size = loop {
+                    let size = pulse::pa_stream_writable_size(stream);
+                    if size > 0 {
+                        break size;
+                    }
+                    self.iterate(deadline)?;
+                };
+
+                let mut data = ptr::null_mut();
+                pulse::pa_stream_begin_write(stream, &mut data, &mut size);
+                if let DeviceStream::Output {
+                    ref mut cur_buffer, ..
+                } = self.stream
+                {
+                    *cur_buffer = data;
+                }
+
+                Ok(api::StreamBuffers {
+                    input: ptr::null(),
+                    output: data as _,
+                    frames: (size / frame_size) as _,
+                })
+            }
+            DeviceStream::Duplex { input, output, .. } => {
+                // Don't queue more input than the playback stream targets.
+                let max_len = (*pulse::pa_stream_get_buffer_attr(output)).tlength as usize;
+
+                let frames = loop {
+                    if let DeviceStream::Duplex {
+                        ref mut input_buffer,
+                        ..
+                    } = self.stream
+                    {
+                        read_fragments(input, input_buffer, max_len);
+
+                        let writable = pulse::pa_stream_writable_size(output) / frame_size;
+                        let readable = input_buffer.len() / frame_size;
+                        let frames = writable.min(readable);
+                        if frames > 0 {
+                            break frames;
+                        }
+                    }
+                    self.iterate(deadline)?;
+                };
+
+                let mut data = ptr::null_mut();
+                let mut size = frames * frame_size;
+                pulse::pa_stream_begin_write(output, &mut data, &mut size);
+                // Bounded by the queued input.
+                size = size.min(frames * frame_size);
+
+                match self.stream {
+                    DeviceStream::Duplex {
+                        ref mut cur_buffer,
+                        ref input_buffer,
+                        ref mut scratch,
+                        ..
+                    } => {
+                        *cur_buffer = data;
+                        // The queue isn't aligned for typed buffer views.
+                        let input = convert::scratch(scratch, size);
+                        input.copy_from_slice(&input_buffer[..size]);
+                        Ok(api::StreamBuffers {
+                            input: input.as_ptr() as _,
+                            output: data as _,
+                            frames: (size / frame_size) as _,
+                        })
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
     }
 
     unsafe fn release_buffers(&mut self, num_frames: api::Frames) -> Result<()> {
-        pulse::pa_stream_write(
-            self.stream,
-            self.cur_buffer,
-            num_frames * self.frame_size,
-            None,
-            0,
-            pulse::PA_SEEK_RELATIVE,
-        );
+        match self.stream {
+            DeviceStream::Input { stream, .. } => {
+                // Empty peeks must not be dropped.
+                if num_frames > 0 {
+                    pulse::pa_stream_drop(stream);
+                }
+            }
+            DeviceStream::Output { stream, cur_buffer } => {
+                return self.write(stream, cur_buffer, num_frames * self.frame_size);
+            }
+            DeviceStream::Duplex {
+                output,
+                cur_buffer,
+                ref mut input_buffer,
+                ..
+            } => {
+                let size = num_frames * self.frame_size;
+                input_buffer.drain(..size);
+                return self.write(output, cur_buffer, size);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `size` bytes of `buffer` to the playback stream.
+    unsafe fn write(
+        &self,
+        stream: *mut pulse::pa_stream,
+        buffer: *mut c_void,
+        size: usize,
+    ) -> Result<()> {
+        if pulse::pa_stream_write(stream, buffer, size, None, 0, pulse::PA_SEEK_RELATIVE) < 0 {
+            return Err(map_error(pulse::pa_context_errno(self.connection.context)));
+        }
         Ok(())
     }
 }
 
 impl api::Device for Device {
+    /// Failures are reported by the next `submit_buffers`, `available_frames` or `poll_ready`.
     unsafe fn start(&self) {
-        println!("Device::start unimplemented");
+        if let Err(err) = self.cork(false) {
+            log::error!("failed to start stream: {}", err);
+            self.cork_error.set(Some(err));
+        }
     }
 
+    /// Failures are reported by the next `submit_buffers`, `available_frames` or `poll_ready`.
     unsafe fn stop(&self) {
-        println!("Device::stop unimplemented");
+        if let Err(err) = self.cork(true) {
+            log::error!("failed to stop stream: {}", err);
+            self.cork_error.set(Some(err));
+        }
     }
 
     unsafe fn stream_properties(&self) -> api::StreamProperties {
-        let stream = self.stream;
+        let stream = self.stream.raw();
 
         let buffer_attrs = &*pulse::pa_stream_get_buffer_attr(stream);
         let sample_spec = &*pulse::pa_stream_get_sample_spec(stream);
         let channel_map = &*pulse::pa_stream_get_channel_map(stream);
 
+        let (buffer_size, num_periods) = match self.stream {
+            DeviceStream::Input { .. } => (buffer_attrs.fragsize, 1),
+            DeviceStream::Output { .. } | DeviceStream::Duplex { .. } => (
+                buffer_attrs.minreq,
+                (buffer_attrs.tlength / buffer_attrs.minreq.max(1)).max(1),
+            ),
+        };
+
         api::StreamProperties {
+            format: self.format,
             channels: map_channels(channel_map),
             sample_rate: sample_spec.rate as _,
-            buffer_size: buffer_attrs.minreq as _,
+            buffer_size: buffer_size as usize / self.frame_size,
+            num_periods: num_periods as _,
         }
     }
 
@@ -412,4 +1758,91 @@ impl api::Device for Device {
         });
         self.release_buffers(buffers.frames)
     }
+
+    unsafe fn available_frames(&mut self) -> Result<api::Frames> {
+        self.cork_result()?;
+        self.dispatch()?;
+        self.pending_frames()
+    }
+
+    /// File descriptors of the device mainloop.
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        Ok(self.connection.poll.wait_handles())
+    }
+
+    unsafe fn dispatch_pending(&mut self) -> Result<()> {
+        self.connection
+            .poll
+            .dispatch_pending(self.connection.mainloop, MAX_DISPATCH_ITERATIONS)
+    }
+
+    unsafe fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<api::Frames>> {
+        if let Err(err) = self.cork_result() {
+            return Poll::Ready(Err(err));
+        }
+        self.waker.set(Some(cx.waker().clone()));
+
+        for _ in 0..MAX_DISPATCH_ITERATIONS {
+            let dispatched = match self.dispatch() {
+                Ok(dispatched) => dispatched,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            match self.pending_frames() {
+                Ok(0) => {}
+                Ok(frames) => {
+                    self.waker.take();
+                    return Poll::Ready(Ok(frames));
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            if dispatched == 0 {
+                break;
+            }
+        }
+
+        if self.waiter.is_none() {
+            match poll::Waiter::new() {
+                Ok(waiter) => self.waiter = Some(waiter),
+                Err(err) => {
+                    return Poll::Ready(Err(api::Error::Internal {
+                        cause: format!("failed to spawn waiter thread: {}", err),
+                    }))
+                }
+            }
+        }
+        if let Some(ref waiter) = self.waiter {
+            let (fds, timeout) = self.connection.poll.fds();
+            waiter.wait(fds, timeout, cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    unsafe fn flush(&mut self) -> Result<()> {
+        for stream in self.stream.streams() {
+            self.await_stream_operation(|cb, user| pulse::pa_stream_flush(stream, cb, user))?;
+        }
+        if let DeviceStream::Duplex {
+            ref mut input_buffer,
+            ..
+        } = self.stream
+        {
+            input_buffer.clear();
+        }
+        Ok(())
+    }
+
+    unsafe fn drain(&self) -> Result<()> {
+        let stream = match self.stream {
+            DeviceStream::Output { stream, .. } => stream,
+            DeviceStream::Duplex { output, .. } => output,
+            DeviceStream::Input { .. } => {
+                return api::Error::validation("`drain` requires an output stream")
+            }
+        };
+        // Corked streams don't play, the drain operation wouldn't complete until started.
+        if pulse::pa_stream_is_corked(stream) > 0 {
+            return api::Error::validation("`drain` requires a started stream");
+        }
+        self.await_stream_operation(|cb, user| pulse::pa_stream_drain(stream, cb, user))
+    }
 }
@@ -0,0 +1,265 @@
+//! Mainloop file descriptors for waiting outside of the mainloop.
+
+use crate::api;
+use libpulse_sys as pulse;
+use std::cell::{Cell, RefCell};
+use std::ffi::c_void;
+use std::os::raw::{c_int, c_ulong};
+use std::sync::mpsc;
+use std::task::Waker;
+use std::time::Duration;
+use std::{io, ptr, thread};
+
+/// File descriptors and timeout of the last mainloop poll.
+pub(super) struct PollState {
+    /// Don't block while polling, only record the file descriptors.
+    nonblocking: Cell<bool>,
+    fds: RefCell<Vec<libc::pollfd>>,
+    /// Timeout in milliseconds, negative for infinite.
+    timeout: Cell<c_int>,
+}
+
+impl PollState {
+    pub fn new() -> Self {
+        PollState {
+            nonblocking: Cell::new(false),
+            fds: RefCell::new(Vec::new()),
+            timeout: Cell::new(-1),
+        }
+    }
+
+    /// Install the recording poll function.
+    pub unsafe fn install(&self, mainloop: *mut pulse::pa_mainloop) {
+        pulse::pa_mainloop_set_poll_func(
+            mainloop,
+            Some(poll_cb),
+            self as *const PollState as *mut c_void,
+        );
+    }
+
+    /// File descriptors and timeout of the last mainloop poll.
+    pub fn fds(&self) -> (Vec<libc::pollfd>, c_int) {
+        (self.fds.borrow().clone(), self.timeout.get())
+    }
+
+    /// Wait handles of the last mainloop poll.
+    pub fn wait_handles(&self) -> api::WaitHandles {
+        let handles = self
+            .fds
+            .borrow()
+            .iter()
+            .map(|fd| {
+                let mut flags = api::WaitFlags::empty();
+                if fd.events & libc::POLLIN != 0 {
+                    flags |= api::WaitFlags::READABLE;
+                }
+                if fd.events & libc::POLLOUT != 0 {
+                    flags |= api::WaitFlags::WRITABLE;
+                }
+                api::WaitHandle::Fd { fd: fd.fd, flags }
+            })
+            .collect();
+        let timeout = self.timeout.get();
+
+        api::WaitHandles {
+            handles,
+            timeout: if timeout < 0 {
+                None
+            } else {
+                Some(Duration::from_millis(timeout as _))
+            },
+        }
+    }
+
+    /// Dispatch pending events without blocking.
+    ///
+    /// Iterates until no events are left, recording the file descriptors for the next wait.
+    pub unsafe fn dispatch_pending(
+        &self,
+        mainloop: *mut pulse::pa_mainloop,
+        max_iterations: usize,
+    ) -> api::Result<()> {
+        for _ in 0..max_iterations {
+            match self.dispatch(mainloop) {
+                Some(0) => break,
+                Some(_) => {}
+                None => return Err(api::Error::Disconnected { source: None }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a single mainloop iteration without blocking.
+    ///
+    /// Returns the number of dispatched events.
+    pub unsafe fn dispatch(&self, mainloop: *mut pulse::pa_mainloop) -> Option<c_int> {
+        self.nonblocking.set(true);
+        let result = if pulse::pa_mainloop_prepare(mainloop, -1) < 0
+            || pulse::pa_mainloop_poll(mainloop) < 0
+        {
+            -1
+        } else {
+            pulse::pa_mainloop_dispatch(mainloop)
+        };
+        self.nonblocking.set(false);
+
+        if result < 0 {
+            None
+        } else {
+            Some(result)
+        }
+    }
+}
+
+extern "C" fn poll_cb(
+    ufds: *mut libc::pollfd,
+    nfds: c_ulong,
+    timeout: c_int,
+    user: *mut c_void,
+) -> c_int {
+    unsafe {
+        let state = &*(user as *const PollState);
+        let mut fds = state.fds.borrow_mut();
+        fds.clear();
+        if !ufds.is_null() {
+            fds.extend_from_slice(std::slice::from_raw_parts(ufds, nfds as _));
+        }
+        state.timeout.set(timeout);
+
+        let timeout = if state.nonblocking.get() { 0 } else { timeout };
+        libc::poll(ufds, nfds as _, timeout)
+    }
+}
+
+struct Request {
+    fds: Vec<libc::pollfd>,
+    timeout: c_int,
+    waker: Waker,
+}
+
+/// Background thread waking tasks on readiness of mainloop file descriptors.
+pub(super) struct Waiter {
+    requests: Option<mpsc::Sender<Request>>,
+    /// Self-pipe for interrupting a pending wait.
+    pipe: [c_int; 2],
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Waiter {
+    pub fn new() -> io::Result<Self> {
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (requests, request_rx) = mpsc::channel();
+        let interrupt = pipe[0];
+        let thread = thread::Builder::new()
+            .name("audir-pulse-waiter".into())
+            .spawn(move || wait_loop(request_rx, interrupt));
+
+        match thread {
+            Ok(thread) => Ok(Waiter {
+                requests: Some(requests),
+                pipe,
+                thread: Some(thread),
+            }),
+            Err(err) => {
+                unsafe {
+                    libc::close(pipe[0]);
+                    libc::close(pipe[1]);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Wake `waker` once any of the file descriptors is ready or the timeout elapsed.
+    ///
+    /// Replaces the pending wait.
+    pub fn wait(&self, fds: Vec<libc::pollfd>, timeout: c_int, waker: Waker) {
+        // Interrupt before sending, the waiter drains the pipe after receiving the request.
+        self.interrupt();
+        if let Some(ref requests) = self.requests {
+            let _ = requests.send(Request {
+                fds,
+                timeout,
+                waker,
+            });
+        }
+    }
+
+    fn interrupt(&self) {
+        let byte = 1u8;
+        unsafe {
+            libc::write(self.pipe[1], &byte as *const u8 as *const c_void, 1);
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.requests = None;
+        self.interrupt();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        unsafe {
+            libc::close(self.pipe[0]);
+            libc::close(self.pipe[1]);
+        }
+    }
+}
+
+/// Discard pending interrupts.
+fn drain(fd: c_int) {
+    let mut buffer = [0u8; 64];
+    while unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) } > 0 {}
+}
+
+fn wait_loop(requests: mpsc::Receiver<Request>, interrupt: c_int) {
+    while let Ok(mut request) = requests.recv() {
+        while let Ok(next) = requests.try_recv() {
+            request = next;
+        }
+        drain(interrupt);
+
+        request.fds.push(libc::pollfd {
+            fd: interrupt,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        unsafe {
+            libc::poll(
+                request.fds.as_mut_ptr(),
+                request.fds.len() as _,
+                request.timeout,
+            );
+        }
+
+        // Interrupted by a newer request or shutdown, the task is already aware.
+        let interrupted = matches!(request.fds.last(), Some(fd) if fd.revents != 0);
+        if !interrupted {
+            request.waker.wake();
+        }
+    }
+}
+
+/// Stream write and read callback, waking the task waiting for readiness.
+pub(super) extern "C" fn stream_ready_cb(
+    _stream: *mut pulse::pa_stream,
+    _nbytes: usize,
+    user: *mut c_void,
+) {
+    unsafe {
+        if let Some(waker) = (*(user as *const Cell<Option<Waker>>)).take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Clear the readiness callbacks of a stream.
+pub(super) unsafe fn clear_stream_callbacks(stream: *mut pulse::pa_stream) {
+    pulse::pa_stream_set_write_callback(stream, None, ptr::null_mut());
+    pulse::pa_stream_set_read_callback(stream, None, ptr::null_mut());
+}
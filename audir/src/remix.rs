@@ -0,0 +1,366 @@
+//! Channel remixing.
+
+use crate::api::{self, ChannelMask, Result};
+use crate::convert::{self, Converter};
+use std::slice;
+
+/// -3 dB
+const ATTENUATION: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Maximum depth for resolving fallback positions.
+const MAX_FALLBACK_DEPTH: usize = 4;
+
+/// Fallback positions for speaker positions missing in the target layout.
+///
+/// Alternatives are tried in order, the first one fully contained in the target layout is used.
+/// Positions of the last alternative are resolved recursively otherwise.
+fn fallbacks(position: ChannelMask) -> &'static [&'static [(ChannelMask, f32)]] {
+    match position {
+        ChannelMask::FRONT_LEFT => &[&[(ChannelMask::FRONT_CENTER, 0.5)]],
+        ChannelMask::FRONT_RIGHT => &[&[(ChannelMask::FRONT_CENTER, 0.5)]],
+        ChannelMask::FRONT_CENTER => &[&[
+            (ChannelMask::FRONT_LEFT, ATTENUATION),
+            (ChannelMask::FRONT_RIGHT, ATTENUATION),
+        ]],
+        ChannelMask::BACK_LEFT => &[
+            &[(ChannelMask::SIDE_LEFT, 1.0)],
+            &[(ChannelMask::FRONT_LEFT, ATTENUATION)],
+        ],
+        ChannelMask::BACK_RIGHT => &[
+            &[(ChannelMask::SIDE_RIGHT, 1.0)],
+            &[(ChannelMask::FRONT_RIGHT, ATTENUATION)],
+        ],
+        ChannelMask::SIDE_LEFT => &[
+            &[(ChannelMask::BACK_LEFT, 1.0)],
+            &[(ChannelMask::FRONT_LEFT, ATTENUATION)],
+        ],
+        ChannelMask::SIDE_RIGHT => &[
+            &[(ChannelMask::BACK_RIGHT, 1.0)],
+            &[(ChannelMask::FRONT_RIGHT, ATTENUATION)],
+        ],
+        ChannelMask::BACK_CENTER => &[
+            &[
+                (ChannelMask::BACK_LEFT, ATTENUATION),
+                (ChannelMask::BACK_RIGHT, ATTENUATION),
+            ],
+            &[
+                (ChannelMask::SIDE_LEFT, ATTENUATION),
+                (ChannelMask::SIDE_RIGHT, ATTENUATION),
+            ],
+            &[
+                (ChannelMask::FRONT_LEFT, 0.5),
+                (ChannelMask::FRONT_RIGHT, 0.5),
+            ],
+        ],
+        ChannelMask::FRONT_LEFT_OF_CENTER => &[&[
+            (ChannelMask::FRONT_LEFT, ATTENUATION),
+            (ChannelMask::FRONT_CENTER, ATTENUATION),
+        ]],
+        ChannelMask::FRONT_RIGHT_OF_CENTER => &[&[
+            (ChannelMask::FRONT_RIGHT, ATTENUATION),
+            (ChannelMask::FRONT_CENTER, ATTENUATION),
+        ]],
+        ChannelMask::TOP_CENTER => &[&[(ChannelMask::FRONT_CENTER, 1.0)]],
+        ChannelMask::TOP_FRONT_LEFT => &[&[(ChannelMask::FRONT_LEFT, 1.0)]],
+        ChannelMask::TOP_FRONT_CENTER => &[&[(ChannelMask::FRONT_CENTER, 1.0)]],
+        ChannelMask::TOP_FRONT_RIGHT => &[&[(ChannelMask::FRONT_RIGHT, 1.0)]],
+        ChannelMask::TOP_BACK_LEFT => &[&[(ChannelMask::BACK_LEFT, 1.0)]],
+        ChannelMask::TOP_BACK_CENTER => &[&[(ChannelMask::BACK_CENTER, 1.0)]],
+        ChannelMask::TOP_BACK_RIGHT => &[&[(ChannelMask::BACK_RIGHT, 1.0)]],
+        // The low frequency channel is dropped in downmixes.
+        _ => &[],
+    }
+}
+
+/// Single speaker positions of a channel mask in interleaving order.
+fn positions(mask: ChannelMask) -> impl Iterator<Item = ChannelMask> {
+    (0..32)
+        .map(|bit| ChannelMask::from_bits_truncate(1 << bit))
+        .filter(move |position| !position.is_empty() && mask.contains(*position))
+}
+
+fn route(
+    coefficients: &mut [f32],
+    target: ChannelMask,
+    position: ChannelMask,
+    gain: f32,
+    depth: usize,
+) {
+    if target.contains(position) {
+        let index = positions(target).position(|p| p == position).unwrap_or(0);
+        coefficients[index] += gain;
+        return;
+    }
+    if depth == MAX_FALLBACK_DEPTH {
+        return;
+    }
+
+    let alternatives = fallbacks(position);
+    let alternative = alternatives
+        .iter()
+        .find(|alternative| alternative.iter().all(|&(p, _)| target.contains(p)))
+        .or_else(|| alternatives.last());
+    if let Some(alternative) = alternative {
+        for &(p, g) in alternative.iter() {
+            route(coefficients, target, p, gain * g, depth + 1);
+        }
+    }
+}
+
+/// Standard mixing matrix from `src` to `dst` layout.
+///
+/// Row-major matrix with one row per target channel and one column per source channel.
+/// Mono sources are duplicated into front left and right channels, other missing
+/// positions are folded down with ITU-R BS.775 style coefficients.
+/// Channels of discrete layouts are mapped by index.
+pub fn mixing_matrix(src: api::ChannelLayout, dst: api::ChannelLayout) -> Vec<f32> {
+    let num_src = src.num_channels();
+    let num_dst = dst.num_channels();
+    let mut matrix = vec![0.0; num_src * num_dst];
+
+    match (src, dst) {
+        (api::ChannelLayout::Mask(src), api::ChannelLayout::Mask(dst)) => {
+            let mut column = vec![0.0; num_dst];
+            for (i, position) in positions(src).enumerate() {
+                column.iter_mut().for_each(|c| *c = 0.0);
+                if src == ChannelMask::MONO
+                    && !dst.contains(ChannelMask::FRONT_CENTER)
+                    && dst.contains(ChannelMask::STEREO)
+                {
+                    route(&mut column, dst, ChannelMask::FRONT_LEFT, 1.0, 0);
+                    route(&mut column, dst, ChannelMask::FRONT_RIGHT, 1.0, 0);
+                } else {
+                    route(&mut column, dst, position, 1.0, 0);
+                }
+                for (row, c) in column.iter().enumerate() {
+                    matrix[row * num_src + i] = *c;
+                }
+            }
+        }
+        _ if num_src == 1 => matrix.iter_mut().for_each(|c| *c = 1.0),
+        _ if num_dst == 1 => matrix.iter_mut().for_each(|c| *c = 1.0 / num_src as f32),
+        _ => {
+            for i in 0..num_src.min(num_dst) {
+                matrix[i * num_src + i] = 1.0;
+            }
+        }
+    }
+
+    matrix
+}
+
+/// Mix interleaved `src` frames into `dst` frames with a row-major mixing matrix.
+pub fn remix(matrix: &[f32], src: &[f32], num_src: usize, dst: &mut [f32], num_dst: usize) {
+    for (src, dst) in src.chunks_exact(num_src).zip(dst.chunks_exact_mut(num_dst)) {
+        for (sample, row) in dst.iter_mut().zip(matrix.chunks_exact(num_src)) {
+            *sample = row.iter().zip(src).map(|(c, s)| c * s).sum();
+        }
+    }
+}
+
+/// Select the mixing matrix for a stage direction, falling back to the standard matrix.
+fn select_matrix(
+    matrix: Option<&Vec<f32>>,
+    src: api::ChannelLayout,
+    dst: api::ChannelLayout,
+) -> Vec<f32> {
+    match matrix {
+        Some(matrix) if matrix.len() == src.num_channels() * dst.num_channels() => matrix.clone(),
+        Some(_) => {
+            log::warn!(
+                "mixing matrix doesn't match layouts {:?} and {:?}, using standard mixing",
+                src,
+                dst
+            );
+            mixing_matrix(src, dst)
+        }
+        None => mixing_matrix(src, dst),
+    }
+}
+
+fn bytes(samples: &[f32]) -> &[u8] {
+    unsafe { slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 4) }
+}
+
+fn bytes_mut(samples: &mut [f32]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, samples.len() * 4) }
+}
+
+/// Wrap the stream callback with a channel remixing stage.
+///
+/// The callback observes streams with the `channels` layout, regardless of the device stream layout.
+/// Remixing is performed on `f32` samples, other stream formats are converted on the fly.
+pub(crate) fn remix_stage(
+    channels: api::ChannelLayout,
+    mixing: api::ChannelMixing,
+    mut callback: api::StreamCallback,
+) -> api::StreamCallback {
+    let (input_matrix, output_matrix) = match mixing {
+        api::ChannelMixing::Standard => (None, None),
+        api::ChannelMixing::Matrix { input, output } => (Some(input), Some(output)),
+    };
+
+    let mut converter = Converter::new(api::Dithering::None);
+    // Created lazily, as the device stream layout is only known after device creation.
+    let mut matrices: Option<(api::ChannelLayout, Vec<f32>, Vec<f32>)> = None;
+    let mut device_samples = Vec::new();
+    let mut samples = Vec::new();
+    let mut input_buffer = Vec::new();
+    let mut output_buffer = Vec::new();
+
+    Box::new(move |stream: api::Stream| {
+        let device_channels = stream.properties.channels;
+        if device_channels == channels {
+            return callback(stream);
+        }
+
+        if matrices.as_ref().map(|(layout, ..)| *layout) != Some(device_channels) {
+            matrices = None;
+        }
+        let (_, input_mix, output_mix) = matrices.get_or_insert_with(|| {
+            (
+                device_channels,
+                select_matrix(input_matrix.as_ref(), device_channels, channels),
+                select_matrix(output_matrix.as_ref(), channels, device_channels),
+            )
+        });
+
+        let format = stream.properties.format;
+        let frames = stream.buffers.frames;
+        let num_device = device_channels.num_channels();
+        let num_channels = channels.num_channels();
+        let mut buffers = stream.buffers;
+
+        if !stream.buffers.input.is_null() {
+            let len = frames * num_device;
+            let src = unsafe {
+                slice::from_raw_parts(stream.buffers.input as *const u8, len * format.size())
+            };
+            device_samples.clear();
+            device_samples.resize(len, 0.0);
+            converter.convert(
+                format,
+                src,
+                api::Format::F32,
+                bytes_mut(&mut device_samples),
+            );
+
+            samples.clear();
+            samples.resize(frames * num_channels, 0.0);
+            remix(
+                input_mix,
+                &device_samples,
+                num_device,
+                &mut samples,
+                num_channels,
+            );
+
+            let buffer = convert::scratch(&mut input_buffer, samples.len() * format.size());
+            converter.convert(api::Format::F32, bytes(&samples), format, buffer);
+            buffers.input = buffer.as_ptr() as _;
+        }
+        if !stream.buffers.output.is_null() {
+            let len = frames * num_channels * format.size();
+            buffers.output = convert::scratch(&mut output_buffer, len).as_mut_ptr() as _;
+        }
+
+        callback(api::Stream {
+            properties: api::StreamProperties {
+                channels,
+                ..stream.properties
+            },
+            buffers,
+        });
+
+        if !stream.buffers.output.is_null() {
+            let src = convert::scratch(&mut output_buffer, frames * num_channels * format.size());
+            samples.clear();
+            samples.resize(frames * num_channels, 0.0);
+            converter.convert(format, src, api::Format::F32, bytes_mut(&mut samples));
+
+            device_samples.clear();
+            device_samples.resize(frames * num_device, 0.0);
+            remix(
+                output_mix,
+                &samples,
+                num_channels,
+                &mut device_samples,
+                num_device,
+            );
+
+            let dst = unsafe {
+                slice::from_raw_parts_mut(
+                    stream.buffers.output as *mut u8,
+                    device_samples.len() * format.size(),
+                )
+            };
+            converter.convert(api::Format::F32, bytes(&device_samples), format, dst);
+        }
+    })
+}
+
+/// Select the device stream channel layouts for a channel remixing stage.
+///
+/// Keeps the requested layouts if supported by the physical device,
+/// otherwise falls back to the layout of the default format.
+pub(crate) unsafe fn device_channels<I: api::Instance + ?Sized>(
+    instance: &I,
+    desc: &api::DeviceDesc,
+    channels: api::Channels,
+) -> Result<api::Channels> {
+    if !channels.input.is_empty()
+        && !channels.output.is_empty()
+        && channels.input != channels.output
+    {
+        return api::Error::validation(
+            "channel remixing requires matching input and output channels",
+        );
+    }
+
+    let capabilities =
+        instance.physical_device_format_capabilities(desc.physical_device, desc.sharing)?;
+    let default_format =
+        instance.physical_device_default_concurrent_format(desc.physical_device)?;
+    let select = |layout: api::ChannelLayout| {
+        if layout.is_empty() || capabilities.channels.contains(&layout) {
+            layout
+        } else {
+            default_format.channels
+        }
+    };
+
+    Ok(api::Channels {
+        input: select(channels.input),
+        output: select(channels.output),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: f32 = ATTENUATION;
+
+    #[test]
+    fn surround_5_1_to_stereo() {
+        let matrix = mixing_matrix(ChannelMask::SURROUND_5_1.into(), ChannelMask::STEREO.into());
+        // Columns: front left, front right, front center, low frequency, back left, back right.
+        #[rustfmt::skip]
+        let expected = [
+            1.0, 0.0, A, 0.0, A, 0.0,
+            0.0, 1.0, A, 0.0, 0.0, A,
+        ];
+        assert_eq!(matrix, expected);
+    }
+
+    #[test]
+    fn mono_to_stereo() {
+        let matrix = mixing_matrix(ChannelMask::MONO.into(), ChannelMask::STEREO.into());
+        assert_eq!(matrix, [1.0, 1.0]);
+
+        let src = [0.25, -0.5, 1.0];
+        let mut dst = [0.0; 6];
+        remix(&matrix, &src, 1, &mut dst, 2);
+        assert_eq!(dst, [0.25, 0.25, -0.5, -0.5, 1.0, 1.0]);
+    }
+}
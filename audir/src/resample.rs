@@ -0,0 +1,413 @@
+//! Sample rate conversion.
+
+use crate::api::{self, Result};
+use crate::convert::{self, Converter};
+use std::f64::consts::PI;
+use std::{ptr, slice};
+
+/// Streaming sample rate converter for interleaved `f32` frames.
+pub struct Resampler {
+    num_channels: usize,
+    quality: api::ResamplingQuality,
+    src_rate: usize,
+    dst_rate: usize,
+    /// Relative cutoff frequency of the sinc filter.
+    cutoff: f64,
+    /// Buffered source frames, including filter history.
+    history: Vec<f32>,
+    /// Source frame of the next target frame in `history`.
+    position: usize,
+    /// Offset of the next target frame to `position` in units of `1 / dst_rate` source frames.
+    ///
+    /// Tracked exactly to avoid accumulating rounding errors while stepping.
+    phase: usize,
+    weights: Vec<f64>,
+}
+
+impl Resampler {
+    pub fn new(
+        quality: api::ResamplingQuality,
+        num_channels: usize,
+        src_rate: usize,
+        dst_rate: usize,
+    ) -> Self {
+        let mut resampler = Resampler {
+            num_channels,
+            quality,
+            src_rate,
+            dst_rate,
+            cutoff: (dst_rate as f64 / src_rate as f64).min(1.0),
+            history: Vec::new(),
+            position: 0,
+            phase: 0,
+            weights: Vec::new(),
+        };
+        // Prime the filter history with silence.
+        resampler.position = resampler.support().0;
+        resampler.history = vec![0.0; resampler.support().0 * num_channels];
+        resampler
+    }
+
+    /// Number of source frames needed before and after the interpolated position.
+    fn support(&self) -> (usize, usize) {
+        match self.quality {
+            api::ResamplingQuality::Linear => (0, 1),
+            api::ResamplingQuality::Sinc { taps } => (taps.max(1) - 1, taps.max(1)),
+        }
+    }
+
+    /// Exclusive upper bound of interpolatable positions in the buffered source frames.
+    fn end(&self) -> usize {
+        let (_, right) = self.support();
+        (self.history.len() / self.num_channels).saturating_sub(right)
+    }
+
+    /// Number of target frames producible from the buffered source frames.
+    pub fn available(&self) -> usize {
+        let start = self.position * self.dst_rate + self.phase;
+        let end = self.end() * self.dst_rate;
+        (end.saturating_sub(start) + self.src_rate - 1) / self.src_rate
+    }
+
+    /// Number of source frames required for producing `frames` target frames.
+    pub fn required(&self, frames: usize) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let start = self.position * self.dst_rate + self.phase;
+        let last = (start + (frames - 1) * self.src_rate) / self.dst_rate;
+        let (_, right) = self.support();
+        (last + right + 1).saturating_sub(self.history.len() / self.num_channels)
+    }
+
+    /// Resample source frames and append the target frames to `dst`.
+    pub fn process(&mut self, src: &[f32], dst: &mut Vec<f32>) {
+        self.history.extend_from_slice(src);
+
+        let num_channels = self.num_channels;
+        let (left, _) = self.support();
+
+        let end = self.end();
+        while self.position < end {
+            let base = self.position;
+            let fract = self.phase as f64 / self.dst_rate as f64;
+
+            match self.quality {
+                api::ResamplingQuality::Linear => {
+                    let frame0 = &self.history[base * num_channels..][..num_channels];
+                    let frame1 = &self.history[(base + 1) * num_channels..][..num_channels];
+                    dst.extend(
+                        frame0
+                            .iter()
+                            .zip(frame1)
+                            .map(|(&a, &b)| (a as f64 + (b as f64 - a as f64) * fract) as f32),
+                    );
+                }
+                api::ResamplingQuality::Sinc { taps } => {
+                    let taps = taps.max(1);
+                    let cutoff = self.cutoff;
+                    self.weights.clear();
+                    self.weights.extend((0..2 * taps).map(|i| {
+                        let x = i as f64 - (taps - 1) as f64 - fract;
+                        windowed_sinc(x, taps as f64, cutoff)
+                    }));
+
+                    let start = base - left;
+                    for channel in 0..num_channels {
+                        let sample = self
+                            .weights
+                            .iter()
+                            .enumerate()
+                            .map(|(i, weight)| {
+                                self.history[(start + i) * num_channels + channel] as f64 * weight
+                            })
+                            .sum::<f64>();
+                        dst.push(sample as f32);
+                    }
+                }
+            }
+
+            self.phase += self.src_rate;
+            self.position += self.phase / self.dst_rate;
+            self.phase %= self.dst_rate;
+        }
+
+        // Drop source frames, which aren't needed anymore.
+        // When downsampling the position may already be ahead of the buffered frames.
+        let num_frames = self.history.len() / num_channels;
+        let consumed = self.position.saturating_sub(left).min(num_frames);
+        self.history.drain(..consumed * num_channels);
+        self.position -= consumed;
+    }
+}
+
+/// Blackman windowed sinc kernel with `half_width` zero crossings per side.
+fn windowed_sinc(x: f64, half_width: f64, cutoff: f64) -> f64 {
+    if x.abs() >= half_width {
+        return 0.0;
+    }
+    let t = x / half_width;
+    let window = 0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos();
+    let sinc = if x == 0.0 {
+        1.0
+    } else {
+        (PI * cutoff * x).sin() / (PI * cutoff * x)
+    };
+    cutoff * sinc * window
+}
+
+/// Convert raw samples of the stream format into `f32` samples.
+fn read_f32(
+    converter: &mut Converter,
+    format: api::Format,
+    src: *const (),
+    dst: &mut Vec<f32>,
+    len: usize,
+) {
+    dst.clear();
+    dst.resize(len, 0.0);
+    let src = unsafe { slice::from_raw_parts(src as *const u8, len * format.size()) };
+    converter.convert(format, src, api::Format::F32, bytes_mut(dst));
+}
+
+fn bytes_mut(samples: &mut [f32]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, samples.len() * 4) }
+}
+
+fn bytes(samples: &[f32]) -> &[u8] {
+    unsafe { slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 4) }
+}
+
+/// Wrap the stream callback with a sample rate conversion stage.
+///
+/// The callback observes streams with `sample_rate`, regardless of the device stream sample rate.
+/// Resampling is performed on `f32` samples, other stream formats are converted on the fly.
+pub(crate) fn resample_stage(
+    sample_rate: usize,
+    resampling: api::Resampling,
+    mut callback: api::StreamCallback,
+) -> api::StreamCallback {
+    let mut converter = Converter::new(api::Dithering::None);
+    // Created lazily, as the device stream sample rate is only known after device creation.
+    let mut resamplers: Option<(usize, Resampler, Resampler)> = None;
+    let mut input = Vec::new();
+    let mut input_resampled = Vec::new();
+    let mut output = Vec::new();
+    let mut output_resampled = Vec::new();
+    let mut input_buffer = Vec::new();
+    let mut output_buffer = Vec::new();
+
+    Box::new(move |stream: api::Stream| {
+        let device_rate = stream.properties.sample_rate;
+        let num_channels = stream.properties.num_channels();
+        if sample_rate == api::DEFAULT_SAMPLE_RATE
+            || device_rate == api::DEFAULT_SAMPLE_RATE
+            || device_rate == sample_rate
+            || num_channels == 0
+        {
+            return callback(stream);
+        }
+
+        let format = stream.properties.format;
+        if resamplers.as_ref().map(|(rate, ..)| *rate) != Some(device_rate) {
+            resamplers = None;
+            output_resampled.clear();
+        }
+        let (_, input_resampler, output_resampler) = resamplers.get_or_insert_with(|| {
+            (
+                device_rate,
+                Resampler::new(resampling.quality, num_channels, device_rate, sample_rate),
+                Resampler::new(resampling.quality, num_channels, sample_rate, device_rate),
+            )
+        });
+
+        let has_input = !stream.buffers.input.is_null();
+        let has_output = !stream.buffers.output.is_null();
+        let device_frames = stream.buffers.frames;
+
+        let properties = api::StreamProperties {
+            sample_rate,
+            buffer_size: stream.properties.buffer_size * sample_rate / device_rate,
+            ..stream.properties
+        };
+        let mut run = |frames: usize, input: *const ()| {
+            let buffer = if has_output {
+                let len = frames * num_channels * format.size();
+                convert::scratch(&mut output_buffer, len).as_mut_ptr() as *mut ()
+            } else {
+                ptr::null_mut()
+            };
+            callback(api::Stream {
+                properties,
+                buffers: api::StreamBuffers {
+                    frames,
+                    input,
+                    output: buffer,
+                },
+            });
+            buffer as *const ()
+        };
+
+        if has_input {
+            read_f32(
+                &mut converter,
+                format,
+                stream.buffers.input,
+                &mut input,
+                device_frames * num_channels,
+            );
+            input_resampled.clear();
+            input_resampler.process(&input, &mut input_resampled);
+
+            let frames = input_resampled.len() / num_channels;
+            if frames > 0 {
+                let len = input_resampled.len() * format.size();
+                let buffer = convert::scratch(&mut input_buffer, len);
+                converter.convert(api::Format::F32, bytes(&input_resampled), format, buffer);
+
+                let app_output = run(frames, buffer.as_ptr() as _);
+                if has_output {
+                    read_f32(
+                        &mut converter,
+                        format,
+                        app_output,
+                        &mut output,
+                        frames * num_channels,
+                    );
+                    output_resampler.process(&output, &mut output_resampled);
+                }
+            }
+        } else if has_output {
+            while output_resampled.len() < device_frames * num_channels {
+                let missing = device_frames - output_resampled.len() / num_channels;
+                let frames = output_resampler
+                    .required(output_resampler.available() + missing)
+                    .max(1);
+                let app_output = run(frames, ptr::null());
+                read_f32(
+                    &mut converter,
+                    format,
+                    app_output,
+                    &mut output,
+                    frames * num_channels,
+                );
+                output_resampler.process(&output, &mut output_resampled);
+            }
+        }
+
+        if has_output {
+            // Duplex streams may run short on output frames initially, pad with silence.
+            let len = device_frames * num_channels;
+            if output_resampled.len() < len {
+                output_resampled.resize(len, 0.0);
+            }
+            let dst = unsafe {
+                slice::from_raw_parts_mut(stream.buffers.output as *mut u8, len * format.size())
+            };
+            converter.convert(
+                api::Format::F32,
+                bytes(&output_resampled[..len]),
+                format,
+                dst,
+            );
+            output_resampled.drain(..len);
+        }
+    })
+}
+
+/// Select the device stream sample rate for a sample rate conversion stage.
+///
+/// Keeps the requested sample rate if supported by the physical device,
+/// otherwise falls back to the sample rate of the default format.
+pub(crate) unsafe fn device_sample_rate<I: api::Instance + ?Sized>(
+    instance: &I,
+    desc: &api::DeviceDesc,
+) -> Result<usize> {
+    let sample_rate = desc.sample_desc.sample_rate;
+    if sample_rate == api::DEFAULT_SAMPLE_RATE {
+        return Ok(sample_rate);
+    }
+
+    let capabilities =
+        instance.physical_device_format_capabilities(desc.physical_device, desc.sharing)?;
+    if capabilities
+        .sample_rates
+        .iter()
+        .any(|range| range.contains(sample_rate))
+    {
+        return Ok(sample_rate);
+    }
+
+    let default_format =
+        instance.physical_device_default_concurrent_format(desc.physical_device)?;
+    Ok(default_format.sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_CHANNELS: usize = 2;
+    const RATES: [(usize, usize); 4] = [
+        (44_100, 48_000),
+        (48_000, 44_100),
+        (48_000, 16_000),
+        (16_000, 48_000),
+    ];
+    const QUALITIES: [api::ResamplingQuality; 2] = [
+        api::ResamplingQuality::Linear,
+        api::ResamplingQuality::Sinc { taps: 8 },
+    ];
+
+    #[test]
+    fn frame_counts() {
+        for &(src_rate, dst_rate) in &RATES {
+            for &quality in &QUALITIES {
+                let mut resampler = Resampler::new(quality, NUM_CHANNELS, src_rate, dst_rate);
+                let mut dst = Vec::new();
+                for &frames in &[1, 7, 256, 480, 1024] {
+                    dst.clear();
+                    let required = resampler.required(frames);
+                    assert!(required > 0);
+
+                    // One source frame less isn't sufficient.
+                    resampler.process(&vec![0.0; (required - 1) * NUM_CHANNELS], &mut dst);
+                    assert!(dst.len() < frames * NUM_CHANNELS);
+                    assert_eq!(resampler.available(), 0);
+
+                    resampler.process(&[0.0; NUM_CHANNELS], &mut dst);
+                    assert!(dst.len() >= frames * NUM_CHANNELS);
+                    assert_eq!(resampler.available(), 0);
+                    assert_eq!(resampler.required(0), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dc_signal() {
+        for &(src_rate, dst_rate) in &RATES {
+            for &quality in &QUALITIES {
+                let mut resampler = Resampler::new(quality, NUM_CHANNELS, src_rate, dst_rate);
+                let mut dst = Vec::new();
+                for _ in 0..8 {
+                    resampler.process(&[0.5; 256 * NUM_CHANNELS], &mut dst);
+                }
+
+                // Skip the filter history primed with silence.
+                let settled = &dst[dst.len() / 2..];
+                assert!(!settled.is_empty());
+                for &sample in settled {
+                    assert!(
+                        (sample - 0.5).abs() < 1e-3,
+                        "{} -> {} {:?}: {}",
+                        src_rate,
+                        dst_rate,
+                        quality,
+                        sample
+                    );
+                }
+            }
+        }
+    }
+}
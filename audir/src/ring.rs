@@ -0,0 +1,142 @@
+//! Lock-free single producer single consumer ring buffer.
+
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Byte ring buffer shared between a single producer and a single consumer thread.
+pub(crate) struct Ring {
+    buffer: Box<[UnsafeCell<u8>]>,
+    /// Read position in `[0, 2 * capacity)`.
+    read: AtomicUsize,
+    /// Write position in `[0, 2 * capacity)`.
+    write: AtomicUsize,
+}
+
+// Producer and consumer access disjoint regions of the buffer.
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    pub fn new(capacity: usize) -> Self {
+        Ring {
+            buffer: (0..capacity.max(1)).map(|_| UnsafeCell::new(0)).collect(),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of bytes available for reading.
+    pub fn readable(&self) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+        (write + 2 * self.capacity() - read) % (2 * self.capacity())
+    }
+
+    /// Number of bytes available for writing.
+    pub fn writable(&self) -> usize {
+        self.capacity() - self.readable()
+    }
+
+    /// Write bytes into the ring, returns the number of bytes written.
+    ///
+    /// ## Validation
+    ///
+    /// - **Must** only be called by the producer thread.
+    pub unsafe fn push(&self, data: &[u8]) -> usize {
+        let len = data.len().min(self.writable());
+        let write = self.write.load(Ordering::Relaxed);
+        let first = len.min(self.capacity() - write % self.capacity());
+        ptr::copy_nonoverlapping(data.as_ptr(), self.slot(write), first);
+        ptr::copy_nonoverlapping(data[first..].as_ptr(), self.slot(0), len - first);
+        self.write
+            .store(self.advance(write, len), Ordering::Release);
+        len
+    }
+
+    /// Read bytes from the ring, returns the number of bytes read.
+    ///
+    /// ## Validation
+    ///
+    /// - **Must** only be called by the consumer thread.
+    pub unsafe fn pop(&self, data: &mut [u8]) -> usize {
+        let len = data.len().min(self.readable());
+        let read = self.read.load(Ordering::Relaxed);
+        let first = len.min(self.capacity() - read % self.capacity());
+        ptr::copy_nonoverlapping(self.slot(read), data.as_mut_ptr(), first);
+        ptr::copy_nonoverlapping(self.slot(0), data[first..].as_mut_ptr(), len - first);
+        self.read.store(self.advance(read, len), Ordering::Release);
+        len
+    }
+
+    fn advance(&self, position: usize, len: usize) -> usize {
+        (position + len) % (2 * self.capacity())
+    }
+
+    /// Pointer to the slot of `position`, contiguous up to the end of the buffer.
+    unsafe fn slot(&self, position: usize) -> *mut u8 {
+        UnsafeCell::raw_get(self.buffer.as_ptr()).add(position % self.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_and_empty() {
+        unsafe {
+            let ring = Ring::new(8);
+            let mut data = [0; 8];
+            assert_eq!(ring.readable(), 0);
+            assert_eq!(ring.pop(&mut data), 0);
+
+            assert_eq!(ring.push(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]), 8);
+            assert_eq!(ring.readable(), 8);
+            assert_eq!(ring.writable(), 0);
+            assert_eq!(ring.push(&[11]), 0);
+
+            assert_eq!(ring.pop(&mut data), 8);
+            assert_eq!(data, [1, 2, 3, 4, 5, 6, 7, 8]);
+            assert_eq!(ring.readable(), 0);
+            assert_eq!(ring.writable(), 8);
+            assert_eq!(ring.pop(&mut data), 0);
+        }
+    }
+
+    #[test]
+    fn wrap_around() {
+        unsafe {
+            let ring = Ring::new(8);
+            let mut next = 0u8;
+            let mut expected = 0u8;
+            // Chunk sizes not dividing the capacity, covering all read and write offsets.
+            for _ in 0..32 {
+                let chunk: Vec<u8> = (0..5).map(|i| next.wrapping_add(i)).collect();
+                assert_eq!(ring.push(&chunk), 5);
+                next = next.wrapping_add(5);
+
+                let mut data = [0; 3];
+                assert_eq!(ring.pop(&mut data), 3);
+                for byte in data {
+                    assert_eq!(byte, expected);
+                    expected = expected.wrapping_add(1);
+                }
+
+                // Drain completely once the ring runs full.
+                if ring.writable() < 5 {
+                    let mut data = vec![0; ring.readable()];
+                    assert_eq!(ring.pop(&mut data), data.len());
+                    for byte in data {
+                        assert_eq!(byte, expected);
+                        expected = expected.wrapping_add(1);
+                    }
+                    assert_eq!(ring.readable(), 0);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,674 @@
+//! Safe wrappers on top of the unsafe instance and device layer.
+//!
+//! Child objects borrow their instance and can't outlive it.
+//! Device descriptors are validated against the physical device before creating a device.
+
+use crate::api::{self, Device as _, Result};
+use crate::dynamic;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use std::{fmt, ptr, thread};
+
+/// Maximum time a single `submit_buffers` call of the run loop waits for buffers.
+const RUN_TIMEOUT_MS: u32 = 100;
+
+/// Interval for checking the run condition of callback based instances.
+const RUN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Owned instance.
+pub struct Instance<I: api::Instance = dynamic::Instance> {
+    raw: I,
+    properties: api::InstanceProperties,
+}
+
+impl<I: api::Instance> Instance<I> {
+    /// Create an instance object.
+    ///
+    /// Fails with `Error::BackendUnavailable` if the backend can't be initialized.
+    pub fn create(name: &str) -> Result<Self> {
+        unsafe { Ok(Self::from_raw(I::create(name)?)) }
+    }
+
+    /// Wrap an existing instance.
+    ///
+    /// ## Validation
+    ///
+    /// - `raw` **must** not have any child objects.
+    ///
+    /// # Safety
+    ///
+    /// - `raw` **must** not be used through other references afterwards, the wrapper
+    ///   relies on owning the instance for the lifetime guarantees of its devices.
+    pub unsafe fn from_raw(raw: I) -> Self {
+        let properties = raw.properties();
+        Instance { raw, properties }
+    }
+
+    /// Underlying unsafe instance.
+    pub fn raw(&self) -> &I {
+        &self.raw
+    }
+
+    /// Get instance properties.
+    pub fn properties(&self) -> api::InstanceProperties {
+        self.properties
+    }
+
+    /// Retrieve a list of physical devices of the current instance.
+    pub fn physical_devices(&self) -> Vec<PhysicalDevice<'_, I>> {
+        unsafe {
+            self.raw
+                .enumerate_physical_devices()
+                .into_iter()
+                .map(|raw| PhysicalDevice::new(&self.raw, raw))
+                .collect()
+        }
+    }
+
+    /// Look up a physical device by its raw handle, e.g reported by an `Event`.
+    ///
+    /// Returns `None` if the handle doesn't belong to a currently available physical device.
+    pub fn physical_device(&self, raw: api::PhysicalDevice) -> Option<PhysicalDevice<'_, I>> {
+        unsafe {
+            self.raw
+                .enumerate_physical_devices()
+                .into_iter()
+                .find(|&physical_device| physical_device == raw)
+                .map(|raw| PhysicalDevice::new(&self.raw, raw))
+        }
+    }
+
+    /// Get the default physical input device.
+    pub fn default_input_device(&self) -> Option<PhysicalDevice<'_, I>> {
+        unsafe {
+            self.raw
+                .default_physical_input_device()
+                .map(|raw| PhysicalDevice::new(&self.raw, raw))
+        }
+    }
+
+    /// Get the default physical output device.
+    pub fn default_output_device(&self) -> Option<PhysicalDevice<'_, I>> {
+        unsafe {
+            self.raw
+                .default_physical_output_device()
+                .map(|raw| PhysicalDevice::new(&self.raw, raw))
+        }
+    }
+
+    pub fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
+    where
+        F: FnMut(api::Event) + Send + 'static,
+    {
+        unsafe { self.raw.set_event_callback(callback) }
+    }
+
+    /// Get the OS handles of the instance event loop, see `api::Instance::wait_handles`.
+    pub fn wait_handles(&self) -> Result<api::WaitHandles> {
+        unsafe { self.raw.wait_handles() }
+    }
+
+    /// Dispatch pending events of the instance event loop without blocking.
+    pub fn dispatch_pending(&self) -> Result<()> {
+        unsafe { self.raw.dispatch_pending() }
+    }
+
+    /// Create a new logical device.
+    ///
+    /// Fails with `Error::Validation` if the descriptor doesn't match the physical device.
+    pub fn create_device<F>(
+        &self,
+        desc: DeviceDesc<'_, I>,
+        channels: api::Channels,
+        mut callback: F,
+    ) -> Result<Device<'_, I>>
+    where
+        F: FnMut(Stream) + Send + 'static,
+    {
+        self.validate_device_desc(&desc, channels)?;
+
+        let callback = Box::new(move |stream: api::Stream| {
+            callback(Stream {
+                raw: stream,
+                _marker: PhantomData,
+            })
+        });
+
+        let raw = unsafe {
+            self.raw.create_device_with_conversion(
+                api::DeviceDesc {
+                    physical_device: desc.physical_device.raw,
+                    sharing: desc.sharing,
+                    sample_desc: desc.sample_desc,
+                    buffering: desc.buffering,
+                },
+                channels,
+                desc.conversion,
+                callback,
+            )?
+        };
+
+        Ok(Device {
+            raw,
+            instance: self,
+        })
+    }
+
+    fn validate_device_desc(
+        &self,
+        desc: &DeviceDesc<'_, I>,
+        channels: api::Channels,
+    ) -> Result<()> {
+        if !ptr::eq(desc.physical_device.instance, &self.raw) {
+            return api::Error::validation("physical device belongs to a different instance");
+        }
+        if channels.input.is_empty() && channels.output.is_empty() {
+            return api::Error::validation("input and output channel layouts are empty");
+        }
+        if let Some(api::BlockSize { frames: 0 }) = desc.conversion.block_size {
+            return api::Error::validation("block size must not be zero");
+        }
+
+        let sharing = match desc.sharing {
+            api::SharingMode::Exclusive => api::SharingModeFlags::EXCLUSIVE,
+            api::SharingMode::Concurrent => api::SharingModeFlags::CONCURRENT,
+        };
+        if !self.properties.sharing.contains(sharing) {
+            return api::Error::validation(format!(
+                "sharing mode {:?} not supported by the instance",
+                desc.sharing
+            ));
+        }
+
+        let properties = desc.physical_device.properties()?;
+        let streams = [
+            (channels.input, api::StreamFlags::INPUT),
+            (channels.output, api::StreamFlags::OUTPUT),
+        ];
+        for &(layout, stream) in &streams {
+            if layout.is_empty() {
+                continue;
+            }
+            if !properties.streams.contains(stream) {
+                return api::Error::validation(format!(
+                    "physical device doesn't support {:?} streams",
+                    stream
+                ));
+            }
+
+            // The default sample rate is resolved by the backend on device creation,
+            // conversion stages select a supported device format.
+            if desc.sample_desc.sample_rate == api::DEFAULT_SAMPLE_RATE
+                || desc.conversion.format.is_some()
+                || desc.conversion.sample_rate.is_some()
+                || desc.conversion.channels.is_some()
+            {
+                continue;
+            }
+            let frame_desc = api::FrameDesc {
+                format: desc.sample_desc.format,
+                channels: layout,
+                sample_rate: desc.sample_desc.sample_rate,
+            };
+            if !desc
+                .physical_device
+                .supports_format(desc.sharing, frame_desc)
+            {
+                return api::Error::validation(format!(
+                    "format {:?} not supported by the physical device",
+                    frame_desc
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Physical device of an instance.
+pub struct PhysicalDevice<'a, I: api::Instance> {
+    instance: &'a I,
+    raw: api::PhysicalDevice,
+}
+
+impl<'a, I: api::Instance> PhysicalDevice<'a, I> {
+    unsafe fn new(instance: &'a I, raw: api::PhysicalDevice) -> Self {
+        PhysicalDevice { instance, raw }
+    }
+
+    /// Raw physical device handle.
+    pub fn raw(&self) -> api::PhysicalDevice {
+        self.raw
+    }
+
+    /// Get physical device properties.
+    pub fn properties(&self) -> Result<api::PhysicalDeviceProperties> {
+        unsafe { self.instance.physical_device_properties(self.raw) }
+    }
+
+    /// Check format support for the physical device.
+    pub fn supports_format(&self, sharing: api::SharingMode, frame_desc: api::FrameDesc) -> bool {
+        unsafe {
+            self.instance
+                .physical_device_supports_format(self.raw, sharing, frame_desc)
+        }
+    }
+
+    /// Get the format capabilities of the physical device.
+    pub fn format_capabilities(
+        &self,
+        sharing: api::SharingMode,
+    ) -> Result<api::FormatCapabilities> {
+        unsafe {
+            self.instance
+                .physical_device_format_capabilities(self.raw, sharing)
+        }
+    }
+
+    /// Negotiate a format with the physical device.
+    pub fn negotiate_format(
+        &self,
+        sharing: api::SharingMode,
+        frame_desc: api::FrameDesc,
+    ) -> Result<api::NegotiatedFormat> {
+        unsafe {
+            self.instance
+                .negotiate_format(self.raw, sharing, frame_desc)
+        }
+    }
+
+    /// Get default concurrent mode format.
+    pub fn default_concurrent_format(&self) -> Result<api::FrameDesc> {
+        unsafe {
+            self.instance
+                .physical_device_default_concurrent_format(self.raw)
+        }
+    }
+}
+
+impl<I: api::Instance> Clone for PhysicalDevice<'_, I> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<I: api::Instance> Copy for PhysicalDevice<'_, I> {}
+
+impl<I: api::Instance> fmt::Debug for PhysicalDevice<'_, I> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple("PhysicalDevice").field(&self.raw).finish()
+    }
+}
+
+/// Device description.
+///
+/// Safe counterpart of `api::DeviceDesc`.
+pub struct DeviceDesc<'a, I: api::Instance> {
+    pub physical_device: PhysicalDevice<'a, I>,
+    pub sharing: api::SharingMode,
+    pub sample_desc: api::SampleDesc,
+    pub buffering: api::Buffering,
+    /// Conversion stages between stream callback and device stream.
+    pub conversion: api::Conversion,
+}
+
+/// Logical device, borrowing its instance.
+pub struct Device<'a, I: api::Instance> {
+    raw: I::Device,
+    instance: &'a Instance<I>,
+}
+
+impl<I: api::Instance> Device<'_, I> {
+    /// Underlying unsafe device.
+    pub fn raw(&self) -> &I::Device {
+        &self.raw
+    }
+
+    pub fn start(&self) {
+        unsafe { self.raw.start() }
+    }
+
+    pub fn stop(&self) {
+        unsafe { self.raw.stop() }
+    }
+
+    /// Properties of the device stream.
+    ///
+    /// Excludes the latency of the block conversion stage, see `api::BlockSize::latency`.
+    pub fn stream_properties(&self) -> api::StreamProperties {
+        unsafe { self.raw.stream_properties() }
+    }
+
+    /// Submit stream buffers.
+    ///
+    /// Fails with `Error::Validation` for devices of callback based instances.
+    pub fn submit_buffers(&mut self, timeout_ms: u32) -> Result<()> {
+        if self.instance.properties.stream_mode != api::StreamMode::Polling {
+            return api::Error::validation("`submit_buffers` requires a polling instance");
+        }
+        unsafe { self.raw.submit_buffers(timeout_ms) }
+    }
+
+    /// Number of frames, which can be submitted without waiting.
+    ///
+    /// Fails with `Error::Validation` for devices of callback based instances.
+    pub fn available_frames(&mut self) -> Result<api::Frames> {
+        if self.instance.properties.stream_mode != api::StreamMode::Polling {
+            return api::Error::validation("`available_frames` requires a polling instance");
+        }
+        unsafe { self.raw.available_frames() }
+    }
+
+    /// Submit stream buffers without waiting.
+    ///
+    /// Fails with `Error::WouldBlock` if no frames are available and
+    /// with `Error::Validation` for devices of callback based instances.
+    pub fn try_submit_buffers(&mut self) -> Result<()> {
+        if self.instance.properties.stream_mode != api::StreamMode::Polling {
+            return api::Error::validation("`try_submit_buffers` requires a polling instance");
+        }
+        unsafe { self.raw.try_submit_buffers() }
+    }
+
+    /// Get the OS handles signaling readiness of the device stream, see `api::Device::wait_handles`.
+    pub fn wait_handles(&self) -> Result<api::WaitHandles> {
+        unsafe { self.raw.wait_handles() }
+    }
+
+    /// Dispatch pending events of the device stream without blocking.
+    pub fn dispatch_pending(&mut self) -> Result<()> {
+        unsafe { self.raw.dispatch_pending() }
+    }
+
+    /// Wait asynchronously until the device stream is ready.
+    ///
+    /// Resolves with the number of frames, which can be submitted without waiting.
+    /// Fails with `Error::Validation` if not supported by the instance.
+    pub fn ready(&mut self) -> api::Ready<'_, I::Device> {
+        unsafe { self.raw.ready() }
+    }
+
+    /// Discard all pending audio of the device stream.
+    pub fn flush(&mut self) -> Result<()> {
+        unsafe { self.raw.flush() }
+    }
+
+    /// Wait until all submitted audio has been played.
+    pub fn drain(&self) -> Result<()> {
+        unsafe { self.raw.drain() }
+    }
+
+    /// Stream on the current thread as long as `running` returns `true`.
+    ///
+    /// The device is started before and stopped after streaming.
+    /// Devices of polling instances submit buffers from an audio session on the current thread,
+    /// for callback based instances the current thread only checks the run condition periodically.
+    /// Pending instance events are dispatched on every iteration, invoking the event callback.
+    pub fn run_while<F>(&mut self, mut running: F) -> Result<()>
+    where
+        F: FnMut() -> bool,
+    {
+        let instance = &self.instance.raw;
+        let mut dispatch = true;
+        match self.instance.properties.stream_mode {
+            api::StreamMode::Polling => {
+                let sample_rate = self.stream_properties().sample_rate;
+                let _session = unsafe { instance.create_session(sample_rate)? };
+
+                self.start();
+                let mut result = Ok(());
+                while result.is_ok() && running() {
+                    result = match unsafe {
+                        api::dispatch_events(instance, &mut dispatch)
+                            .and_then(|()| self.raw.submit_buffers(RUN_TIMEOUT_MS))
+                    } {
+                        // Periods may be longer than the timeout, only bounding the run condition checks.
+                        Err(api::Error::Timeout { .. }) => Ok(()),
+                        result => result,
+                    };
+                }
+                self.stop();
+                result
+            }
+            api::StreamMode::Callback => {
+                self.start();
+                let mut result = Ok(());
+                while result.is_ok() && running() {
+                    result = unsafe { api::dispatch_events(instance, &mut dispatch) };
+                    thread::sleep(RUN_INTERVAL);
+                }
+                self.stop();
+                result
+            }
+        }
+    }
+
+    /// Stream on the current thread for the given duration.
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        let start = Instant::now();
+        self.run_while(|| start.elapsed() < duration)
+    }
+}
+
+/// Stream buffers passed to the device callback.
+pub struct Stream<'a> {
+    raw: api::Stream,
+    _marker: PhantomData<&'a mut ()>,
+}
+
+impl Stream<'_> {
+    pub fn properties(&self) -> api::StreamProperties {
+        self.raw.properties
+    }
+
+    /// Number of frames per buffer.
+    pub fn frames(&self) -> usize {
+        self.raw.buffers.frames
+    }
+
+    /// Typed view over the interleaved input buffer.
+    ///
+    /// Fails with `Error::Validation` if the stream has no input or
+    /// `T` doesn't match the stream format.
+    pub fn input<T: api::Sample>(&self) -> Result<api::Interleaved<'_, T>> {
+        unsafe { self.raw.input() }
+    }
+
+    /// Typed view over the interleaved output buffer.
+    ///
+    /// Fails with `Error::Validation` if the stream has no output or
+    /// `T` doesn't match the stream format.
+    pub fn output<T: api::Sample>(&mut self) -> Result<api::InterleavedMut<'_, T>> {
+        unsafe { self.raw.output() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::null;
+    use crate::polling::PollingDevice;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    type EventCallback = Box<dyn FnMut(api::Event) + Send>;
+
+    /// Upper bound for streaming until the tested condition is met.
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Polling instance on top of the null backend, dispatching queued events.
+    struct Events {
+        null: null::Instance,
+        queue: Arc<Mutex<Vec<api::Event>>>,
+        callback: Mutex<Option<EventCallback>>,
+    }
+
+    impl api::Instance for Events {
+        type Device = PollingDevice<null::Device>;
+        type Session = ();
+
+        unsafe fn properties(&self) -> api::InstanceProperties {
+            api::InstanceProperties {
+                stream_mode: api::StreamMode::Polling,
+                ..self.null.properties()
+            }
+        }
+
+        unsafe fn create(name: &str) -> Result<Self> {
+            Ok(Events {
+                null: null::Instance::create(name)?,
+                queue: Arc::new(Mutex::new(Vec::new())),
+                callback: Mutex::new(None),
+            })
+        }
+
+        unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
+            self.null.enumerate_physical_devices()
+        }
+
+        unsafe fn default_physical_input_device(&self) -> Option<api::PhysicalDevice> {
+            self.null.default_physical_input_device()
+        }
+
+        unsafe fn default_physical_output_device(&self) -> Option<api::PhysicalDevice> {
+            self.null.default_physical_output_device()
+        }
+
+        unsafe fn physical_device_properties(
+            &self,
+            physical_device: api::PhysicalDevice,
+        ) -> Result<api::PhysicalDeviceProperties> {
+            self.null.physical_device_properties(physical_device)
+        }
+
+        unsafe fn physical_device_supports_format(
+            &self,
+            physical_device: api::PhysicalDevice,
+            sharing: api::SharingMode,
+            frame_desc: api::FrameDesc,
+        ) -> bool {
+            self.null
+                .physical_device_supports_format(physical_device, sharing, frame_desc)
+        }
+
+        unsafe fn physical_device_default_concurrent_format(
+            &self,
+            physical_device: api::PhysicalDevice,
+        ) -> Result<api::FrameDesc> {
+            self.null
+                .physical_device_default_concurrent_format(physical_device)
+        }
+
+        unsafe fn create_device(
+            &self,
+            desc: api::DeviceDesc,
+            channels: api::Channels,
+            callback: api::StreamCallback,
+        ) -> Result<Self::Device> {
+            PollingDevice::create(&self.null, desc, channels, callback)
+        }
+
+        unsafe fn create_session(&self, _: usize) -> Result<Self::Session> {
+            Ok(())
+        }
+
+        unsafe fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
+        where
+            F: FnMut(api::Event) + Send + 'static,
+        {
+            *self.callback.lock().unwrap() = callback.map(|callback| Box::new(callback) as _);
+            Ok(())
+        }
+
+        unsafe fn dispatch_pending(&self) -> Result<()> {
+            let events = self.queue.lock().unwrap().drain(..).collect::<Vec<_>>();
+            if let Some(ref mut callback) = *self.callback.lock().unwrap() {
+                events.into_iter().for_each(callback);
+            }
+            Ok(())
+        }
+    }
+
+    fn device_desc<I: api::Instance>(
+        physical_device: PhysicalDevice<'_, I>,
+        buffering: api::Buffering,
+    ) -> DeviceDesc<'_, I> {
+        DeviceDesc {
+            physical_device,
+            sharing: api::SharingMode::Concurrent,
+            sample_desc: api::SampleDesc {
+                format: api::Format::F32,
+                sample_rate: 48_000,
+            },
+            buffering,
+            conversion: api::Conversion::default(),
+        }
+    }
+
+    #[test]
+    fn events_during_run() {
+        let mut instance = Instance::<Events>::create("audir").unwrap();
+        let received = Arc::new(AtomicBool::new(false));
+        let event_received = received.clone();
+        instance
+            .set_event_callback(Some(move |event| {
+                if let api::Event::Added(0) = event {
+                    event_received.store(true, Ordering::Relaxed);
+                }
+            }))
+            .unwrap();
+
+        // Fire the event from the stream callback, i.e while running.
+        let queue = instance.raw().queue.clone();
+        let mut device = instance
+            .create_device(
+                device_desc(
+                    instance.default_output_device().unwrap(),
+                    api::Buffering::LowLatency,
+                ),
+                api::Channels {
+                    input: api::ChannelLayout::empty(),
+                    output: api::ChannelMask::STEREO.into(),
+                },
+                move |_| queue.lock().unwrap().push(api::Event::Added(0)),
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        device
+            .run_while(|| !received.load(Ordering::Relaxed) && start.elapsed() < TEST_TIMEOUT)
+            .unwrap();
+        assert!(received.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn periods_exceeding_run_timeout() {
+        let instance = Instance::<Events>::create("audir").unwrap();
+        let periods = Arc::new(Mutex::new(0));
+        let counter = periods.clone();
+
+        // Periods of 200ms, submitting buffers times out in between.
+        let mut device = instance
+            .create_device(
+                device_desc(
+                    instance.default_input_device().unwrap(),
+                    api::Buffering::Explicit {
+                        period_size: 9_600,
+                        num_periods: 2,
+                    },
+                ),
+                api::Channels {
+                    input: api::ChannelMask::MONO.into(),
+                    output: api::ChannelLayout::empty(),
+                },
+                move |_| *counter.lock().unwrap() += 1,
+            )
+            .unwrap();
+
+        let start = Instant::now();
+        device
+            .run_while(|| *periods.lock().unwrap() < 2 && start.elapsed() < TEST_TIMEOUT)
+            .unwrap();
+        assert_eq!(*periods.lock().unwrap(), 2);
+    }
+}
@@ -10,15 +10,25 @@ pub type WasapiResult<T> = (T, HRESULT);
 
 use com::{Guid, WeakPtr};
 use std::{
-    collections::HashMap, ffi::OsString, mem, os::windows::ffi::OsStringExt, ptr, slice,
-    sync::Mutex,
+    collections::HashMap,
+    ffi::OsString,
+    mem,
+    os::windows::ffi::OsStringExt,
+    ptr, slice,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 use winapi::shared::{
-    devpkey::*, ksmedia, minwindef::DWORD, mmreg::*, winerror, wtypes::PROPERTYKEY,
+    devpkey::*,
+    guiddef::GUID,
+    ksmedia,
+    minwindef::{DWORD, WORD},
+    mmreg::*,
+    winerror,
+    wtypes::PROPERTYKEY,
 };
 use winapi::um::{
     audioclient::*, audiosessiontypes::*, combaseapi::*, coml2api::STGM_READ, mmdeviceapi::*,
-    objbase::COINIT_MULTITHREADED, propsys::*, winnt::*,
+    objbase::COINIT_MULTITHREADED, propsys::*, winbase::WAIT_OBJECT_0, winnt::*,
 };
 use winapi::Interface;
 
@@ -28,12 +38,15 @@ use crate::{
 };
 
 unsafe fn string_from_wstr(os_str: *const WCHAR) -> String {
+    if os_str.is_null() {
+        return String::new();
+    }
     let mut len = 0;
     while *os_str.offset(len) != 0 {
         len += 1;
     }
     let string: OsString = OsStringExt::from_wide(slice::from_raw_parts(os_str, len as _));
-    string.into_string().unwrap()
+    string.to_string_lossy().into_owned()
 }
 
 #[repr(C)]
@@ -48,17 +61,17 @@ pub struct NotificationClient {
 #[com_impl::com_impl]
 unsafe impl IMMNotificationClient for NotificationClient {
     unsafe fn on_device_state_changed(&self, pwstrDeviceId: LPCWSTR, state: DWORD) -> HRESULT {
-        println!("changed {} to {}", string_from_wstr(pwstrDeviceId), state);
+        log::debug!("changed {} to {}", string_from_wstr(pwstrDeviceId), state);
         winerror::S_OK
     }
 
     unsafe fn on_device_added(&self, pwstrDeviceId: LPCWSTR) -> HRESULT {
-        println!("added {}", string_from_wstr(pwstrDeviceId));
+        log::debug!("added {}", string_from_wstr(pwstrDeviceId));
         winerror::S_OK
     }
 
     unsafe fn on_device_removed(&self, pwstrDeviceId: LPCWSTR) -> HRESULT {
-        println!("removed {}", string_from_wstr(pwstrDeviceId));
+        log::debug!("removed {}", string_from_wstr(pwstrDeviceId));
         winerror::S_OK
     }
 
@@ -69,7 +82,7 @@ unsafe impl IMMNotificationClient for NotificationClient {
         pwstrDefaultDeviceId: LPCWSTR,
     ) -> HRESULT {
         if role == eConsole {
-            println!("default {:?} ({})", pwstrDefaultDeviceId, role);
+            log::debug!("default {:?} ({})", pwstrDefaultDeviceId, role);
         }
 
         winerror::S_OK
@@ -84,33 +97,103 @@ unsafe impl IMMNotificationClient for NotificationClient {
     }
 }
 
-fn map_frame_desc(frame_desc: &api::FrameDesc) -> Option<WAVEFORMATEXTENSIBLE> {
-    let (format_tag, sub_format, bytes_per_sample) = match frame_desc.format {
-        api::Format::F32 => (
-            WAVE_FORMAT_EXTENSIBLE,
-            ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
-            4,
-        ),
-        api::Format::U32 => return None,
-        _ => unimplemented!(),
-    };
+/// Speaker positions in channel mask order.
+const SPEAKER_POSITIONS: [(api::ChannelMask, DWORD); 18] = [
+    (api::ChannelMask::FRONT_LEFT, SPEAKER_FRONT_LEFT),
+    (api::ChannelMask::FRONT_RIGHT, SPEAKER_FRONT_RIGHT),
+    (api::ChannelMask::FRONT_CENTER, SPEAKER_FRONT_CENTER),
+    (api::ChannelMask::LOW_FREQUENCY, SPEAKER_LOW_FREQUENCY),
+    (api::ChannelMask::BACK_LEFT, SPEAKER_BACK_LEFT),
+    (api::ChannelMask::BACK_RIGHT, SPEAKER_BACK_RIGHT),
+    (
+        api::ChannelMask::FRONT_LEFT_OF_CENTER,
+        SPEAKER_FRONT_LEFT_OF_CENTER,
+    ),
+    (
+        api::ChannelMask::FRONT_RIGHT_OF_CENTER,
+        SPEAKER_FRONT_RIGHT_OF_CENTER,
+    ),
+    (api::ChannelMask::BACK_CENTER, SPEAKER_BACK_CENTER),
+    (api::ChannelMask::SIDE_LEFT, SPEAKER_SIDE_LEFT),
+    (api::ChannelMask::SIDE_RIGHT, SPEAKER_SIDE_RIGHT),
+    (api::ChannelMask::TOP_CENTER, SPEAKER_TOP_CENTER),
+    (api::ChannelMask::TOP_FRONT_LEFT, SPEAKER_TOP_FRONT_LEFT),
+    (api::ChannelMask::TOP_FRONT_CENTER, SPEAKER_TOP_FRONT_CENTER),
+    (api::ChannelMask::TOP_FRONT_RIGHT, SPEAKER_TOP_FRONT_RIGHT),
+    (api::ChannelMask::TOP_BACK_LEFT, SPEAKER_TOP_BACK_LEFT),
+    (api::ChannelMask::TOP_BACK_CENTER, SPEAKER_TOP_BACK_CENTER),
+    (api::ChannelMask::TOP_BACK_RIGHT, SPEAKER_TOP_BACK_RIGHT),
+];
+
+fn map_channel_mask(channels: api::ChannelMask) -> DWORD {
+    SPEAKER_POSITIONS
+        .iter()
+        .filter(|(mask, _)| channels.contains(*mask))
+        .fold(0, |speakers, (_, speaker)| speakers | speaker)
+}
 
-    let mut channel_mask = 0;
-    {
-        let channels = frame_desc.channels;
-        if channels.contains(api::ChannelMask::FRONT_LEFT) {
-            channel_mask |= SPEAKER_FRONT_LEFT;
-        }
-        if channels.contains(api::ChannelMask::FRONT_RIGHT) {
-            channel_mask |= SPEAKER_FRONT_RIGHT;
+fn map_speakers(speakers: DWORD) -> api::ChannelMask {
+    SPEAKER_POSITIONS
+        .iter()
+        .filter(|(_, speaker)| speakers & speaker != 0)
+        .fold(api::ChannelMask::empty(), |channels, (mask, _)| {
+            channels | *mask
+        })
+}
+
+/// Wave sub format for little-endian sample formats.
+///
+/// WASAPI stores 24-bit samples in 32-bit containers MSB aligned, which doesn't match `I24In32`.
+fn map_sub_format(format: api::Format) -> Option<GUID> {
+    if format.is_big_endian() || format == api::Format::I24In32 {
+        None
+    } else if format.is_float() {
+        Some(ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT)
+    } else {
+        Some(ksmedia::KSDATAFORMAT_SUBTYPE_PCM)
+    }
+}
+
+/// Sample format from the wave sub format and the container and valid bit sizes.
+fn map_sample_format(
+    sub_format: GUID,
+    bits_per_sample: WORD,
+    valid_bits: WORD,
+) -> Option<api::Format> {
+    let sub_format = Guid(sub_format);
+    if sub_format == Guid(ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT) {
+        match bits_per_sample {
+            32 => Some(api::Format::F32),
+            64 => Some(api::Format::F64),
+            _ => None,
         }
-        if channels.contains(api::ChannelMask::FRONT_CENTER) {
-            channel_mask |= SPEAKER_FRONT_CENTER;
+    } else if sub_format == Guid(ksmedia::KSDATAFORMAT_SUBTYPE_PCM) {
+        match (bits_per_sample, valid_bits) {
+            (8, 8) => Some(api::Format::U8),
+            (16, 16) => Some(api::Format::I16),
+            (24, 24) => Some(api::Format::I24),
+            // MSB aligned 24-bit samples can be processed as 32-bit samples.
+            (32, 24) | (32, 32) => Some(api::Format::I32),
+            _ => None,
         }
+    } else {
+        None
     }
+}
+
+fn map_frame_desc(frame_desc: &api::FrameDesc) -> Option<WAVEFORMATEXTENSIBLE> {
+    let format_tag = WAVE_FORMAT_EXTENSIBLE;
+    let sub_format = map_sub_format(frame_desc.format)?;
+    let bytes_per_sample = frame_desc.format.size();
+
+    let channel_mask = match frame_desc.channels {
+        api::ChannelLayout::Mask(mask) => map_channel_mask(mask),
+        api::ChannelLayout::Discrete(_) => 0,
+    };
 
     let num_channels = frame_desc.num_channels();
     let bits_per_sample = 8 * bytes_per_sample;
+    let valid_bits = frame_desc.format.valid_bits();
 
     let format = WAVEFORMATEX {
         wFormatTag: format_tag,
@@ -124,7 +207,7 @@ fn map_frame_desc(frame_desc: &api::FrameDesc) -> Option<WAVEFORMATEXTENSIBLE> {
 
     Some(WAVEFORMATEXTENSIBLE {
         Format: format,
-        Samples: bits_per_sample as _,
+        Samples: valid_bits as _,
         dwChannelMask: channel_mask,
         SubFormat: sub_format,
     })
@@ -135,27 +218,26 @@ unsafe fn map_waveformat(format: *const WAVEFORMATEX) -> Result<api::FrameDesc>
     match wave_format.wFormatTag {
         WAVE_FORMAT_EXTENSIBLE => {
             let wave_format_ex = &*(format as *const WAVEFORMATEXTENSIBLE);
-            let subformat = Guid(wave_format_ex.SubFormat);
-            let samples = wave_format_ex.Samples;
-            let format =
-                if subformat == Guid(ksmedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT) && samples == 32 {
-                    api::Format::F32
-                } else {
+            let format = match map_sample_format(
+                wave_format_ex.SubFormat,
+                wave_format.wBitsPerSample,
+                wave_format_ex.Samples,
+            ) {
+                Some(format) => format,
+                None => {
                     return Err(api::Error::Internal {
                         cause: "unsupported format".into(),
-                    }); // TODO
-                };
+                    })
+                }
+            };
 
-            let mut channels = api::ChannelMask::empty();
-            if wave_format_ex.dwChannelMask & SPEAKER_FRONT_LEFT != 0 {
-                channels |= api::ChannelMask::FRONT_LEFT;
-            }
-            if wave_format_ex.dwChannelMask & SPEAKER_FRONT_RIGHT != 0 {
-                channels |= api::ChannelMask::FRONT_RIGHT;
-            }
-            if wave_format_ex.dwChannelMask & SPEAKER_FRONT_CENTER != 0 {
-                channels |= api::ChannelMask::FRONT_CENTER;
-            }
+            // Channels without speaker assignment are reported as discrete layout.
+            let num_channels = wave_format.nChannels as usize;
+            let channels = if wave_format_ex.dwChannelMask.count_ones() as usize == num_channels {
+                api::ChannelLayout::Mask(map_speakers(wave_format_ex.dwChannelMask))
+            } else {
+                api::ChannelLayout::Discrete(num_channels)
+            };
 
             Ok(api::FrameDesc {
                 format,
@@ -169,6 +251,25 @@ unsafe fn map_waveformat(format: *const WAVEFORMATEX) -> Result<api::FrameDesc>
     }
 }
 
+/// Map a failed `HRESULT` to the corresponding error.
+fn map_hresult(hr: HRESULT) -> api::Error {
+    let source = Some(api::NativeError::HResult(hr));
+    match hr {
+        AUDCLNT_E_DEVICE_IN_USE => api::Error::DeviceBusy { source },
+        AUDCLNT_E_DEVICE_INVALIDATED | AUDCLNT_E_RESOURCES_INVALIDATED => {
+            api::Error::Disconnected { source }
+        }
+        AUDCLNT_E_UNSUPPORTED_FORMAT
+        | AUDCLNT_E_EXCLUSIVE_MODE_NOT_ALLOWED
+        | AUDCLNT_E_BUFFER_SIZE_ERROR
+        | AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED => api::Error::Unsupported { source },
+        AUDCLNT_E_SERVICE_NOT_RUNNING => api::Error::BackendUnavailable { source },
+        _ => api::Error::Internal {
+            cause: format!("HRESULT 0x{:08X}", hr),
+        },
+    }
+}
+
 fn map_sharing_mode(sharing: api::SharingMode) -> AUDCLNT_SHAREMODE {
     match sharing {
         api::SharingMode::Exclusive => AUDCLNT_SHAREMODE_EXCLUSIVE,
@@ -200,7 +301,9 @@ pub struct Session(Option<audio_thread_priority::RtPriorityHandle>);
 impl std::ops::Drop for Session {
     fn drop(&mut self) {
         if let Some(handle) = self.0.take() {
-            audio_thread_priority::demote_current_thread_from_real_time(handle).unwrap();
+            if audio_thread_priority::demote_current_thread_from_real_time(handle).is_err() {
+                log::warn!("failed to demote audio session thread");
+            }
         }
     }
 }
@@ -211,11 +314,16 @@ pub struct Instance {
     notifier: WeakPtr<NotificationClient>,
 }
 
+// Audio interfaces are free-threaded, the instance may move between threads of the process.
+unsafe impl Send for Instance {}
+// The notification client is only replaced through `&mut self`, the device map is locked.
+unsafe impl Sync for Instance {}
+
 impl api::Instance for Instance {
     type Device = Device;
     type Session = Session;
 
-    unsafe fn properties() -> api::InstanceProperties {
+    unsafe fn properties(&self) -> api::InstanceProperties {
         api::InstanceProperties {
             driver_id: api::DriverId::Wasapi,
             stream_mode: api::StreamMode::Polling,
@@ -223,31 +331,42 @@ impl api::Instance for Instance {
         }
     }
 
-    unsafe fn create(_: &str) -> Self {
-        CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED);
+    unsafe fn create(_: &str) -> Result<Self> {
+        // Already initialized COM apartments of the current thread can be used as well.
+        let hr = CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED);
+        if winerror::FAILED(hr) && hr != winerror::RPC_E_CHANGED_MODE {
+            return Err(api::Error::BackendUnavailable {
+                source: Some(api::NativeError::HResult(hr)),
+            });
+        }
 
         let mut instance = InstanceRaw::null();
-        let _hr = CoCreateInstance(
+        let hr = CoCreateInstance(
             &CLSID_MMDeviceEnumerator,
             ptr::null_mut(),
             CLSCTX_ALL,
             &IMMDeviceEnumerator::uuidof(),
             instance.mut_void(),
         );
+        if winerror::FAILED(hr) {
+            return Err(api::Error::BackendUnavailable {
+                source: Some(api::NativeError::HResult(hr)),
+            });
+        }
 
         let mut physical_devices = HashMap::new();
         Self::enumerate_physical_devices_by_flow(&mut physical_devices, instance, eCapture);
         Self::enumerate_physical_devices_by_flow(&mut physical_devices, instance, eRender);
 
-        Instance {
+        Ok(Instance {
             raw: instance,
             physical_devices: Mutex::new(physical_devices),
             notifier: WeakPtr::null(),
-        }
+        })
     }
 
     unsafe fn enumerate_physical_devices(&self) -> Vec<api::PhysicalDevice> {
-        let mut physical_devices = self.physical_devices.lock().unwrap();
+        let mut physical_devices = self.physical_devices();
 
         Self::enumerate_physical_devices_by_flow(&mut physical_devices, self.raw, eCapture);
         Self::enumerate_physical_devices_by_flow(&mut physical_devices, self.raw, eRender);
@@ -255,7 +374,8 @@ impl api::Instance for Instance {
         physical_devices
             .values()
             .filter_map(|device| {
-                if device.state() & DEVICE_STATE_ACTIVE != 0 {
+                // Devices activated after the initial enumeration have no audio client.
+                if device.state() & DEVICE_STATE_ACTIVE != 0 && !device.audio_client.is_null() {
                     Some(device.raw())
                 } else {
                     None
@@ -273,7 +393,8 @@ impl api::Instance for Instance {
             None
         } else {
             let id = Self::get_physical_device_id(device);
-            Some(self.physical_devices.lock().unwrap()[&id].raw())
+            device.Release();
+            self.physical_devices().get(&id?).map(|device| device.raw())
         }
     }
 
@@ -286,7 +407,8 @@ impl api::Instance for Instance {
             None
         } else {
             let id = Self::get_physical_device_id(device);
-            Some(self.physical_devices.lock().unwrap()[&id].raw())
+            device.Release();
+            self.physical_devices().get(&id?).map(|device| device.raw())
         }
     }
 
@@ -335,9 +457,18 @@ impl api::Instance for Instance {
     ) -> Result<api::FrameDesc> {
         let physical_device = Handle::<PhysicalDevice>::from_raw(physical_device);
 
+        if physical_device.audio_client.is_null() {
+            return Err(api::Error::DeviceLost);
+        }
+
         let mut mix_format = ptr::null_mut();
-        physical_device.audio_client.GetMixFormat(&mut mix_format);
-        map_waveformat(mix_format)
+        let hr = physical_device.audio_client.GetMixFormat(&mut mix_format);
+        if winerror::FAILED(hr) {
+            return Err(map_hresult(hr));
+        }
+        let frame_desc = map_waveformat(mix_format);
+        CoTaskMemFree(mix_format as _);
+        frame_desc
     }
 
     unsafe fn create_device(
@@ -359,6 +490,9 @@ impl api::Instance for Instance {
         }
 
         let physical_device = Handle::<PhysicalDevice>::from_raw(desc.physical_device);
+        if physical_device.audio_client.is_null() {
+            return Err(api::Error::DeviceLost);
+        }
         let sharing = map_sharing_mode(desc.sharing);
 
         let fence = Fence::create(false, false);
@@ -379,21 +513,74 @@ impl api::Instance for Instance {
             },
             sample_rate,
         };
-        let mix_format = map_frame_desc(&frame_desc).unwrap(); // todo
-        let _hr = physical_device.audio_client.Initialize(
+        let mix_format = match map_frame_desc(&frame_desc) {
+            Some(format) => format,
+            None => {
+                return api::Error::validation(format!(
+                    "unsupported format ({:?})",
+                    desc.sample_desc.format
+                ))
+            }
+        };
+
+        // Buffer durations in 100ns units.
+        let (buffer_duration, periodicity) = match desc.buffering.periods(sample_rate) {
+            Some((period_size, num_periods)) => {
+                let period = (period_size as i64 * 10_000_000) / sample_rate as i64;
+                match desc.sharing {
+                    // Event driven exclusive streams require equal buffer duration and periodicity.
+                    api::SharingMode::Exclusive => (period, period),
+                    api::SharingMode::Concurrent => (period * num_periods as i64, 0),
+                }
+            }
+            None => (0, 0),
+        };
+
+        let hr = physical_device.audio_client.Initialize(
             sharing,
             AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-            0,
-            0,
+            buffer_duration,
+            periodicity,
             &mix_format as *const _ as _,
             ptr::null(),
         );
+        if winerror::FAILED(hr) {
+            return Err(map_hresult(hr));
+        }
 
-        physical_device.audio_client.SetEventHandle(fence.0);
+        let hr = physical_device.audio_client.SetEventHandle(fence.0);
+        if winerror::FAILED(hr) {
+            return Err(map_hresult(hr));
+        }
 
         let mut mix_format = ptr::null_mut();
-        physical_device.audio_client.GetMixFormat(&mut mix_format);
-        let frame_desc = map_waveformat(mix_format).unwrap();
+        let hr = physical_device.audio_client.GetMixFormat(&mut mix_format);
+        if winerror::FAILED(hr) {
+            return Err(map_hresult(hr));
+        }
+        let frame_desc = map_waveformat(mix_format);
+        CoTaskMemFree(mix_format as _);
+        let frame_desc = frame_desc?;
+
+        let mut buffer_size = 0;
+        let hr = physical_device.audio_client.GetBufferSize(&mut buffer_size);
+        if winerror::FAILED(hr) {
+            return Err(map_hresult(hr));
+        }
+        let period_size = match desc.sharing {
+            api::SharingMode::Exclusive => buffer_size as api::Frames,
+            api::SharingMode::Concurrent => {
+                let mut default_period = 0;
+                let hr = physical_device
+                    .audio_client
+                    .GetDevicePeriod(&mut default_period, ptr::null_mut());
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
+                (default_period as usize * frame_desc.sample_rate / 10_000_000).max(1)
+            }
+        };
+        let num_periods = (buffer_size as usize / period_size).max(1);
 
         let (properties, device_stream) = if !channels.input.is_empty() {
             let mut capture_client = WeakPtr::<IAudioCaptureClient>::null();
@@ -401,16 +588,12 @@ impl api::Instance for Instance {
                 &IAudioCaptureClient::uuidof(),
                 capture_client.mut_void() as _,
             );
-            let buffer_size = {
-                let mut size = 0;
-                physical_device.audio_client.GetBufferSize(&mut size);
-                size
-            };
-
             let properties = api::StreamProperties {
+                format: frame_desc.format,
                 channels: frame_desc.channels,
                 sample_rate: frame_desc.sample_rate,
-                buffer_size: buffer_size as _,
+                buffer_size: period_size,
+                num_periods,
             };
             let device_stream = DeviceStream::Input {
                 client: capture_client,
@@ -422,16 +605,12 @@ impl api::Instance for Instance {
             physical_device
                 .audio_client
                 .GetService(&IAudioRenderClient::uuidof(), render_client.mut_void() as _);
-            let buffer_size = {
-                let mut size = 0;
-                physical_device.audio_client.GetBufferSize(&mut size);
-                size
-            };
-
             let properties = api::StreamProperties {
+                format: frame_desc.format,
                 channels: frame_desc.channels,
                 sample_rate: frame_desc.sample_rate,
-                buffer_size: buffer_size as _,
+                buffer_size: period_size,
+                num_periods,
             };
             let device_stream = DeviceStream::Output {
                 client: render_client,
@@ -457,10 +636,12 @@ impl api::Instance for Instance {
             );
         }
 
-        let rt_handle =
-            audio_thread_priority::promote_current_thread_to_real_time(0, sample_rate as _)
-                .unwrap();
-        Ok(Session(Some(rt_handle)))
+        match audio_thread_priority::promote_current_thread_to_real_time(0, sample_rate as _) {
+            Ok(rt_handle) => Ok(Session(Some(rt_handle))),
+            Err(err) => Err(api::Error::Internal {
+                cause: format!("failed to promote thread to real-time: {}", err),
+            }),
+        }
     }
 
     unsafe fn set_event_callback<F>(&mut self, callback: Option<F>) -> Result<()>
@@ -489,8 +670,14 @@ impl api::Instance for Instance {
         frame_desc: api::FrameDesc,
     ) -> bool {
         let physical_device = Handle::<PhysicalDevice>::from_raw(physical_device);
+        if physical_device.audio_client.is_null() {
+            return false;
+        }
 
-        let wave_format = map_frame_desc(&frame_desc).unwrap(); // todo
+        let wave_format = match map_frame_desc(&frame_desc) {
+            Some(format) => format,
+            None => return false,
+        };
         let sharing = map_sharing_mode(sharing);
 
         let mut closest_format = ptr::null_mut();
@@ -499,21 +686,80 @@ impl api::Instance for Instance {
             &wave_format as *const _ as _,
             &mut closest_format,
         );
+        if !closest_format.is_null() {
+            CoTaskMemFree(closest_format as _);
+        }
 
         hr == winerror::S_OK
     }
+
+    unsafe fn negotiate_format(
+        &self,
+        physical_device: api::PhysicalDevice,
+        sharing: api::SharingMode,
+        frame_desc: api::FrameDesc,
+    ) -> Result<api::NegotiatedFormat> {
+        let wave_format = match map_frame_desc(&frame_desc) {
+            Some(format) => format,
+            None => {
+                return api::negotiate_format_from_capabilities(
+                    self,
+                    physical_device,
+                    sharing,
+                    frame_desc,
+                )
+            }
+        };
+
+        let device = Handle::<PhysicalDevice>::from_raw(physical_device);
+        if device.audio_client.is_null() {
+            return Err(api::Error::DeviceLost);
+        }
+        let mut closest_format = ptr::null_mut();
+        let hr = device.audio_client.IsFormatSupported(
+            map_sharing_mode(sharing),
+            &wave_format as *const _ as _,
+            &mut closest_format,
+        );
+
+        match hr {
+            winerror::S_OK => Ok(api::NegotiatedFormat::Exact(frame_desc)),
+            // Only concurrent mode suggests a closest format.
+            winerror::S_FALSE if !closest_format.is_null() => {
+                let closest = map_waveformat(closest_format);
+                CoTaskMemFree(closest_format as _);
+                let closest = closest?;
+                Ok(api::NegotiatedFormat::Closest {
+                    frame_desc: closest,
+                    reason: api::FormatMismatch::between(&frame_desc, &closest),
+                })
+            }
+            _ => {
+                api::negotiate_format_from_capabilities(self, physical_device, sharing, frame_desc)
+            }
+        }
+    }
 }
 
 impl Instance {
-    unsafe fn get_physical_device_id(device: PhysicalDeviceRaw) -> String {
+    /// Lock the physical device map.
+    ///
+    /// Entries are only inserted once fully initialized, a poisoned lock holds no broken state.
+    fn physical_devices(&self) -> MutexGuard<'_, PhysialDeviceMap> {
+        self.physical_devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    unsafe fn get_physical_device_id(device: PhysicalDeviceRaw) -> Option<String> {
         let mut str_id = ptr::null_mut();
-        device.GetId(&mut str_id);
-        let mut len = 0;
-        while *str_id.offset(len) != 0 {
-            len += 1;
+        let hr = device.GetId(&mut str_id);
+        if winerror::FAILED(hr) || str_id.is_null() {
+            return None;
         }
-        let name: OsString = OsStringExt::from_wide(slice::from_raw_parts(str_id, len as _));
-        name.into_string().unwrap()
+        let id = string_from_wstr(str_id);
+        CoTaskMemFree(str_id as _);
+        Some(id)
     }
 
     unsafe fn enumerate_physical_devices_by_flow(
@@ -531,11 +777,15 @@ impl Instance {
 
         let collection = {
             let mut collection = DeviceCollection::null();
-            let _hr = instance.EnumAudioEndpoints(
+            let hr = instance.EnumAudioEndpoints(
                 ty,
                 DEVICE_STATEMASK_ALL,
                 collection.mut_void() as *mut _,
             );
+            if winerror::FAILED(hr) {
+                log::warn!("failed to enumerate audio endpoints: {}", map_hresult(hr));
+                return;
+            }
             collection
         };
 
@@ -547,8 +797,25 @@ impl Instance {
 
         for i in 0..num_items {
             let mut device = PhysicalDeviceRaw::null();
-            collection.Item(i, device.mut_void() as *mut _);
-            let id = Self::get_physical_device_id(device);
+            let hr = collection.Item(i, device.mut_void() as *mut _);
+            if winerror::FAILED(hr) {
+                log::warn!("skipping audio endpoint {}: {}", i, map_hresult(hr));
+                continue;
+            }
+            let id = match Self::get_physical_device_id(device) {
+                Some(id) => id,
+                None => {
+                    log::warn!("skipping audio endpoint {}: missing id", i);
+                    device.Release();
+                    continue;
+                }
+            };
+
+            if let Some(physical_device) = physical_devices.get_mut(&id) {
+                physical_device.streams |= stream_flags;
+                device.Release();
+                continue;
+            }
 
             let state = {
                 let mut state = 0;
@@ -556,29 +823,29 @@ impl Instance {
                 state
             };
 
-            physical_devices
-                .entry(id)
-                .and_modify(|device| {
-                    device.streams |= stream_flags;
-                })
-                .or_insert_with(|| {
-                    let mut audio_client = WeakPtr::<IAudioClient>::null();
-
-                    if state & DEVICE_STATE_ACTIVE != 0 {
-                        device.Activate(
-                            &IAudioClient::uuidof(),
-                            CLSCTX_ALL,
-                            ptr::null_mut(),
-                            audio_client.mut_void() as *mut _,
-                        );
-                    }
-
-                    Handle::new(PhysicalDevice {
-                        device,
-                        audio_client,
-                        streams: stream_flags,
-                    })
-                });
+            let mut audio_client = WeakPtr::<IAudioClient>::null();
+            if state & DEVICE_STATE_ACTIVE != 0 {
+                let hr = device.Activate(
+                    &IAudioClient::uuidof(),
+                    CLSCTX_ALL,
+                    ptr::null_mut(),
+                    audio_client.mut_void() as *mut _,
+                );
+                if winerror::FAILED(hr) {
+                    log::warn!("skipping audio endpoint {}: {}", id, map_hresult(hr));
+                    device.Release();
+                    continue;
+                }
+            }
+
+            physical_devices.insert(
+                id,
+                Handle::new(PhysicalDevice {
+                    device,
+                    audio_client,
+                    streams: stream_flags,
+                }),
+            );
         }
 
         collection.Release();
@@ -616,6 +883,9 @@ pub struct Device {
     properties: api::StreamProperties,
 }
 
+// Audio clients are free-threaded, the device may move between threads of the process.
+unsafe impl Send for Device {}
+
 impl std::ops::Drop for Device {
     fn drop(&mut self) {
         unsafe {
@@ -627,27 +897,44 @@ impl std::ops::Drop for Device {
 
 impl Device {
     unsafe fn acquire_buffers(&mut self, timeout_ms: u32) -> Result<api::StreamBuffers> {
-        self.fence.wait(timeout_ms);
+        match self.fence.wait(timeout_ms) {
+            WAIT_OBJECT_0 => {}
+            winerror::WAIT_TIMEOUT => return Err(api::Error::Timeout { source: None }),
+            _ => {
+                return Err(api::Error::Internal {
+                    cause: format!(
+                        "failed to wait for buffers: {}",
+                        std::io::Error::last_os_error()
+                    ),
+                })
+            }
+        }
 
         match self.device_stream {
             DeviceStream::Input { client } => {
                 let mut len = 0;
-                client.GetNextPacketSize(&mut len);
+                let hr = client.GetNextPacketSize(&mut len);
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
 
                 let mut data = ptr::null_mut();
                 let mut num_frames = 0;
                 let mut flags = 0;
 
-                client.GetBuffer(
+                let hr = client.GetBuffer(
                     &mut data,
                     &mut num_frames,
                     &mut flags,
                     ptr::null_mut(),
                     ptr::null_mut(),
                 );
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
 
                 if flags != 0 {
-                    dbg!(flags);
+                    log::trace!("capture buffer flags: {:#x}", flags);
                 }
 
                 Ok(api::StreamBuffers {
@@ -663,10 +950,16 @@ impl Device {
                 let mut data = ptr::null_mut();
                 let mut padding = 0;
 
-                self.client.GetCurrentPadding(&mut padding);
+                let hr = self.client.GetCurrentPadding(&mut padding);
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
 
                 let len = buffer_size - padding;
-                client.GetBuffer(len, &mut data);
+                let hr = client.GetBuffer(len, &mut data);
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
                 Ok(api::StreamBuffers {
                     frames: len as _,
                     input: ptr::null(),
@@ -710,4 +1003,37 @@ impl api::Device for Device {
         });
         self.release_buffers(buffers.frames)
     }
+
+    /// Event signaled by the audio engine once buffers are ready.
+    unsafe fn wait_handles(&self) -> Result<api::WaitHandles> {
+        Ok(api::WaitHandles {
+            handles: vec![api::WaitHandle::Event(self.fence.0 as _)],
+            timeout: None,
+        })
+    }
+
+    unsafe fn dispatch_pending(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn available_frames(&mut self) -> Result<api::Frames> {
+        match self.device_stream {
+            DeviceStream::Input { client } => {
+                let mut len = 0;
+                let hr = client.GetNextPacketSize(&mut len);
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
+                Ok(len as _)
+            }
+            DeviceStream::Output { buffer_size, .. } => {
+                let mut padding = 0;
+                let hr = self.client.GetCurrentPadding(&mut padding);
+                if winerror::FAILED(hr) {
+                    return Err(map_hresult(hr));
+                }
+                Ok((buffer_size - padding) as _)
+            }
+        }
+    }
 }